@@ -0,0 +1,146 @@
+//! Measures the throughput of syncing a dag of large blocks between two peers.
+//!
+//! Run with `cargo bench --bench transfer`.
+use async_std::task;
+use fnv::FnvHashMap;
+use futures::prelude::*;
+use libipld::cbor::DagCborCodec;
+use libipld::multihash::Code;
+use libipld::store::DefaultParams;
+use libipld::{Block, Cid, Ipld, Result};
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::Boxed;
+use libp2p::identity;
+use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
+use libp2p::swarm::SwarmEvent;
+use libp2p::tcp::{self, async_io};
+use libp2p::yamux::YamuxConfig;
+use libp2p::{PeerId, Swarm, Transport};
+use libp2p_bitswap::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore};
+use multihash::MultihashDigest;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const BLOCK_SIZE: usize = 512 * 1024;
+const BLOCKS: usize = 64;
+const ROUNDS: usize = 5;
+
+#[derive(Clone, Default)]
+struct Store(Arc<Mutex<FnvHashMap<Cid, Vec<u8>>>>);
+
+impl BitswapStore for Store {
+    type Params = DefaultParams;
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        Ok(self.0.lock().unwrap().contains_key(cid))
+    }
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.lock().unwrap().get(cid).cloned())
+    }
+    fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(*block.cid(), block.data().to_vec());
+        Ok(())
+    }
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        let mut stack = vec![*cid];
+        let mut missing = vec![];
+        while let Some(cid) = stack.pop() {
+            if let Some(data) = self.get(&cid)? {
+                let block = Block::<Self::Params>::new_unchecked(cid, data);
+                block.references(&mut stack)?;
+            } else {
+                missing.push(cid);
+            }
+        }
+        Ok(missing)
+    }
+}
+
+fn mk_transport() -> (PeerId, Boxed<(PeerId, StreamMuxerBox)>) {
+    let id_key = identity::Keypair::generate_ed25519();
+    let peer_id = id_key.public().to_peer_id();
+    let dh_key = Keypair::<X25519Spec>::new()
+        .into_authentic(&id_key)
+        .unwrap();
+    let noise = NoiseConfig::xx(dh_key).into_authenticated();
+    let transport = async_io::Transport::new(tcp::Config::new().nodelay(true))
+        .upgrade(libp2p::core::upgrade::Version::V1)
+        .authenticate(noise)
+        .multiplex(YamuxConfig::default())
+        .timeout(Duration::from_secs(20))
+        .boxed();
+    (peer_id, transport)
+}
+
+fn mk_swarm(store: Store) -> Swarm<Bitswap<DefaultParams>> {
+    let (peer_id, trans) = mk_transport();
+    let bitswap = Bitswap::new(BitswapConfig::new(), store);
+    let mut swarm = Swarm::with_async_std_executor(trans, bitswap, peer_id);
+    swarm
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    while swarm.next().now_or_never().is_some() {}
+    swarm
+}
+
+/// Creates a root block linking to `BLOCKS` raw blocks.
+fn create_dag(store: &Store) -> Cid {
+    let mut store = store.0.lock().unwrap();
+    let mut links = vec![];
+    for i in 0..BLOCKS {
+        let data: Vec<u8> = (0..BLOCK_SIZE).map(|j| (i * 31 + j * 7) as u8).collect();
+        let cid = Cid::new_v1(0x55, Code::Blake3_256.digest(&data));
+        store.insert(cid, data);
+        links.push(Ipld::Link(cid));
+    }
+    let root =
+        Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &Ipld::List(links)).unwrap();
+    store.insert(*root.cid(), root.data().to_vec());
+    *root.cid()
+}
+
+async fn sync_round(root: Cid, provider: &Store) -> Duration {
+    let mut swarm1 = mk_swarm(provider.clone());
+    let mut swarm2 = mk_swarm(Store::default());
+    let peer1 = *swarm1.local_peer_id();
+    let addr1 = swarm1.listeners().next().unwrap().clone();
+    task::spawn(async move {
+        loop {
+            swarm1.next().await;
+        }
+    });
+    swarm2.behaviour_mut().add_address(&peer1, addr1);
+
+    let start = Instant::now();
+    let id = swarm2
+        .behaviour_mut()
+        .sync(root, vec![peer1], std::iter::once(root));
+    loop {
+        if let Some(SwarmEvent::Behaviour(BitswapEvent::Complete(id2, res))) = swarm2.next().await {
+            assert_eq!(id, id2);
+            res.unwrap();
+            return start.elapsed();
+        }
+    }
+}
+
+fn main() {
+    let provider = Store::default();
+    let root = create_dag(&provider);
+    let bytes = (BLOCKS * BLOCK_SIZE) as f64;
+    let mut times = vec![];
+    for _ in 0..ROUNDS {
+        times.push(task::block_on(sync_round(root, &provider)));
+    }
+    times.sort();
+    let median = times[ROUNDS / 2];
+    println!(
+        "sync {} x {} KiB: median {:?}, {:.1} MiB/s",
+        BLOCKS,
+        BLOCK_SIZE / 1024,
+        median,
+        bytes / median.as_secs_f64() / (1024.0 * 1024.0)
+    );
+}
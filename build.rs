@@ -1,4 +1,7 @@
 fn main() {
     #[cfg(feature = "compat")]
-    prost_build::compile_protos(&["src/compat/bitswap_pb.proto"], &["src/compat"]).unwrap();
+    prost_build::Config::new()
+        .bytes([".bitswap_pb.Message.Block.data"])
+        .compile_protos(&["src/compat/bitswap_pb.proto"], &["src/compat"])
+        .unwrap();
 }
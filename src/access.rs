@@ -0,0 +1,21 @@
+use libipld::Cid;
+use libp2p::PeerId;
+
+/// Decides which blocks are served to which peers.
+///
+/// A refused have or block request is answered with an unauthorized response, or like
+/// a block that isn't in the store on protocol versions without status responses.
+/// Refused descendants are left out of dag responses.
+pub trait AccessPolicy: Send + Sync + 'static {
+    /// Returns true if the peer may learn whether the store has the block and fetch it.
+    fn allow(&self, peer: &PeerId, cid: &Cid) -> bool;
+}
+
+impl<F> AccessPolicy for F
+where
+    F: Fn(&PeerId, &Cid) -> bool + Send + Sync + 'static,
+{
+    fn allow(&self, peer: &PeerId, cid: &Cid) -> bool {
+        self(peer, cid)
+    }
+}
@@ -5,43 +5,77 @@
 //!
 //! The `Bitswap` struct implements the `NetworkBehaviour` trait. When used, it
 //! will allow providing and reciving IPFS blocks.
+use crate::access::AccessPolicy;
 #[cfg(feature = "compat")]
-use crate::compat::{CompatMessage, CompatProtocol, InboundMessage};
+use crate::compat::{CompatEvent, CompatHandler, CompatMessage, CompatProtocol};
+use crate::dag::{DagRequest, DagVerifier};
+use crate::db::{start_db_thread, BlockingStore, DbRequest, DbResponse, DbWorker};
+use crate::handler::{Handler, HandlerEvent, HandlerIn};
+use crate::pipeline::{
+    PipelineEvent, PipelineFailure, PipelineHandler, PipelineIn, PipelineProtocol,
+};
 use crate::protocol::{
-    BitswapCodec, BitswapProtocol, BitswapRequest, BitswapResponse, RequestType,
+    BitswapCodec, BitswapProtocol, BitswapRequest, BitswapRequests, BitswapResponse,
+    BitswapResponses, Capabilities, ProtocolId, RequestType, DEFAULT_PROTOCOL_PREFIX,
+    MAX_BATCH_ENTRIES,
 };
+use crate::push::{PushManager, PushPolicy, PushRejected, PushUnsupported, PushedBlock};
 use crate::query::{QueryEvent, QueryId, QueryManager, Request, Response};
+use crate::reconcile::BloomFilter;
+use crate::reputation::{Misbehaviour, Reputation};
 use crate::stats::*;
-use fnv::FnvHashMap;
-#[cfg(feature = "compat")]
-use fnv::FnvHashSet;
+use crate::validator::BlockValidator;
+use async_trait::async_trait;
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::{
     channel::mpsc,
-    stream::{Stream, StreamExt},
+    future::{BoxFuture, Future, FutureExt},
+    sink::SinkExt,
+    stream::{FuturesUnordered, Stream, StreamExt},
     task::{Context, Poll},
 };
-use libipld::{error::BlockNotFound, store::StoreParams, Block, Cid, Result};
-#[cfg(feature = "compat")]
+use futures_timer::Delay;
+use libipld::codec::References;
+use libipld::{error::BlockNotFound, store::StoreParams, Block, Cid, Ipld, Result};
 use libp2p::core::either::EitherOutput;
 use libp2p::core::{connection::ConnectionId, Multiaddr, PeerId};
 use libp2p::swarm::derive_prelude::{ConnectionClosed, DialFailure, FromSwarm, ListenFailure};
-#[cfg(feature = "compat")]
-use libp2p::swarm::{ConnectionHandlerSelect, NotifyHandler, OneShotHandler};
 use libp2p::{
     request_response::{
         InboundFailure, OutboundFailure, ProtocolSupport, RequestId, RequestResponse,
         RequestResponseConfig, RequestResponseEvent, RequestResponseMessage, ResponseChannel,
     },
-    swarm::{ConnectionHandler, NetworkBehaviour, NetworkBehaviourAction, PollParameters},
+    swarm::{
+        ConnectionHandler, ConnectionHandlerSelect, NetworkBehaviour, NetworkBehaviourAction,
+        NotifyHandler, PollParameters,
+    },
 };
 use prometheus::Registry;
-use std::{pin::Pin, time::Duration};
+use rand::seq::IteratorRandom;
+use std::{
+    any::Any,
+    borrow::Cow,
+    collections::VecDeque,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
 
 /// Bitswap response channel.
-pub type Channel = ResponseChannel<BitswapResponse>;
+pub type Channel = ResponseChannel<BitswapResponses>;
+
+/// Connection handler of the request-response native protocol.
+type RequestHandler<P> = <RequestResponse<BitswapCodec<P>> as NetworkBehaviour>::ConnectionHandler;
+
+/// Connection handlers of the request-response and pipelined native protocols.
+type NativeHandler<P> =
+    ConnectionHandlerSelect<Handler<RequestHandler<P>>, Handler<PipelineHandler<P>>>;
 
 /// Event emitted by the bitswap behaviour.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum BitswapEvent {
     /// Received a block from a peer. Includes the number of known missing blocks for a
     /// sync query. When a block is received and missing blocks is not empty the counter
@@ -49,6 +83,70 @@ pub enum BitswapEvent {
     Progress(QueryId, usize),
     /// A get or sync query completed.
     Complete(QueryId, Result<()>),
+    /// A peer was banned for misbehaving. It won't be used as a provider or served
+    /// until the ban expires, so the application may want to disconnect it.
+    PeerBanned(PeerId),
+    /// Learned that a connected peer supports another bitswap protocol. Contains all
+    /// protocols the peer is known to support.
+    ProtocolsLearned(PeerId, Vec<SupportedProtocol>),
+}
+
+/// Bitswap protocol supported by a peer. The names below use the default prefixes.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SupportedProtocol {
+    /// `/ipfs-embed/bitswap/1.0.0`
+    Native,
+    /// `/ipfs-embed/bitswap/1.1.0`, which batches requests.
+    NativeBatch,
+    /// `/ipfs-embed/bitswap/1.2.0`, which batches requests and announces the optional
+    /// features the peer serves in every message. They are listed in
+    /// [`PeerInfo::capabilities`].
+    NativeCapabilities,
+    /// `/ipfs-embed/bitswap/2.0.0`, which is like `/ipfs-embed/bitswap/1.2.0` but
+    /// pipelines requests over a long lived substream.
+    NativePipeline,
+    /// `/ipfs/bitswap/1.2.0`
+    Compat,
+}
+
+impl SupportedProtocol {
+    /// Returns the protocol name with the given prefixes.
+    pub fn protocol_name(&self, names: &ProtocolNames) -> String {
+        let (prefix, suffix) = match self {
+            Self::Native => (&names.prefix, "/bitswap/1.0.0"),
+            Self::NativeBatch => (&names.prefix, "/bitswap/1.1.0"),
+            Self::NativeCapabilities => (&names.prefix, "/bitswap/1.2.0"),
+            Self::NativePipeline => (&names.prefix, "/bitswap/2.0.0"),
+            Self::Compat => (&names.compat_prefix, "/ipfs/bitswap/1.2.0"),
+        };
+        format!("{}{}", prefix, suffix)
+    }
+}
+
+/// Information about a connected peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerInfo {
+    /// Peer id.
+    pub peer_id: PeerId,
+    /// Bitswap protocols the peer is known to support. Empty until the peer opened or
+    /// accepted a bitswap substream.
+    pub protocols: Vec<SupportedProtocol>,
+    /// Optional features of the native protocol the peer is known to serve.
+    pub capabilities: Capabilities,
+    /// Time since the first connection to the peer was established.
+    pub connection_age: Duration,
+    /// Number of requests sent to the peer that weren't answered yet.
+    pub in_flight: usize,
+}
+
+/// Connection state of a peer.
+struct PeerState {
+    connections: Vec<ConnectionId>,
+    connected_since: Instant,
+    protocols: FnvHashSet<SupportedProtocol>,
+    capabilities: Capabilities,
+    /// Set once the peer refused a pipelined substream.
+    pipeline_unsupported: bool,
 }
 
 /// Trait implemented by a block store.
@@ -63,6 +161,139 @@ pub trait BitswapStore: Send + Sync + 'static {
     fn insert(&mut self, block: &Block<Self::Params>) -> Result<()>;
     /// A sync query needs a list of missing blocks to make progress.
     fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>>;
+    /// Batched version of `contains`. Must return one entry per cid.
+    fn contains_many(&mut self, cids: &[Cid]) -> Result<Vec<bool>> {
+        cids.iter().map(|cid| self.contains(cid)).collect()
+    }
+    /// Batched version of `get`. Must return one entry per cid.
+    fn get_many(&mut self, cids: &[Cid]) -> Result<Vec<Option<Vec<u8>>>> {
+        cids.iter().map(|cid| self.get(cid)).collect()
+    }
+    /// Batched version of `insert`.
+    fn insert_batch(&mut self, blocks: &[Block<Self::Params>]) -> Result<()> {
+        blocks.iter().try_for_each(|block| self.insert(block))
+    }
+    /// Returns the size of a block if it is in the store and the size is known without
+    /// reading the block. Sizes are announced to peers in have responses. The default
+    /// doesn't know any sizes.
+    fn size(&mut self, _cid: &Cid) -> Result<Option<usize>> {
+        Ok(None)
+    }
+    /// Batched version of `size`. Must return one entry per cid.
+    fn size_many(&mut self, cids: &[Cid]) -> Result<Vec<Option<usize>>> {
+        cids.iter().map(|cid| self.size(cid)).collect()
+    }
+}
+
+/// Trait implemented by an asynchronous block store.
+///
+/// The methods take `&self` so that the futures of multiple requests can be polled
+/// concurrently. A [`BitswapStore`] can be used where an async store is expected by
+/// wrapping it in a [`BlockingStore`].
+#[async_trait]
+pub trait AsyncBitswapStore: Send + Sync + 'static {
+    /// The store params.
+    type Params: StoreParams;
+    /// A have query needs to know if the block store contains the block.
+    async fn contains(&self, cid: &Cid) -> Result<bool>;
+    /// A block query needs to retrieve the block from the store.
+    async fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>>;
+    /// A block response needs to insert the block into the store.
+    async fn insert(&self, block: &Block<Self::Params>) -> Result<()>;
+    /// A sync query needs a list of missing blocks to make progress.
+    async fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>>;
+    /// Batched version of `contains`. Must return one entry per cid.
+    async fn contains_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        let mut res = Vec::with_capacity(cids.len());
+        for cid in cids {
+            res.push(self.contains(cid).await?);
+        }
+        Ok(res)
+    }
+    /// Batched version of `get`. Must return one entry per cid.
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut res = Vec::with_capacity(cids.len());
+        for cid in cids {
+            res.push(self.get(cid).await?);
+        }
+        Ok(res)
+    }
+    /// Batched version of `insert`.
+    async fn insert_batch(&self, blocks: &[Block<Self::Params>]) -> Result<()> {
+        for block in blocks {
+            self.insert(block).await?;
+        }
+        Ok(())
+    }
+    /// Returns the size of a block if it is in the store and the size is known without
+    /// reading the block. Sizes are announced to peers in have responses. The default
+    /// doesn't know any sizes.
+    async fn size(&self, _cid: &Cid) -> Result<Option<usize>> {
+        Ok(None)
+    }
+    /// Batched version of `size`. Must return one entry per cid.
+    async fn size_many(&self, cids: &[Cid]) -> Result<Vec<Option<usize>>> {
+        let mut res = Vec::with_capacity(cids.len());
+        for cid in cids {
+            res.push(self.size(cid).await?);
+        }
+        Ok(res)
+    }
+}
+
+/// Error returned when the store is used after [`Bitswap::shutdown`].
+#[derive(Debug, Error)]
+#[error("bitswap is shut down")]
+pub struct ShutDown;
+
+/// Error returned by [`Bitswap::shutdown`] when the store isn't of the requested type.
+#[derive(Debug, Error)]
+#[error("store isn't a {0}")]
+pub struct WrongStoreType(pub &'static str);
+
+/// Error returned by [`Bitswap::set_config`].
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The request timeout is zero.
+    #[error("request timeout must not be zero")]
+    ZeroRequestTimeout,
+    /// The db queue capacity differs from the running one.
+    #[error("db queue capacity can't be changed from {0}")]
+    DbQueueCapacity(usize),
+}
+
+/// Error returned when a block can't be pushed because no protocol the peer supports
+/// is enabled.
+#[derive(Debug, Error)]
+#[error("no bitswap protocol enabled for peer {0}")]
+pub struct ProtocolDisabled(pub PeerId);
+
+/// Names of the bitswap protocols, which are fixed when the behaviour is created.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProtocolNames {
+    /// Prefix of the native protocol names, `/ipfs-embed` by default. Private networks
+    /// can use their own prefix, like `/myapp` for `/myapp/bitswap/1.0.0`, so that
+    /// they don't exchange blocks with other networks.
+    pub prefix: Cow<'static, str>,
+    /// Prefix of the compat protocol name, empty by default. Like the prefix option of
+    /// go-bitswap, `/myapp` gives `/myapp/ipfs/bitswap/1.2.0`.
+    pub compat_prefix: Cow<'static, str>,
+}
+
+impl ProtocolNames {
+    /// Creates the default `ProtocolNames`.
+    pub fn new() -> Self {
+        Self {
+            prefix: Cow::Borrowed(DEFAULT_PROTOCOL_PREFIX),
+            compat_prefix: Cow::Borrowed(""),
+        }
+    }
+}
+
+impl Default for ProtocolNames {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Bitswap configuration.
@@ -70,8 +301,48 @@ pub trait BitswapStore: Send + Sync + 'static {
 pub struct BitswapConfig {
     /// Timeout of a request.
     pub request_timeout: Duration,
-    /// Time a connection is kept alive.
+    /// Time an idle connection is kept alive. Connections to peers that an active
+    /// query needs are kept alive until the query completes.
     pub connection_keep_alive: Duration,
+    /// Number of requests that can be queued for the block store. When the queue is
+    /// full, inbound requests are answered as busy and no new requests are sent to
+    /// peers until the store catches up. Received blocks that don't fit in a second
+    /// queue of the same size are dropped and requested again once it drained.
+    pub db_queue_capacity: usize,
+    /// Time after which peers may retry requests that were answered as busy.
+    pub busy_retry_after: Duration,
+    /// Number of requests that can be in flight to a peer. Further requests of queries
+    /// wait until a response arrives. Zero doesn't limit the requests.
+    pub max_concurrent_requests: usize,
+    /// Score at which a misbehaving peer gets banned. A threshold of zero disables
+    /// banning.
+    pub ban_threshold: u32,
+    /// Time a misbehaving peer is banned.
+    pub ban_duration: Duration,
+    /// Number of randomly chosen connected peers that are asked for a block when a get
+    /// query runs out of providers. Zero disables asking connected peers.
+    pub broadcast_peers: usize,
+    /// Speaks the native protocol. When disabled, inbound substreams of the native
+    /// protocol are refused and requests are sent with the compat protocol if it is
+    /// enabled.
+    pub enable_native: bool,
+    /// Speaks the compat protocol. When disabled, inbound substreams of the compat
+    /// protocol are refused and peers that don't support the native protocol aren't
+    /// asked. Only has an effect with the `compat` feature.
+    pub enable_compat: bool,
+    /// Sends dag requests, which ask providers for a missing block of a sync query
+    /// together with its descendants, and answers the dag requests of peers with the
+    /// descendants in the store. Needs [`Bitswap::collect_references`], without it dag
+    /// requests are answered like block requests. Disabled by default.
+    pub dag_requests: bool,
+    /// Depth limit of the descendants a sync query asks for along with a block.
+    /// `None` asks for as many as fit in a response message and zero asks for the
+    /// block only.
+    pub dag_depth: Option<u64>,
+    /// Speaks the pipelined native protocol, which sends all requests to a peer over
+    /// one long lived substream per connection. Requests to peers that don't support
+    /// it are sent with the native protocol. Disabled by default.
+    pub pipelining: bool,
 }
 
 impl BitswapConfig {
@@ -80,6 +351,17 @@ impl BitswapConfig {
         Self {
             request_timeout: Duration::from_secs(10),
             connection_keep_alive: Duration::from_secs(10),
+            db_queue_capacity: 1024,
+            busy_retry_after: Duration::from_secs(1),
+            max_concurrent_requests: 0,
+            ban_threshold: 16,
+            ban_duration: Duration::from_secs(600),
+            broadcast_peers: 0,
+            enable_native: true,
+            enable_compat: true,
+            dag_requests: false,
+            dag_depth: None,
+            pipelining: false,
         }
     }
 }
@@ -90,19 +372,57 @@ impl Default for BitswapConfig {
     }
 }
 
+/// Outbound native request message.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum MessageId {
+    /// Message sent with request-response.
+    Request(RequestId),
+    /// Message sent on a pipelined substream.
+    Pipeline(u64),
+    /// Block pushed to a peer with the compat protocol.
+    #[cfg(feature = "compat")]
+    Compat(PeerId, Cid),
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 enum BitswapId {
-    Bitswap(RequestId),
+    /// Entry of a native request message.
+    Bitswap(MessageId, usize),
     #[cfg(feature = "compat")]
     Compat(Cid),
 }
 
-enum BitswapChannel {
-    Bitswap(Channel),
+pub(crate) enum BitswapChannel {
+    /// Entry of an inbound native request message.
+    Bitswap(u64, usize),
     #[cfg(feature = "compat")]
     Compat(PeerId, Cid),
 }
 
+/// Change sent to the connection handlers of a connection.
+#[derive(Clone, Copy, Debug)]
+enum HandlerCommand {
+    KeepAlive(bool),
+    ListenNative(bool),
+    ListenPipeline(bool),
+    #[cfg(feature = "compat")]
+    ListenCompat(bool),
+}
+
+/// Channel an inbound native request message is answered on.
+enum InboundChannel {
+    Request(Channel),
+    /// Message received on the pipelined substream of a connection.
+    Pipeline(PeerId, ConnectionId, u64),
+}
+
+/// Inbound native request message that is answered once all its requests are.
+struct InboundBatch {
+    protocol: BitswapProtocol,
+    channel: InboundChannel,
+    responses: Vec<Option<BitswapResponse>>,
+}
+
 /// Network behaviour that handles sending and receiving blocks.
 pub struct Bitswap<P: StoreParams> {
     /// Inner behaviour.
@@ -111,10 +431,69 @@ pub struct Bitswap<P: StoreParams> {
     query_manager: QueryManager,
     /// Requests.
     requests: FnvHashMap<BitswapId, QueryId>,
+    /// Requests that are sent at the end of the poll, batched per peer.
+    outbox: FnvHashMap<PeerId, Vec<(QueryId, BitswapRequest)>>,
+    /// Inbound messages waiting for responses.
+    inbound: FnvHashMap<u64, InboundBatch>,
+    /// Next inbound message id.
+    next_inbound: u64,
     /// Db request channel.
-    db_tx: mpsc::UnboundedSender<DbRequest<P>>,
+    db_tx: mpsc::Sender<DbRequest<P>>,
     /// Db response channel.
-    db_rx: mpsc::UnboundedReceiver<DbResponse>,
+    db_rx: mpsc::Receiver<DbResponse>,
+    /// Db requests waiting for space in the db request channel.
+    db_pending: VecDeque<DbRequest<P>>,
+    /// Responses to inbound requests that are answered without the store, because
+    /// they were shed, refused by the access policy or are refused pushes.
+    local_responses: VecDeque<DbResponse>,
+    /// Validator run on received blocks.
+    validator: Option<Box<dyn BlockValidator<P>>>,
+    /// Pushes to peers.
+    push_manager: PushManager<P, MessageId>,
+    /// Policy deciding which pushed blocks are stored.
+    push_policy: Option<Box<dyn PushPolicy<P>>>,
+    /// Policy deciding which blocks are served to which peers.
+    access_policy: Option<Arc<dyn AccessPolicy>>,
+    /// Backoff queries waiting to ask a peer again.
+    backoffs: FuturesUnordered<BoxFuture<'static, QueryId>>,
+    /// Collects the links of blocks once dag requests are enabled.
+    dag_references: Option<crate::push::References<P>>,
+    /// Depth limits of the dag requests waiting for a response.
+    dag_requests: FnvHashMap<MessageId, Option<u64>>,
+    /// Reconcile queries waiting for their filter, with their root and providers.
+    reconciles: FnvHashMap<QueryId, (Cid, Vec<PeerId>)>,
+    /// Filters sent along with the dag requests of reconcile queries.
+    filters: FnvHashMap<QueryId, BloomFilter>,
+    /// Pipelined protocol with the configured name.
+    pipeline_protocol: PipelineProtocol,
+    /// Connections of the pipelined messages waiting for a response.
+    pipelined: FnvHashMap<u64, ConnectionId>,
+    /// Next pipelined message id.
+    next_pipelined: u64,
+    /// Pipelined messages to send.
+    pipeline_out: VecDeque<(PeerId, ConnectionId, PipelineIn)>,
+    /// Compat messages to send.
+    #[cfg(feature = "compat")]
+    compat_out: VecDeque<(PeerId, CompatMessage)>,
+    /// Compat protocol with the configured name.
+    #[cfg(feature = "compat")]
+    compat_protocol: CompatProtocol,
+    /// Misbehaving peers.
+    reputation: Reputation,
+    /// Events that are not the result of a query.
+    events: VecDeque<BitswapEvent>,
+    /// Current config.
+    config: BitswapConfig,
+    /// Names of the protocols.
+    names: ProtocolNames,
+    /// Connected peers.
+    peers: FnvHashMap<PeerId, PeerState>,
+    /// Peers needed by a query, whose connections are kept alive.
+    pinned: FnvHashSet<PeerId>,
+    /// Keep alive and listen changes to send to connection handlers.
+    commands: VecDeque<(PeerId, ConnectionId, HandlerCommand)>,
+    /// Db thread, until the behaviour is shut down.
+    worker: Option<DbWorker>,
     /// Compat peers.
     #[cfg(feature = "compat")]
     compat: FnvHashSet<PeerId>,
@@ -123,23 +502,288 @@ pub struct Bitswap<P: StoreParams> {
 impl<P: StoreParams> Bitswap<P> {
     /// Creates a new `Bitswap` behaviour.
     pub fn new<S: BitswapStore<Params = P>>(config: BitswapConfig, store: S) -> Self {
+        Self::with_names(config, ProtocolNames::new(), store)
+    }
+
+    /// Creates a new `Bitswap` behaviour that speaks protocols with the given names.
+    pub fn with_names<S: BitswapStore<Params = P>>(
+        config: BitswapConfig,
+        names: ProtocolNames,
+        store: S,
+    ) -> Self {
+        Self::with_store(config, names, BlockingStore::new(store), |store| {
+            Box::new(store.into_inner())
+        })
+    }
+
+    /// Creates a new `Bitswap` behaviour backed by an async store.
+    pub fn new_async<S: AsyncBitswapStore<Params = P>>(config: BitswapConfig, store: S) -> Self {
+        Self::async_with_names(config, ProtocolNames::new(), store)
+    }
+
+    /// Creates a new `Bitswap` behaviour backed by an async store that speaks protocols
+    /// with the given names.
+    pub fn async_with_names<S: AsyncBitswapStore<Params = P>>(
+        config: BitswapConfig,
+        names: ProtocolNames,
+        store: S,
+    ) -> Self {
+        Self::with_store(config, names, store, |store| Box::new(store))
+    }
+
+    fn with_store<S: AsyncBitswapStore<Params = P>>(
+        config: BitswapConfig,
+        names: ProtocolNames,
+        store: S,
+        into_store: fn(S) -> Box<dyn Any + Send>,
+    ) -> Self {
         let mut rr_config = RequestResponseConfig::default();
         rr_config.set_connection_keep_alive(config.connection_keep_alive);
         rr_config.set_request_timeout(config.request_timeout);
-        let protocols = std::iter::once((BitswapProtocol, ProtocolSupport::Full));
+        let protocols = [
+            BitswapProtocol::Capabilities,
+            BitswapProtocol::Batch,
+            BitswapProtocol::Single,
+        ];
+        let protocols = protocols.iter().map(|version| {
+            let id = ProtocolId::new(&names.prefix, *version);
+            (id, ProtocolSupport::Full)
+        });
         let inner = RequestResponse::new(BitswapCodec::<P>::default(), protocols, rr_config);
-        let (db_tx, db_rx) = start_db_thread(store);
+        let mut query_manager = QueryManager::default();
+        query_manager.set_broadcast(config.broadcast_peers > 0);
+        let (db_tx, db_rx, worker) = start_db_thread(store, config.db_queue_capacity, into_store);
         Self {
             inner,
-            query_manager: Default::default(),
+            query_manager,
             requests: Default::default(),
+            outbox: Default::default(),
+            inbound: Default::default(),
+            next_inbound: 0,
             db_tx,
             db_rx,
+            db_pending: Default::default(),
+            local_responses: Default::default(),
+            validator: None,
+            push_manager: Default::default(),
+            push_policy: None,
+            access_policy: None,
+            backoffs: Default::default(),
+            dag_references: None,
+            dag_requests: Default::default(),
+            reconciles: Default::default(),
+            filters: Default::default(),
+            pipeline_protocol: PipelineProtocol::new(&names.prefix),
+            pipelined: Default::default(),
+            next_pipelined: 0,
+            pipeline_out: Default::default(),
+            #[cfg(feature = "compat")]
+            compat_out: Default::default(),
+            #[cfg(feature = "compat")]
+            compat_protocol: CompatProtocol::new(&names.compat_prefix),
+            reputation: Reputation::new(config.ban_threshold, config.ban_duration),
+            events: Default::default(),
+            config,
+            names,
+            peers: Default::default(),
+            pinned: Default::default(),
+            commands: Default::default(),
+            worker: Some(worker),
             #[cfg(feature = "compat")]
             compat: Default::default(),
         }
     }
 
+    /// Returns the current config.
+    pub fn config(&self) -> &BitswapConfig {
+        &self.config
+    }
+
+    /// Returns the names of the protocols.
+    pub fn protocol_names(&self) -> &ProtocolNames {
+        &self.names
+    }
+
+    /// Changes the config of a running behaviour.
+    ///
+    /// The request timeout and keep alive apply to new connections, the request limit
+    /// and dag requests apply to requests that are sent or answered from now on and the
+    /// ban limits apply to future misbehaviour. Enabling or disabling a protocol applies to all
+    /// connections. The db queue capacity is fixed when the behaviour is created and
+    /// can't be changed.
+    pub fn set_config(&mut self, config: BitswapConfig) -> Result<(), ConfigError> {
+        if config.request_timeout.is_zero() {
+            return Err(ConfigError::ZeroRequestTimeout);
+        }
+        if config.db_queue_capacity != self.config.db_queue_capacity {
+            return Err(ConfigError::DbQueueCapacity(self.config.db_queue_capacity));
+        }
+        let native = config.enable_native != self.config.enable_native;
+        let pipeline = native || config.pipelining != self.config.pipelining;
+        #[cfg(feature = "compat")]
+        let compat = config.enable_compat != self.config.enable_compat;
+        for (peer, state) in &self.peers {
+            for conn in &state.connections {
+                if native {
+                    let command = HandlerCommand::ListenNative(config.enable_native);
+                    self.commands.push_back((*peer, *conn, command));
+                }
+                if pipeline {
+                    let command =
+                        HandlerCommand::ListenPipeline(config.enable_native && config.pipelining);
+                    self.commands.push_back((*peer, *conn, command));
+                }
+                #[cfg(feature = "compat")]
+                if compat {
+                    let command = HandlerCommand::ListenCompat(config.enable_compat);
+                    self.commands.push_back((*peer, *conn, command));
+                }
+            }
+        }
+        self.reputation
+            .set_limits(config.ban_threshold, config.ban_duration);
+        self.query_manager.set_broadcast(config.broadcast_peers > 0);
+        self.config = config;
+        Ok(())
+    }
+
+    fn wrap_handler<H>(&self, handler: H, listen: bool) -> Handler<H> {
+        Handler::new(
+            handler,
+            self.config.request_timeout,
+            self.config.connection_keep_alive,
+            listen,
+        )
+    }
+
+    /// Returns true if pipelined substreams are accepted.
+    fn listen_pipeline(&self) -> bool {
+        self.config.enable_native && self.config.pipelining
+    }
+
+    /// Wraps the connection handler of the native protocol and adds the pipelined and
+    /// compat ones.
+    fn connection_handler(
+        &self,
+        handler: RequestHandler<P>,
+    ) -> <Self as NetworkBehaviour>::ConnectionHandler {
+        let handler = ConnectionHandler::select(
+            self.wrap_handler(handler, self.config.enable_native),
+            self.wrap_handler(
+                PipelineHandler::new(self.pipeline_protocol.clone(), self.config.request_timeout),
+                self.listen_pipeline(),
+            ),
+        );
+        #[cfg(not(feature = "compat"))]
+        return handler;
+        #[cfg(feature = "compat")]
+        ConnectionHandler::select(
+            handler,
+            self.wrap_handler(
+                CompatHandler::new(self.compat_protocol.clone()),
+                self.config.enable_compat,
+            ),
+        )
+    }
+
+    /// Returns true if requests to a peer are sent with the compat protocol.
+    #[cfg(feature = "compat")]
+    fn use_compat(&self, peer: &PeerId) -> bool {
+        self.config.enable_compat && (!self.config.enable_native || self.compat.contains(peer))
+    }
+
+    /// Returns the number of requests in flight to each peer.
+    fn in_flight(&self) -> FnvHashMap<PeerId, usize> {
+        let mut in_flight = FnvHashMap::<PeerId, usize>::default();
+        let queries = self
+            .requests
+            .values()
+            .filter_map(|id| self.query_manager.query_info(*id)?.peer);
+        for peer in queries.chain(self.push_manager.request_peers()) {
+            *in_flight.entry(peer).or_default() += 1;
+        }
+        in_flight
+    }
+
+    /// Returns the connected peers.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let in_flight = self.in_flight();
+        let now = Instant::now();
+        self.peers
+            .iter()
+            .map(|(peer_id, state)| {
+                let mut protocols: Vec<_> = state.protocols.iter().copied().collect();
+                protocols.sort();
+                PeerInfo {
+                    peer_id: *peer_id,
+                    protocols,
+                    capabilities: state.capabilities,
+                    connection_age: now.duration_since(state.connected_since),
+                    in_flight: in_flight.get(peer_id).copied().unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Sets a validator that received blocks need to pass before they are inserted
+    /// into the store.
+    pub fn set_block_validator(&mut self, validator: impl BlockValidator<P>) {
+        self.validator = Some(Box::new(validator));
+    }
+
+    /// Sets the policy that decides which blocks pushed by peers are stored. Without a
+    /// policy all pushed blocks are refused.
+    pub fn set_push_policy(&mut self, policy: impl PushPolicy<P>) {
+        self.push_policy = Some(Box::new(policy));
+    }
+
+    /// Sets the policy that decides which blocks are served to which peers. Without a
+    /// policy all blocks in the store are served.
+    pub fn set_access_policy(&mut self, policy: impl AccessPolicy) {
+        self.access_policy = Some(Arc::new(policy));
+    }
+
+    /// Pushes a block from the store to a peer. Completes once the peer stored it.
+    pub fn push(&mut self, peer: PeerId, cid: Cid) -> QueryId {
+        self.start_push(peer, cid, None)
+    }
+
+    /// Pushes a dag from the store to a peer. Completes once the peer stored all blocks.
+    pub fn push_dag(&mut self, peer: PeerId, root: Cid) -> QueryId
+    where
+        Ipld: References<P::Codecs>,
+    {
+        self.start_push(peer, root, Some(|block, refs| block.references(refs)))
+    }
+
+    /// Lets the behaviour collect the references of blocks, which dag requests and
+    /// reconcile queries need. They are only sent and answered while `dag_requests` is
+    /// enabled in the config.
+    pub fn collect_references(&mut self)
+    where
+        Ipld: References<P::Codecs>,
+    {
+        self.dag_references = Some(|block, refs| block.references(refs));
+    }
+
+    /// Returns the reference collector if dag requests are enabled.
+    fn dag_references(&self) -> Option<crate::push::References<P>> {
+        self.dag_references.filter(|_| self.config.dag_requests)
+    }
+
+    fn start_push(
+        &mut self,
+        peer: PeerId,
+        cid: Cid,
+        references: Option<crate::push::References<P>>,
+    ) -> QueryId {
+        let id = self.query_manager.next_id();
+        tracing::trace!("{} push {} to {}", id, cid, peer);
+        self.push_manager.push(id, peer, cid, references);
+        self.send_db_request(DbRequest::Get(id, cid));
+        id
+    }
+
     /// Adds an address for a peer.
     pub fn add_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
         self.inner.add_address(peer_id, addr);
@@ -150,12 +794,14 @@ impl<P: StoreParams> Bitswap<P> {
         self.inner.remove_address(peer_id, addr);
     }
 
-    /// Starts a get query with an initial guess of providers.
+    /// Starts a get query with an initial guess of providers. Completes without
+    /// asking the providers if the block is already in the store.
     pub fn get(&mut self, cid: Cid, peers: impl Iterator<Item = PeerId>) -> QueryId {
         self.query_manager.get(None, cid, peers)
     }
 
-    /// Starts a sync query with an the initial set of missing blocks.
+    /// Starts a sync query with an the initial set of missing blocks. If the missing
+    /// set is empty, the missing blocks are computed from the store starting at `cid`.
     pub fn sync(
         &mut self,
         cid: Cid,
@@ -165,15 +811,117 @@ impl<P: StoreParams> Bitswap<P> {
         self.query_manager.sync(cid, peers, missing)
     }
 
-    /// Cancels an in progress query. Returns true if a query was cancelled.
+    /// Starts a sync query for a dag that shares most of its blocks with the dag below
+    /// `base`, like a new version of a dataset. The blocks below `base` that are in
+    /// the store are collected into a filter that is sent along with the dag request
+    /// for `cid`, so that providers leave them out. Without dag requests it is a plain
+    /// sync query.
+    pub fn reconcile(&mut self, cid: Cid, peers: Vec<PeerId>, base: Cid) -> QueryId {
+        let references = if let Some(references) = self.dag_references() {
+            references
+        } else {
+            return self.query_manager.sync(cid, peers, std::iter::empty());
+        };
+        let id = self.query_manager.next_id();
+        tracing::trace!("{} reconcile {} with {}", id, cid, base);
+        self.reconciles.insert(id, (cid, peers));
+        let depth = self.config.dag_depth;
+        self.send_db_request(DbRequest::Filter(id, base, depth, references));
+        id
+    }
+
+    /// Returns the total size of the blocks a get or sync query is still retrieving.
+    /// Only counts blocks whose size providers announced, so it is an estimate that
+    /// grows as a sync query discovers more blocks.
+    pub fn pending_bytes(&self, id: QueryId) -> u64 {
+        self.query_manager.pending_bytes(id)
+    }
+
+    /// Cancels an in progress query or push. Returns true if it was cancelled.
     pub fn cancel(&mut self, id: QueryId) -> bool {
-        let res = self.query_manager.cancel(id);
+        self.filters.remove(&id);
+        let res = self.query_manager.cancel(id)
+            || self.push_manager.cancel(id)
+            || self.reconciles.remove(&id).is_some();
         if res {
             REQUESTS_CANCELED.inc();
         }
         res
     }
 
+    /// Shuts down the block store. All queries are cancelled, pending pushes complete
+    /// with [`ShutDown`] and inbound requests that weren't answered yet are answered
+    /// with errors. The db thread is joined once the queued inserts are flushed and the
+    /// returned future resolves to the store that was passed to `new` or `new_async`.
+    /// Fails with [`WrongStoreType`] if that store isn't an `S`.
+    ///
+    /// After shutting down, inbound requests are no longer answered and new queries fail.
+    pub fn shutdown<S: Any + Send>(&mut self) -> impl Future<Output = Result<S>> + Send {
+        for id in self.push_manager.cancel_all() {
+            tracing::trace!("{} shutdown cancel", id);
+            REQUESTS_CANCELED.inc();
+            let event = BitswapEvent::Complete(id, Err(ShutDown.into()));
+            self.events.push_back(event);
+        }
+        let reconciles = self.reconciles.drain().map(|(id, _)| id);
+        for id in self
+            .query_manager
+            .cancel_all()
+            .into_iter()
+            .chain(reconciles)
+        {
+            tracing::trace!("{} shutdown cancel", id);
+            REQUESTS_CANCELED.inc();
+        }
+        self.requests.clear();
+        self.outbox.clear();
+        self.dag_requests.clear();
+        self.filters.clear();
+        self.pipelined.clear();
+        self.pipeline_out.clear();
+        self.backoffs.clear();
+        self.local_responses.clear();
+        for (_, batch) in std::mem::take(&mut self.inbound) {
+            let responses = batch
+                .responses
+                .into_iter()
+                .map(|response| response.unwrap_or(BitswapResponse::Error))
+                .collect();
+            let responses = BitswapResponses {
+                protocol: batch.protocol,
+                capabilities: Capabilities::local(),
+                responses,
+            };
+            self.send_batch(batch.channel, responses);
+        }
+        let (tx, _) = mpsc::channel(0);
+        let (_, rx) = mpsc::channel(0);
+        let mut db_tx = std::mem::replace(&mut self.db_tx, tx);
+        let db_rx = std::mem::replace(&mut self.db_rx, rx);
+        let pending = std::mem::take(&mut self.db_pending);
+        let worker = self.worker.take();
+        async move {
+            // nobody is waiting for responses anymore
+            drop(db_rx);
+            let worker = worker.ok_or(ShutDown)?;
+            for request in pending {
+                if let DbRequest::Insert(_, _, _) | DbRequest::InsertDag(_) = request {
+                    if db_tx.send(request).await.is_ok() {
+                        DB_QUEUE_DEPTH.inc();
+                    }
+                }
+            }
+            drop(db_tx);
+            let store = worker.store.await.map_err(|_| ShutDown)?;
+            // handing back the store is the last thing the thread does
+            worker.thread.join().map_err(|_| ShutDown)?;
+            match store.downcast() {
+                Ok(store) => Ok(*store),
+                Err(_) => Err(WrongStoreType(std::any::type_name::<S>()).into()),
+            }
+        }
+    }
+
     /// Registers prometheus metrics.
     pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
         registry.register(Box::new(REQUESTS_TOTAL.clone()))?;
@@ -184,156 +932,1008 @@ impl<P: StoreParams> Bitswap<P> {
         registry.register(Box::new(MISSING_BLOCKS_TOTAL.clone()))?;
         registry.register(Box::new(RECEIVED_BLOCK_BYTES.clone()))?;
         registry.register(Box::new(RECEIVED_INVALID_BLOCK_BYTES.clone()))?;
+        registry.register(Box::new(REJECTED_BLOCK_BYTES.clone()))?;
         registry.register(Box::new(SENT_BLOCK_BYTES.clone()))?;
         registry.register(Box::new(RESPONSES_TOTAL.clone()))?;
         registry.register(Box::new(THROTTLED_INBOUND.clone()))?;
         registry.register(Box::new(THROTTLED_OUTBOUND.clone()))?;
         registry.register(Box::new(OUTBOUND_FAILURE.clone()))?;
         registry.register(Box::new(INBOUND_FAILURE.clone()))?;
+        registry.register(Box::new(DB_QUEUE_DEPTH.clone()))?;
+        registry.register(Box::new(SHED_INBOUND.clone()))?;
+        registry.register(Box::new(MISBEHAVIOUR_TOTAL.clone()))?;
+        registry.register(Box::new(PEERS_BANNED.clone()))?;
+        registry.register(Box::new(PUSHED_BLOCKS.clone()))?;
+        registry.register(Box::new(BROADCAST_REQUESTS.clone()))?;
+        #[cfg(feature = "compression")]
+        registry.register(Box::new(COMPRESSION_RATIO.clone()))?;
         Ok(())
     }
 }
 
-enum DbRequest<P: StoreParams> {
-    Bitswap(BitswapChannel, BitswapRequest),
-    Insert(Block<P>),
-    MissingBlocks(QueryId, Cid),
-}
+impl<P: StoreParams> Bitswap<P> {
+    /// Processes an incoming native request message.
+    fn inject_requests(
+        &mut self,
+        peer: PeerId,
+        channel: InboundChannel,
+        requests: BitswapRequests,
+    ) {
+        self.learn_native(peer, requests.protocol, requests.capabilities);
+        if self.reputation.is_banned(&peer) {
+            tracing::debug!("ignoring request from banned peer {}", peer);
+            return;
+        }
+        let id = self.next_inbound;
+        self.next_inbound += 1;
+        let batch = InboundBatch {
+            protocol: requests.protocol,
+            channel,
+            responses: requests.requests.iter().map(|_| None).collect(),
+        };
+        self.inbound.insert(id, batch);
+        for (i, request) in requests.requests.into_iter().enumerate() {
+            self.inject_request(peer, BitswapChannel::Bitswap(id, i), request);
+        }
+    }
 
-enum DbResponse {
-    Bitswap(BitswapChannel, BitswapResponse),
-    MissingBlocks(QueryId, Result<Vec<Cid>>),
-}
+    /// Answers an entry of an inbound native request message. The message is
+    /// answered once all its entries are.
+    fn send_response(&mut self, id: u64, index: usize, response: BitswapResponse) {
+        let batch = if let Some(batch) = self.inbound.get_mut(&id) {
+            batch
+        } else {
+            return;
+        };
+        batch.responses[index] = Some(response);
+        if batch.responses.iter().all(Option::is_some) {
+            let batch = self.inbound.remove(&id).unwrap();
+            let responses = BitswapResponses {
+                protocol: batch.protocol,
+                capabilities: Capabilities::local(),
+                responses: batch.responses.into_iter().flatten().collect(),
+            };
+            self.send_batch(batch.channel, responses);
+        }
+    }
 
-fn start_db_thread<S: BitswapStore>(
-    mut store: S,
-) -> (
-    mpsc::UnboundedSender<DbRequest<S::Params>>,
-    mpsc::UnboundedReceiver<DbResponse>,
-) {
-    let (tx, requests) = mpsc::unbounded();
-    let (responses, rx) = mpsc::unbounded();
-    std::thread::spawn(move || {
-        let mut requests: mpsc::UnboundedReceiver<DbRequest<S::Params>> = requests;
-        while let Some(request) = futures::executor::block_on(requests.next()) {
-            match request {
-                DbRequest::Bitswap(channel, request) => {
-                    let response = match request.ty {
-                        RequestType::Have => {
-                            let have = store.contains(&request.cid).ok().unwrap_or_default();
-                            if have {
-                                RESPONSES_TOTAL.with_label_values(&["have"]).inc();
-                            } else {
-                                RESPONSES_TOTAL.with_label_values(&["dont_have"]).inc();
-                            }
-                            tracing::trace!("have {}", have);
-                            BitswapResponse::Have(have)
-                        }
-                        RequestType::Block => {
-                            let block = store.get(&request.cid).ok().unwrap_or_default();
-                            if let Some(data) = block {
-                                RESPONSES_TOTAL.with_label_values(&["block"]).inc();
-                                SENT_BLOCK_BYTES.inc_by(data.len() as u64);
-                                tracing::trace!("block {}", data.len());
-                                BitswapResponse::Block(data)
-                            } else {
-                                RESPONSES_TOTAL.with_label_values(&["dont_have"]).inc();
-                                tracing::trace!("have false");
-                                BitswapResponse::Have(false)
-                            }
-                        }
-                    };
-                    responses
-                        .unbounded_send(DbResponse::Bitswap(channel, response))
-                        .ok();
+    /// Answers an inbound dag request, which is the only entry of its message.
+    fn send_dag_response(&mut self, id: u64, responses: Vec<BitswapResponse>) {
+        if let Some(batch) = self.inbound.remove(&id) {
+            let responses = BitswapResponses {
+                protocol: batch.protocol,
+                capabilities: Capabilities::local(),
+                responses,
+            };
+            self.send_batch(batch.channel, responses);
+        }
+    }
+
+    /// Sends the responses to an inbound native request message.
+    fn send_batch(&mut self, channel: InboundChannel, responses: BitswapResponses) {
+        match channel {
+            InboundChannel::Request(channel) => {
+                self.inner.send_response(channel, responses).ok();
+            }
+            InboundChannel::Pipeline(peer, conn, id) => {
+                let msg = PipelineIn::Response(id, responses);
+                self.pipeline_out.push_back((peer, conn, msg));
+            }
+        }
+    }
+
+    /// Returns true if the block a sync query requests from a peer is requested with
+    /// its descendants.
+    fn request_dag(&self, id: QueryId, peer: &PeerId) -> bool {
+        if self.dag_references().is_none()
+            || self.config.dag_depth == Some(0)
+            || !self.query_manager.in_sync(id)
+        {
+            return false;
+        }
+        #[cfg(feature = "compat")]
+        if self.use_compat(peer) {
+            return false;
+        }
+        self.peers
+            .get(peer)
+            .map(|state| state.capabilities.contains(Capabilities::DAG))
+            .unwrap_or_default()
+    }
+
+    /// Builds the request for the block a query asks a peer for.
+    fn block_request(&self, id: QueryId, peer: &PeerId, cid: Cid) -> BitswapRequest {
+        let ty = if self.request_dag(id, peer) {
+            // only the request for the root carries the filter, the requests for the
+            // blocks its response left out would each send it again
+            let query = self.query_manager.query_info(id);
+            let first = query
+                .and_then(|query| self.query_manager.query_info(query.root))
+                .map(|root| root.cid == cid)
+                .unwrap_or_default();
+            let filter = query
+                .filter(|_| first)
+                .and_then(|query| self.filters.get(&query.root));
+            match filter {
+                Some(filter) => RequestType::Reconcile(self.config.dag_depth, filter.clone()),
+                None => RequestType::Dag(self.config.dag_depth),
+            }
+        } else {
+            RequestType::Block
+        };
+        BitswapRequest { ty, cid }
+    }
+
+    /// Queues a request that is sent at the end of the poll.
+    fn queue_request(&mut self, peer: PeerId, id: QueryId, request: BitswapRequest) {
+        self.outbox.entry(peer).or_default().push((id, request));
+    }
+
+    /// Sends the queued requests. Requests to peers that support batching are sent
+    /// in as few messages as possible. Requests beyond `max_concurrent_requests` stay
+    /// queued until responses arrive.
+    fn flush_requests(&mut self) {
+        let limit = self.config.max_concurrent_requests;
+        let in_flight = if limit > 0 && !self.outbox.is_empty() {
+            self.in_flight()
+        } else {
+            Default::default()
+        };
+        for (peer, mut requests) in std::mem::take(&mut self.outbox) {
+            if limit > 0 {
+                // requests of cancelled queries are dropped
+                requests.retain(|(id, _)| self.query_manager.query_info(*id).is_some());
+                let free = limit.saturating_sub(in_flight.get(&peer).copied().unwrap_or_default());
+                if requests.len() > free {
+                    self.outbox.insert(peer, requests.split_off(free));
                 }
-                DbRequest::Insert(block) => {
-                    if let Err(err) = store.insert(&block) {
-                        tracing::error!("error inserting blocks {}", err);
-                    }
+                if requests.is_empty() {
+                    continue;
+                }
+            }
+            #[cfg(feature = "compat")]
+            if self.use_compat(&peer) {
+                for (id, request) in requests {
+                    self.requests.insert(BitswapId::Compat(request.cid), id);
+                    self.compat_out
+                        .push_back((peer, CompatMessage::Request(request)));
+                }
+                continue;
+            }
+            if !self.config.enable_native {
+                for (id, _) in requests {
+                    self.query_manager
+                        .inject_response(id, Response::Have(peer, false));
+                }
+                continue;
+            }
+            let (dags, requests): (Vec<_>, Vec<_>) = requests
+                .into_iter()
+                .partition(|(_, request)| request.is_dag());
+            for (id, request) in dags {
+                let depth = match request.ty {
+                    RequestType::Dag(depth) | RequestType::Reconcile(depth, _) => depth,
+                    _ => None,
+                };
+                // the descendants fill the response message
+                let mid = self.send_message(peer, vec![request]);
+                self.requests.insert(BitswapId::Bitswap(mid, 0), id);
+                self.dag_requests.insert(mid, depth);
+            }
+            let batch = self.pipeline_connection(&peer).is_some()
+                || self
+                    .peers
+                    .get(&peer)
+                    .map(|state| {
+                        state.protocols.iter().any(|p| {
+                            !matches!(p, SupportedProtocol::Native | SupportedProtocol::Compat)
+                        })
+                    })
+                    .unwrap_or_default();
+            let size = if batch { MAX_BATCH_ENTRIES } else { 1 };
+            let mut requests = requests.into_iter();
+            loop {
+                let (ids, requests): (Vec<_>, Vec<_>) = requests.by_ref().take(size).unzip();
+                if ids.is_empty() {
+                    break;
                 }
-                DbRequest::MissingBlocks(id, cid) => {
-                    let res = store.missing_blocks(&cid);
-                    responses
-                        .unbounded_send(DbResponse::MissingBlocks(id, res))
-                        .ok();
+                let mid = self.send_message(peer, requests);
+                for (i, id) in ids.into_iter().enumerate() {
+                    self.requests.insert(BitswapId::Bitswap(mid, i), id);
                 }
             }
         }
-    });
-    (tx, rx)
-}
+    }
 
-impl<P: StoreParams> Bitswap<P> {
-    /// Processes an incoming bitswap request.
-    fn inject_request(&mut self, channel: BitswapChannel, request: BitswapRequest) {
-        self.db_tx
-            .unbounded_send(DbRequest::Bitswap(channel, request))
-            .ok();
+    /// Returns the connection whose pipelined substream requests to a peer are sent
+    /// on, unless they are sent with request-response.
+    fn pipeline_connection(&self, peer: &PeerId) -> Option<ConnectionId> {
+        if !self.config.enable_native || !self.config.pipelining {
+            return None;
+        }
+        let state = self.peers.get(peer)?;
+        if state.pipeline_unsupported {
+            return None;
+        }
+        state.connections.first().copied()
     }
 
-    /// Processes an incoming bitswap response.
-    fn inject_response(&mut self, id: BitswapId, peer: PeerId, response: BitswapResponse) {
-        if let Some(id) = self.requests.remove(&id) {
-            match response {
-                BitswapResponse::Have(have) => {
-                    self.query_manager
-                        .inject_response(id, Response::Have(peer, have));
-                }
-                BitswapResponse::Block(data) => {
-                    if let Some(info) = self.query_manager.query_info(id) {
-                        let len = data.len();
-                        if let Ok(block) = Block::new(info.cid, data) {
-                            RECEIVED_BLOCK_BYTES.inc_by(len as u64);
-                            self.db_tx.unbounded_send(DbRequest::Insert(block)).ok();
-                            self.query_manager
-                                .inject_response(id, Response::Block(peer, true));
-                        } else {
-                            tracing::error!("received invalid block");
-                            RECEIVED_INVALID_BLOCK_BYTES.inc_by(len as u64);
-                            self.query_manager
-                                .inject_response(id, Response::Block(peer, false));
-                        }
-                    }
+    /// Sends a native request message, pipelined if the peer supports it.
+    fn send_message(&mut self, peer: PeerId, requests: Vec<BitswapRequest>) -> MessageId {
+        let requests = BitswapRequests {
+            protocol: BitswapProtocol::Capabilities,
+            capabilities: Capabilities::local(),
+            requests,
+        };
+        if let Some(conn) = self.pipeline_connection(&peer) {
+            let id = self.next_pipelined;
+            self.next_pipelined += 1;
+            self.pipelined.insert(id, conn);
+            self.pipeline_out
+                .push_back((peer, conn, PipelineIn::Request(id, requests)));
+            MessageId::Pipeline(id)
+        } else {
+            MessageId::Request(self.inner.send_request(&peer, requests))
+        }
+    }
+
+    /// Processes an incoming bitswap request.
+    fn inject_request(&mut self, peer: PeerId, channel: BitswapChannel, request: BitswapRequest) {
+        if self.reputation.is_banned(&peer) {
+            tracing::debug!("ignoring request from banned peer {}", peer);
+            return;
+        }
+        if let RequestType::Push(data) = request.ty {
+            self.inject_push(peer, Some(channel), request.cid, data);
+            return;
+        }
+        let allowed = self
+            .access_policy
+            .as_ref()
+            .map(|policy| policy.allow(&peer, &request.cid))
+            .unwrap_or(true);
+        if !allowed {
+            tracing::debug!("refusing to serve {} to {}", request.cid, peer);
+            RESPONSES_TOTAL.with_label_values(&["unauthorized"]).inc();
+            self.local_responses
+                .push_back(DbResponse::Bitswap(channel, BitswapResponse::Unauthorized));
+            return;
+        }
+        // without references the store answers it like a block request
+        if let (BitswapChannel::Bitswap(id, _), Some(references)) =
+            (&channel, self.dag_references())
+        {
+            let (depth, filter) = match request.ty {
+                RequestType::Dag(depth) => (depth, None),
+                RequestType::Reconcile(depth, filter) => (depth, Some(filter)),
+                ty => {
+                    let request = BitswapRequest { ty, ..request };
+                    self.send_inbound(DbRequest::Bitswap(channel, request));
+                    return;
                 }
-            }
+            };
+            let request = DagRequest {
+                peer,
+                root: request.cid,
+                depth,
+                filter,
+                references,
+                access_policy: self.access_policy.clone(),
+            };
+            self.send_inbound(DbRequest::Dag(*id, request));
+            return;
         }
+        self.send_inbound(DbRequest::Bitswap(channel, request));
     }
 
-    fn inject_outbound_failure(
+    /// Processes a block pushed by a peer.
+    fn inject_push(
         &mut self,
-        peer: &PeerId,
-        request_id: RequestId,
-        error: &OutboundFailure,
+        peer: PeerId,
+        channel: Option<BitswapChannel>,
+        cid: Cid,
+        data: Bytes,
     ) {
-        tracing::debug!(
-            "bitswap outbound failure {} {} {:?}",
-            peer,
-            request_id,
-            error
-        );
-        match error {
-            OutboundFailure::DialFailure => {
-                OUTBOUND_FAILURE.with_label_values(&["dial_failure"]).inc();
-            }
-            OutboundFailure::Timeout => {
-                OUTBOUND_FAILURE.with_label_values(&["timeout"]).inc();
-            }
-            OutboundFailure::ConnectionClosed => {
-                OUTBOUND_FAILURE
-                    .with_label_values(&["connection_closed"])
-                    .inc();
-            }
-            OutboundFailure::UnsupportedProtocols => {
-                OUTBOUND_FAILURE
-                    .with_label_values(&["unsupported_protocols"])
-                    .inc();
+        let len = data.len();
+        let block = if let Ok(block) = Block::<P>::new(cid, data.into()) {
+            block
+        } else {
+            tracing::error!("received invalid pushed block");
+            RECEIVED_INVALID_BLOCK_BYTES.inc_by(len as u64);
+            self.report(peer, Misbehaviour::InvalidBlock);
+            self.refuse_push(channel);
+            return;
+        };
+        let valid = match self.validator.as_ref().map(|v| v.validate(&block)) {
+            Some(Err(err)) => {
+                tracing::debug!("rejected pushed block {}: {}", cid, err);
+                false
             }
+            _ => true,
+        };
+        let accepted = valid
+            && self
+                .push_policy
+                .as_ref()
+                .map(|policy| policy.accept(&peer, &block))
+                .unwrap_or_default();
+        if !accepted {
+            tracing::debug!("refusing block {} pushed by {}", cid, peer);
+            REJECTED_BLOCK_BYTES.inc_by(len as u64);
+            self.refuse_push(channel);
+            return;
         }
+        RECEIVED_BLOCK_BYTES.inc_by(len as u64);
+        self.send_inbound(DbRequest::Push(channel, block));
     }
 
-    fn inject_inbound_failure(
-        &mut self,
+    fn refuse_push(&mut self, channel: Option<BitswapChannel>) {
+        PUSHED_BLOCKS.with_label_values(&["refused"]).inc();
+        if let Some(channel) = channel {
+            self.local_responses
+                .push_back(DbResponse::Bitswap(channel, BitswapResponse::Have(false)));
+        }
+    }
+
+    /// Queues a db request on behalf of a peer. If the db request channel is full the
+    /// request is shed, if the db thread stopped it is answered with an error.
+    fn send_inbound(&mut self, request: DbRequest<P>) {
+        let (request, response, label) = match self.db_tx.try_send(request) {
+            Ok(()) => {
+                DB_QUEUE_DEPTH.inc();
+                return;
+            }
+            Err(err) if err.is_full() => {
+                tracing::debug!("db queue full, shedding inbound request");
+                SHED_INBOUND.inc();
+                let response = BitswapResponse::Busy(self.config.busy_retry_after);
+                (err.into_inner(), response, "busy")
+            }
+            // the db thread is gone, the request would never be answered
+            Err(err) => (err.into_inner(), BitswapResponse::Error, "error"),
+        };
+        let channel = match request {
+            DbRequest::Bitswap(channel, _) | DbRequest::Push(Some(channel), _) => channel,
+            DbRequest::Dag(id, _) => BitswapChannel::Bitswap(id, 0),
+            _ => return,
+        };
+        RESPONSES_TOTAL.with_label_values(&[label]).inc();
+        self.local_responses
+            .push_back(DbResponse::Bitswap(channel, response));
+    }
+
+    /// Sends a block read from the store to the peer it is pushed to.
+    fn send_push(&mut self, id: QueryId, block: PushedBlock) {
+        let peer = if let Some(peer) = self.push_manager.peer(id) {
+            peer
+        } else {
+            return;
+        };
+        #[cfg(feature = "compat")]
+        if self.use_compat(&peer) {
+            let request = Self::push_request(&block);
+            self.send_compat_push(id, peer, request, block);
+            return;
+        }
+        if !self.config.enable_native {
+            self.fail_push(id, ProtocolDisabled(peer).into());
+            return;
+        }
+        match self.push_support(&peer) {
+            Some(false) => {
+                self.fail_push(id, PushUnsupported(peer).into());
+                return;
+            }
+            None if self.pipeline_connection(&peer).is_none() => {
+                // the response tells if the block can be pushed
+                let request = BitswapRequest {
+                    ty: RequestType::Have,
+                    cid: block.cid,
+                };
+                let mid = self.send_message(peer, vec![request]);
+                self.push_manager.probed(mid, id, block);
+                return;
+            }
+            _ => {}
+        }
+        let mid = self.send_message(peer, vec![Self::push_request(&block)]);
+        self.push_manager.sent(mid, id, block);
+    }
+
+    /// Builds the request that pushes a block, which shares the data of the block.
+    fn push_request(block: &PushedBlock) -> BitswapRequest {
+        PUSHED_BLOCKS.with_label_values(&["sent"]).inc();
+        SENT_BLOCK_BYTES.inc_by(block.data.len() as u64);
+        BitswapRequest {
+            ty: RequestType::Push(block.data.clone()),
+            cid: block.cid,
+        }
+    }
+
+    /// Returns whether a peer accepts pushed blocks, or `None` if it didn't announce
+    /// its capabilities yet. Peers that only used the versions without capabilities
+    /// don't speak the newer ones, they would have been negotiated otherwise.
+    fn push_support(&self, peer: &PeerId) -> Option<bool> {
+        let state = self.peers.get(peer)?;
+        if state.capabilities != Capabilities::empty() {
+            return Some(state.capabilities.contains(Capabilities::PUSH));
+        }
+        let announces = state.protocols.iter().any(|protocol| {
+            matches!(
+                protocol,
+                SupportedProtocol::NativeCapabilities | SupportedProtocol::NativePipeline
+            )
+        });
+        let native = state.protocols.iter().any(|protocol| {
+            matches!(
+                protocol,
+                SupportedProtocol::Native | SupportedProtocol::NativeBatch
+            )
+        });
+        if native && !announces {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Sends a pushed block as an unsolicited bitswap 1.2.0 payload. The peer doesn't
+    /// acknowledge it, so it counts as stored once it is written to the substream.
+    #[cfg(feature = "compat")]
+    fn send_compat_push(
+        &mut self,
+        id: QueryId,
+        peer: PeerId,
+        request: BitswapRequest,
+        block: PushedBlock,
+    ) {
+        let mid = MessageId::Compat(peer, request.cid);
+        self.compat_out
+            .push_back((peer, CompatMessage::Request(request)));
+        self.push_manager.sent(mid, id, block);
+    }
+
+    /// Continues a push once a block was stored by the peer.
+    fn push_acked(&mut self, id: QueryId, block: &PushedBlock) {
+        for cid in self.push_manager.ack(id, block) {
+            self.send_db_request(DbRequest::Get(id, cid));
+        }
+        if self.push_manager.complete(id) {
+            tracing::trace!("{} push ok", id);
+            self.events.push_back(BitswapEvent::Complete(id, Ok(())));
+        }
+    }
+
+    fn fail_push(&mut self, id: QueryId, err: libipld::error::Error) {
+        if self.push_manager.cancel(id) {
+            tracing::debug!("{} push err {}", id, err);
+            self.events.push_back(BitswapEvent::Complete(id, Err(err)));
+        }
+    }
+
+    /// Processes the response to a push request.
+    fn inject_push_response(
+        &mut self,
+        id: QueryId,
+        block: PushedBlock,
+        peer: PeerId,
+        response: BitswapResponse,
+    ) {
+        if let BitswapResponse::Have(true) | BitswapResponse::HaveSize(_) = response {
+            self.push_acked(id, &block);
+        } else {
+            self.fail_push(id, PushRejected(peer, block.cid).into());
+        }
+    }
+
+    /// Queues a db request that must not be dropped. If the db request channel is full
+    /// the request is sent once there is space again.
+    fn send_db_request(&mut self, request: DbRequest<P>) {
+        if !self.db_pending.is_empty() {
+            self.queue_db_request(request);
+            return;
+        }
+        match self.db_tx.try_send(request) {
+            Ok(()) => DB_QUEUE_DEPTH.inc(),
+            Err(err) if err.is_full() => self.queue_db_request(err.into_inner()),
+            Err(err) => match err.into_inner() {
+                DbRequest::Insert(id, _, _)
+                | DbRequest::MissingBlocks(id, _)
+                | DbRequest::Contains(id, _) => {
+                    if let Some(event) = self.fail_query(id, ShutDown.into()) {
+                        self.events.push_back(event);
+                    }
+                }
+                DbRequest::Get(id, _) => self.fail_push(id, ShutDown.into()),
+                DbRequest::Filter(id, _, _, _) => {
+                    if self.reconciles.remove(&id).is_some() {
+                        let event = BitswapEvent::Complete(id, Err(ShutDown.into()));
+                        self.events.push_back(event);
+                    }
+                }
+                DbRequest::Bitswap(_, _)
+                | DbRequest::Dag(_, _)
+                | DbRequest::InsertDag(_)
+                | DbRequest::Push(_, _) => {}
+            },
+        }
+    }
+
+    /// Queues a db request until there is space in the db request channel. Blocks
+    /// received from peers are shed once as many requests are pending as the channel
+    /// holds, so a flood of responses can't grow the queue. Shed blocks are requested
+    /// again once the queue drained, descendants of a dag are found missing by the sync
+    /// that requested them. The other requests are issued by the api and are bounded by
+    /// the queries it started.
+    fn queue_db_request(&mut self, request: DbRequest<P>) {
+        if self.db_pending.len() < self.config.db_queue_capacity.max(1) {
+            self.db_pending.push_back(request);
+            return;
+        }
+        match request {
+            DbRequest::Insert(id, _, block) => {
+                tracing::debug!("db queue full, shedding received block {}", block.cid());
+                // new requests are only sent while nothing is pending
+                self.query_manager.inject_response(id, Response::Backoff);
+            }
+            DbRequest::InsertDag(block) => {
+                tracing::debug!("db queue full, shedding received block {}", block.cid());
+            }
+            request => self.db_pending.push_back(request),
+        }
+    }
+
+    /// Sends pending db requests until the db request channel is full.
+    fn poll_db_pending(&mut self, cx: &mut Context) {
+        while !self.db_pending.is_empty() {
+            match self.db_tx.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let request = self.db_pending.pop_front().unwrap();
+                    if self.db_tx.start_send(request).is_ok() {
+                        DB_QUEUE_DEPTH.inc();
+                    }
+                }
+                Poll::Ready(Err(_)) => self.db_pending.clear(),
+                Poll::Pending => break,
+            }
+        }
+    }
+
+    /// Returns the next response to a db request.
+    fn poll_db_response(&mut self, cx: &mut Context) -> Option<DbResponse> {
+        if let Some(response) = self.local_responses.pop_front() {
+            return Some(response);
+        }
+        match Pin::new(&mut self.db_rx).poll_next(cx) {
+            Poll::Ready(response) => response,
+            Poll::Pending => None,
+        }
+    }
+
+    /// Processes an incoming native response message.
+    fn inject_responses(
+        &mut self,
+        request_id: MessageId,
+        peer: PeerId,
+        responses: BitswapResponses,
+    ) {
+        self.learn_native(peer, responses.protocol, responses.capabilities);
+        if let Some((id, block, probe)) = self.push_manager.take_request(&request_id) {
+            if !responses.capabilities.contains(Capabilities::PUSH) {
+                self.fail_push(id, PushUnsupported(peer).into());
+                return;
+            }
+            if probe {
+                self.send_push(id, block);
+                return;
+            }
+            let response = responses.responses.into_iter().next();
+            let response = response.unwrap_or(BitswapResponse::Have(false));
+            self.inject_push_response(id, block, peer, response);
+            return;
+        }
+        if let Some(depth) = self.dag_requests.remove(&request_id) {
+            self.inject_dag_response(request_id, peer, depth, responses.responses);
+            return;
+        }
+        let len = responses.responses.len();
+        for (i, response) in responses.responses.into_iter().enumerate() {
+            self.inject_response(BitswapId::Bitswap(request_id, i), peer, response);
+        }
+        // a message with fewer responses than requests failed to answer the rest
+        for i in len.. {
+            if let Some(id) = self.requests.remove(&BitswapId::Bitswap(request_id, i)) {
+                self.query_manager
+                    .inject_response(id, Response::Error(peer));
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Processes an incoming bitswap response.
+    fn inject_response(&mut self, id: BitswapId, peer: PeerId, response: BitswapResponse) {
+        let id = match (self.requests.remove(&id), id, response) {
+            (Some(query), _, response) => (query, response),
+            #[cfg(feature = "compat")]
+            (None, BitswapId::Compat(cid), BitswapResponse::Block(data))
+                if self.push_policy.is_some() =>
+            {
+                // bitswap 1.2.0 peers push blocks as unsolicited payloads
+                if !self.reputation.is_banned(&peer) {
+                    self.inject_push(peer, None, cid, data);
+                }
+                return;
+            }
+            _ => {
+                self.report(peer, Misbehaviour::UnsolicitedResponse);
+                return;
+            }
+        };
+        let (id, response) = id;
+        match response {
+            BitswapResponse::Have(have) => {
+                self.query_manager
+                    .inject_response(id, Response::Have(peer, have));
+            }
+            BitswapResponse::HaveSize(size) => {
+                self.query_manager
+                    .inject_response(id, Response::HaveSize(peer, size));
+            }
+            BitswapResponse::Block(data) => {
+                if let Some(info) = self.query_manager.query_info(id) {
+                    if let Some(block) = self.verify_block(peer, info.cid, data) {
+                        // the query makes progress once the block is stored
+                        self.send_db_request(DbRequest::Insert(id, peer, block));
+                    } else {
+                        self.query_manager
+                            .inject_response(id, Response::Block(peer, false));
+                    }
+                }
+            }
+            BitswapResponse::DagBlock(_, _) => {
+                self.report(peer, Misbehaviour::UnsolicitedResponse);
+                self.query_manager
+                    .inject_response(id, Response::Block(peer, false));
+            }
+            BitswapResponse::Busy(retry_after) => {
+                // a peer can't hold up a query for longer than a request timeout
+                let retry_after = retry_after.min(self.config.request_timeout);
+                self.query_manager
+                    .inject_response(id, Response::Busy(peer, retry_after));
+            }
+            BitswapResponse::Unauthorized => {
+                self.query_manager
+                    .inject_response(id, Response::Unauthorized(peer));
+            }
+            BitswapResponse::Error => {
+                self.query_manager
+                    .inject_response(id, Response::Error(peer));
+            }
+        }
+    }
+
+    /// Checks that a received block hashes to its cid and passes the validator.
+    fn verify_block(&mut self, peer: PeerId, cid: Cid, data: Bytes) -> Option<Block<P>> {
+        let len = data.len();
+        // takes over the buffer unless it is shared
+        let block = if let Ok(block) = Block::new(cid, data.into()) {
+            block
+        } else {
+            tracing::error!("received invalid block");
+            RECEIVED_INVALID_BLOCK_BYTES.inc_by(len as u64);
+            self.report(peer, Misbehaviour::InvalidBlock);
+            return None;
+        };
+        RECEIVED_BLOCK_BYTES.inc_by(len as u64);
+        if let Some(Err(err)) = self.validator.as_ref().map(|v| v.validate(&block)) {
+            tracing::error!("rejected block {}: {}", block.cid(), err);
+            REJECTED_BLOCK_BYTES.inc_by(len as u64);
+            self.report(peer, Misbehaviour::InvalidBlock);
+            return None;
+        }
+        Some(block)
+    }
+
+    /// Processes the response to a dag request. The descendants are stored along with
+    /// the block, so that the sync query only asks for the blocks that are still
+    /// missing once the block is stored.
+    fn inject_dag_response(
+        &mut self,
+        request_id: MessageId,
+        peer: PeerId,
+        depth: Option<u64>,
+        responses: Vec<BitswapResponse>,
+    ) {
+        let mut responses = responses.into_iter();
+        let root = responses.next().unwrap_or(BitswapResponse::Have(false));
+        let bitswap_id = BitswapId::Bitswap(request_id, 0);
+        let query = self
+            .requests
+            .get(&bitswap_id)
+            .and_then(|id| self.query_manager.query_info(*id))
+            .map(|info| (info.id, info.cid));
+        let (id, cid, data, references) = match (query, root, self.dag_references()) {
+            (Some((id, cid)), BitswapResponse::Block(data), Some(references)) => {
+                (id, cid, data, references)
+            }
+            // the descendants of a block that wasn't sent are ignored
+            (_, root, _) => {
+                self.inject_response(bitswap_id, peer, root);
+                return;
+            }
+        };
+        self.requests.remove(&bitswap_id);
+        let root = if let Some(block) = self.verify_block(peer, cid, data) {
+            block
+        } else {
+            self.query_manager
+                .inject_response(id, Response::Block(peer, false));
+            return;
+        };
+        let mut verifier = DagVerifier::new(&root, depth, references);
+        self.send_db_request(DbRequest::Insert(id, peer, root));
+        for response in responses {
+            let block = if let BitswapResponse::DagBlock(cid, data) = response {
+                self.verify_block(peer, cid, data)
+            } else {
+                self.report(peer, Misbehaviour::UnsolicitedResponse);
+                break;
+            };
+            match block {
+                Some(block) if verifier.verify(&block) => {
+                    self.send_db_request(DbRequest::InsertDag(block));
+                }
+                Some(block) => {
+                    tracing::error!("received block {} that isn't linked", block.cid());
+                    self.report(peer, Misbehaviour::UnsolicitedResponse);
+                    break;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Chooses connected peers to ask for a block that no provider has.
+    fn broadcast_peers(&self, exclude: &[PeerId]) -> Vec<PeerId> {
+        let peers = self
+            .peers
+            .keys()
+            .filter(|peer| !exclude.contains(peer) && !self.reputation.is_banned(peer));
+        let peers = peers
+            .copied()
+            .choose_multiple(&mut rand::thread_rng(), self.config.broadcast_peers);
+        BROADCAST_REQUESTS.inc_by(peers.len() as u64);
+        peers
+    }
+
+    /// Keeps the connections to peers needed by a query alive and releases the others.
+    fn update_pins(&mut self) {
+        let mut pinned = self.query_manager.providers();
+        pinned.extend(self.push_manager.peers());
+        for peer in pinned.symmetric_difference(&self.pinned) {
+            let pin = pinned.contains(peer);
+            let conns = self.peers.get(peer).map(|state| &state.connections);
+            for conn in conns.into_iter().flatten() {
+                let command = HandlerCommand::KeepAlive(pin);
+                self.commands.push_back((*peer, *conn, command));
+            }
+        }
+        self.pinned = pinned;
+    }
+
+    fn notify_handler(
+        &self,
+        (peer_id, conn, command): (PeerId, ConnectionId, HandlerCommand),
+    ) -> NetworkBehaviourAction<BitswapEvent, <Self as NetworkBehaviour>::ConnectionHandler> {
+        tracing::trace!("peer {} {:?}", peer_id, command);
+        let event = match command {
+            HandlerCommand::KeepAlive(pin) => EitherOutput::First(HandlerIn::KeepAlive(pin)),
+            HandlerCommand::ListenNative(listen) => EitherOutput::First(HandlerIn::Listen(listen)),
+            HandlerCommand::ListenPipeline(listen) => {
+                EitherOutput::Second(HandlerIn::Listen(listen))
+            }
+            #[cfg(feature = "compat")]
+            HandlerCommand::ListenCompat(listen) => {
+                return NetworkBehaviourAction::NotifyHandler {
+                    peer_id,
+                    handler: NotifyHandler::One(conn),
+                    event: EitherOutput::Second(HandlerIn::Listen(listen)),
+                };
+            }
+        };
+        NetworkBehaviourAction::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::One(conn),
+            event: Self::native_event(event),
+        }
+    }
+
+    /// Addresses an event to the connection handlers of the native protocol.
+    fn native_event(
+        event: <NativeHandler<P> as ConnectionHandler>::InEvent,
+    ) -> <<Self as NetworkBehaviour>::ConnectionHandler as ConnectionHandler>::InEvent {
+        #[cfg(not(feature = "compat"))]
+        return event;
+        #[cfg(feature = "compat")]
+        EitherOutput::First(event)
+    }
+
+    /// Returns the next pipelined message to send.
+    fn poll_pipeline_out(
+        &mut self,
+    ) -> Option<NetworkBehaviourAction<BitswapEvent, <Self as NetworkBehaviour>::ConnectionHandler>>
+    {
+        let (peer_id, conn, msg) = self.pipeline_out.pop_front()?;
+        Some(NetworkBehaviourAction::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::One(conn),
+            event: Self::native_event(EitherOutput::Second(HandlerIn::Inner(msg))),
+        })
+    }
+
+    /// Processes an event of the pipelined protocol.
+    fn inject_pipeline_event(&mut self, peer: PeerId, conn: ConnectionId, event: PipelineEvent) {
+        match event {
+            PipelineEvent::Request(id, requests) => {
+                self.inject_requests(peer, InboundChannel::Pipeline(peer, conn, id), requests)
+            }
+            PipelineEvent::Response(id, responses) => {
+                self.pipelined.remove(&id);
+                self.inject_responses(MessageId::Pipeline(id), peer, responses);
+            }
+            PipelineEvent::Failed(ids, failure) => {
+                for id in ids {
+                    self.inject_pipeline_failure(peer, id, failure);
+                }
+            }
+            PipelineEvent::Negotiated(version) => {
+                // the capabilities arrive with the first message
+                self.learn_native(peer, version, Capabilities::empty())
+            }
+            PipelineEvent::Misbehaviour(misbehaviour) => self.report(peer, misbehaviour),
+        }
+    }
+
+    /// Fails the requests of a pipelined message. If the peer doesn't support
+    /// pipelining, they are sent again with request-response.
+    fn inject_pipeline_failure(&mut self, peer: PeerId, id: u64, failure: PipelineFailure) {
+        tracing::debug!("bitswap pipeline failure {} {} {:?}", peer, id, failure);
+        if self.pipelined.remove(&id).is_none() {
+            return;
+        }
+        let label = match failure {
+            PipelineFailure::Unsupported => "unsupported_protocols",
+            PipelineFailure::Timeout => "timeout",
+            PipelineFailure::Closed => "connection_closed",
+        };
+        OUTBOUND_FAILURE.with_label_values(&[label]).inc();
+        let unsupported = failure == PipelineFailure::Unsupported;
+        if unsupported {
+            if let Some(state) = self.peers.get_mut(&peer) {
+                state.pipeline_unsupported = true;
+            }
+        }
+        if let Some((push, block, _)) = self.push_manager.take_request(&MessageId::Pipeline(id)) {
+            if unsupported {
+                self.send_push(push, block);
+            } else {
+                let err = libipld::error::Error::msg(format!(
+                    "pushing {} failed: {:?}",
+                    block.cid, failure
+                ));
+                self.fail_push(push, err);
+            }
+            return;
+        }
+        self.dag_requests.remove(&MessageId::Pipeline(id));
+        for i in 0.. {
+            let bitswap_id = BitswapId::Bitswap(MessageId::Pipeline(id), i);
+            let id = if let Some(id) = self.requests.remove(&bitswap_id) {
+                id
+            } else {
+                break;
+            };
+            match self.query_manager.query_info(id) {
+                Some(info) if unsupported => {
+                    let request = match info.label {
+                        "have" => BitswapRequest {
+                            ty: RequestType::Have,
+                            cid: info.cid,
+                        },
+                        _ => self.block_request(id, &peer, info.cid),
+                    };
+                    self.queue_request(peer, id, request);
+                }
+                _ => {
+                    self.query_manager
+                        .inject_response(id, Response::Have(peer, false));
+                }
+            }
+        }
+    }
+
+    /// Records the native protocol version a message was received with and the
+    /// capabilities of its sender.
+    fn learn_native(&mut self, peer: PeerId, protocol: BitswapProtocol, caps: Capabilities) {
+        let protocol = match protocol {
+            BitswapProtocol::Single => SupportedProtocol::Native,
+            BitswapProtocol::Batch => SupportedProtocol::NativeBatch,
+            BitswapProtocol::Capabilities => SupportedProtocol::NativeCapabilities,
+            BitswapProtocol::Pipeline => SupportedProtocol::NativePipeline,
+        };
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.capabilities = state.capabilities | caps;
+        }
+        self.learn_protocol(peer, protocol);
+    }
+
+    /// Records that a peer supports a protocol.
+    fn learn_protocol(&mut self, peer: PeerId, protocol: SupportedProtocol) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            if state.protocols.insert(protocol) {
+                tracing::debug!("peer {} supports {:?}", peer, protocol);
+                let mut protocols: Vec<_> = state.protocols.iter().copied().collect();
+                protocols.sort();
+                self.events
+                    .push_back(BitswapEvent::ProtocolsLearned(peer, protocols));
+            }
+        }
+    }
+
+    /// Records a misbehaviour of a peer.
+    fn report(&mut self, peer: PeerId, misbehaviour: Misbehaviour) {
+        if self.reputation.report(peer, misbehaviour) {
+            self.events.push_back(BitswapEvent::PeerBanned(peer));
+        }
+    }
+
+    /// Cancels the query a subquery belongs to because of a store error.
+    fn fail_query(&mut self, id: QueryId, err: libipld::error::Error) -> Option<BitswapEvent> {
+        let root = self.query_manager.query_info(id)?.root;
+        tracing::error!("{} {} store error {}", root, id, err);
+        self.filters.remove(&root);
+        if self.query_manager.cancel(root) {
+            Some(BitswapEvent::Complete(root, Err(err)))
+        } else {
+            None
+        }
+    }
+
+    fn inject_outbound_failure(
+        &mut self,
+        peer: &PeerId,
+        request_id: RequestId,
+        error: &OutboundFailure,
+    ) {
+        tracing::debug!(
+            "bitswap outbound failure {} {} {:?}",
+            peer,
+            request_id,
+            error
+        );
+        match error {
+            OutboundFailure::DialFailure => {
+                OUTBOUND_FAILURE.with_label_values(&["dial_failure"]).inc();
+            }
+            OutboundFailure::Timeout => {
+                OUTBOUND_FAILURE.with_label_values(&["timeout"]).inc();
+            }
+            OutboundFailure::ConnectionClosed => {
+                OUTBOUND_FAILURE
+                    .with_label_values(&["connection_closed"])
+                    .inc();
+            }
+            OutboundFailure::UnsupportedProtocols => {
+                OUTBOUND_FAILURE
+                    .with_label_values(&["unsupported_protocols"])
+                    .inc();
+            }
+        }
+    }
+
+    fn inject_inbound_failure(
+        &mut self,
         peer: &PeerId,
         request_id: RequestId,
         error: &InboundFailure,
@@ -367,24 +1967,82 @@ impl<P: StoreParams> Bitswap<P> {
     }
 }
 
+#[cfg(feature = "compat")]
+impl<P: StoreParams> Bitswap<P> {
+    /// Returns the next compat message to send.
+    fn poll_compat_out(
+        &mut self,
+    ) -> Option<NetworkBehaviourAction<BitswapEvent, <Self as NetworkBehaviour>::ConnectionHandler>>
+    {
+        let (peer_id, msg) = self.compat_out.pop_front()?;
+        Some(NetworkBehaviourAction::NotifyHandler {
+            peer_id,
+            handler: NotifyHandler::Any,
+            event: EitherOutput::Second(HandlerIn::Inner(msg)),
+        })
+    }
+
+    /// Processes an event of the compat protocol.
+    fn inject_compat_event(&mut self, peer_id: PeerId, event: HandlerEvent<CompatEvent>) {
+        match event {
+            HandlerEvent::Misbehaviour(misbehaviour)
+            | HandlerEvent::Inner(CompatEvent::Misbehaviour(misbehaviour)) => {
+                self.report(peer_id, misbehaviour)
+            }
+            HandlerEvent::Negotiated => self.learn_protocol(peer_id, SupportedProtocol::Compat),
+            HandlerEvent::Inner(CompatEvent::Message(msg)) => {
+                for msg in msg {
+                    match msg {
+                        CompatMessage::Request(req) => {
+                            tracing::trace!("received compat request");
+                            self.inject_request(
+                                peer_id,
+                                BitswapChannel::Compat(peer_id, req.cid),
+                                req,
+                            );
+                        }
+                        CompatMessage::Response(cid, res) => {
+                            tracing::trace!("received compat response");
+                            self.inject_response(BitswapId::Compat(cid), peer_id, res);
+                        }
+                    }
+                }
+            }
+            HandlerEvent::Inner(CompatEvent::Dropped(cids)) => {
+                // the queries move on to the next provider
+                for cid in cids {
+                    if let Some(id) = self.requests.remove(&BitswapId::Compat(cid)) {
+                        self.query_manager
+                            .inject_response(id, Response::Have(peer_id, false));
+                    }
+                    let mid = MessageId::Compat(peer_id, cid);
+                    if let Some((id, block, _)) = self.push_manager.take_request(&mid) {
+                        let err = format!("pushing {} failed: dropped", block.cid);
+                        self.fail_push(id, libipld::error::Error::msg(err));
+                    }
+                }
+            }
+            HandlerEvent::Inner(CompatEvent::Sent(cid)) => {
+                let mid = MessageId::Compat(peer_id, cid);
+                if let Some((id, block, _)) = self.push_manager.take_request(&mid) {
+                    self.push_acked(id, &block);
+                }
+            }
+        }
+    }
+}
+
 impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
     #[cfg(not(feature = "compat"))]
-    type ConnectionHandler =
-        <RequestResponse<BitswapCodec<P>> as NetworkBehaviour>::ConnectionHandler;
+    type ConnectionHandler = NativeHandler<P>;
 
     #[cfg(feature = "compat")]
-    #[allow(clippy::type_complexity)]
-    type ConnectionHandler = ConnectionHandlerSelect<
-        <RequestResponse<BitswapCodec<P>> as NetworkBehaviour>::ConnectionHandler,
-        OneShotHandler<CompatProtocol, CompatMessage, InboundMessage>,
-    >;
+    type ConnectionHandler = ConnectionHandlerSelect<NativeHandler<P>, Handler<CompatHandler>>;
     type OutEvent = BitswapEvent;
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
-        #[cfg(not(feature = "compat"))]
-        return self.inner.new_handler();
-        #[cfg(feature = "compat")]
-        ConnectionHandler::select(self.inner.new_handler(), OneShotHandler::default())
+        let handler = self.inner.new_handler();
+        self.connection_handler(handler)
     }
 
     fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
@@ -393,9 +2051,40 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
 
     fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
         match event {
-            FromSwarm::ConnectionEstablished(ev) => self
-                .inner
-                .on_swarm_event(FromSwarm::ConnectionEstablished(ev)),
+            FromSwarm::ConnectionEstablished(ev) => {
+                self.peers
+                    .entry(ev.peer_id)
+                    .or_insert_with(|| PeerState {
+                        connections: vec![],
+                        connected_since: Instant::now(),
+                        protocols: Default::default(),
+                        capabilities: Capabilities::empty(),
+                        pipeline_unsupported: false,
+                    })
+                    .connections
+                    .push(ev.connection_id);
+                if self.pinned.contains(&ev.peer_id) {
+                    let command = HandlerCommand::KeepAlive(true);
+                    self.commands
+                        .push_back((ev.peer_id, ev.connection_id, command));
+                }
+                // the handler was created before the connection was established and
+                // may have missed a config change since
+                let command = HandlerCommand::ListenNative(self.config.enable_native);
+                self.commands
+                    .push_back((ev.peer_id, ev.connection_id, command));
+                let command = HandlerCommand::ListenPipeline(self.listen_pipeline());
+                self.commands
+                    .push_back((ev.peer_id, ev.connection_id, command));
+                #[cfg(feature = "compat")]
+                {
+                    let command = HandlerCommand::ListenCompat(self.config.enable_compat);
+                    self.commands
+                        .push_back((ev.peer_id, ev.connection_id, command));
+                }
+                self.inner
+                    .on_swarm_event(FromSwarm::ConnectionEstablished(ev))
+            }
             FromSwarm::ConnectionClosed(ConnectionClosed {
                 peer_id,
                 connection_id,
@@ -403,12 +2092,38 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                 handler,
                 remaining_established,
             }) => {
+                // the pipelined substreams closed with the connection
+                let failed: Vec<u64> = self
+                    .pipelined
+                    .iter()
+                    .filter(|(_, conn)| **conn == connection_id)
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in failed {
+                    self.inject_pipeline_failure(peer_id, id, PipelineFailure::Closed);
+                }
                 #[cfg(feature = "compat")]
                 if remaining_established == 0 {
                     self.compat.remove(&peer_id);
+                    // compat pushes that weren't written are lost with the connection
+                    let unsent = self.push_manager.take_requests(
+                        |mid| matches!(mid, MessageId::Compat(peer, _) if *peer == peer_id),
+                    );
+                    for (id, block, _) in unsent {
+                        let err = format!("pushing {} failed: connection closed", block.cid);
+                        self.fail_push(id, libipld::error::Error::msg(err));
+                    }
+                }
+                if remaining_established == 0 {
+                    self.reputation.prune();
+                    self.peers.remove(&peer_id);
+                } else if let Some(state) = self.peers.get_mut(&peer_id) {
+                    state.connections.retain(|conn| *conn != connection_id);
                 }
                 #[cfg(feature = "compat")]
-                let (handler, _oneshot) = handler.into_inner();
+                let (handler, _compat) = handler.into_inner();
+                let (handler, _pipeline) = handler.into_inner();
+                let handler = handler.into_inner();
                 self.inner
                     .on_swarm_event(FromSwarm::ConnectionClosed(ConnectionClosed {
                         peer_id,
@@ -424,7 +2139,9 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                 error,
             }) => {
                 #[cfg(feature = "compat")]
-                let (handler, _oneshot) = handler.into_inner();
+                let (handler, _compat) = handler.into_inner();
+                let (handler, _pipeline) = handler.into_inner();
+                let handler = handler.into_inner();
                 self.inner
                     .on_swarm_event(FromSwarm::DialFailure(DialFailure {
                         peer_id,
@@ -439,7 +2156,9 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                 handler,
             }) => {
                 #[cfg(feature = "compat")]
-                let (handler, _oneshot) = handler.into_inner();
+                let (handler, _compat) = handler.into_inner();
+                let (handler, _pipeline) = handler.into_inner();
+                let handler = handler.into_inner();
                 self.inner
                     .on_swarm_event(FromSwarm::ListenFailure(ListenFailure {
                         local_addr,
@@ -472,27 +2191,25 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
         event: <Self::ConnectionHandler as ConnectionHandler>::OutEvent,
     ) {
         tracing::trace!(?event, "on_connection_handler_event");
-        #[cfg(not(feature = "compat"))]
-        return self.inner.on_connection_handler_event(peer_id, conn, event);
         #[cfg(feature = "compat")]
+        let event = match event {
+            EitherOutput::First(event) => event,
+            EitherOutput::Second(event) => return self.inject_compat_event(peer_id, event),
+        };
         match event {
-            EitherOutput::First(event) => {
+            EitherOutput::First(HandlerEvent::Inner(event)) => {
                 self.inner.on_connection_handler_event(peer_id, conn, event)
             }
-            EitherOutput::Second(msg) => {
-                for msg in msg.0 {
-                    match msg {
-                        CompatMessage::Request(req) => {
-                            tracing::trace!("received compat request");
-                            self.inject_request(BitswapChannel::Compat(peer_id, req.cid), req);
-                        }
-                        CompatMessage::Response(cid, res) => {
-                            tracing::trace!("received compat response");
-                            self.inject_response(BitswapId::Compat(cid), peer_id, res);
-                        }
-                    }
-                }
+            EitherOutput::Second(HandlerEvent::Inner(event)) => {
+                self.inject_pipeline_event(peer_id, conn, event)
+            }
+            EitherOutput::First(HandlerEvent::Misbehaviour(misbehaviour))
+            | EitherOutput::Second(HandlerEvent::Misbehaviour(misbehaviour)) => {
+                self.report(peer_id, misbehaviour)
             }
+            // the version is learned from the messages
+            EitherOutput::First(HandlerEvent::Negotiated)
+            | EitherOutput::Second(HandlerEvent::Negotiated) => {}
         }
     }
 
@@ -501,15 +2218,29 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
         cx: &mut Context,
         pp: &mut impl PollParameters,
     ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
-        let mut exit = false;
-        while !exit {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+        if let Some(command) = self.commands.pop_front() {
+            return Poll::Ready(self.notify_handler(command));
+        }
+        if let Some(event) = self.poll_pipeline_out() {
+            return Poll::Ready(event);
+        }
+        #[cfg(feature = "compat")]
+        if let Some(event) = self.poll_compat_out() {
+            return Poll::Ready(event);
+        }
+        let mut exit = false;
+        while !exit {
             exit = true;
-            while let Poll::Ready(Some(response)) = Pin::new(&mut self.db_rx).poll_next(cx) {
+            self.poll_db_pending(cx);
+            while let Some(response) = self.poll_db_response(cx) {
                 exit = false;
                 match response {
                     DbResponse::Bitswap(channel, response) => match channel {
-                        BitswapChannel::Bitswap(channel) => {
-                            self.inner.send_response(channel, response).ok();
+                        BitswapChannel::Bitswap(id, index) => {
+                            self.send_response(id, index, response);
                         }
                         #[cfg(feature = "compat")]
                         BitswapChannel::Compat(peer_id, cid) => {
@@ -517,10 +2248,67 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                             return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
                                 peer_id,
                                 handler: NotifyHandler::Any,
-                                event: EitherOutput::Second(compat),
+                                event: EitherOutput::Second(HandlerIn::Inner(compat)),
                             });
                         }
                     },
+                    DbResponse::Dag(id, responses) => self.send_dag_response(id, responses),
+                    DbResponse::Insert(id, peer, res) => match res {
+                        Ok(()) => {
+                            self.query_manager
+                                .inject_response(id, Response::Block(peer, true));
+                        }
+                        Err(err) => {
+                            if let Some(event) = self.fail_query(id, err) {
+                                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                            }
+                        }
+                    },
+                    DbResponse::Get(id, cid, res) => match res {
+                        Ok(Some(data)) => match Block::new(cid, data) {
+                            Ok(block) => {
+                                let block = self.push_manager.block(id, block);
+                                self.send_push(id, block);
+                            }
+                            Err(err) => self.fail_push(id, err),
+                        },
+                        Ok(None) => self.fail_push(id, BlockNotFound(cid).into()),
+                        Err(err) => self.fail_push(id, err),
+                    },
+                    DbResponse::Contains(id, res) => match res {
+                        Ok(contains) => {
+                            self.query_manager
+                                .inject_response(id, Response::Contains(contains));
+                        }
+                        Err(err) => {
+                            if let Some(event) = self.fail_query(id, err) {
+                                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                            }
+                        }
+                    },
+                    DbResponse::Filter(id, res) => {
+                        // unless the query was cancelled
+                        if let Some((cid, peers)) = self.reconciles.remove(&id) {
+                            match res {
+                                Ok(filter) => {
+                                    self.filters.insert(id, filter);
+                                    self.query_manager.start_sync(
+                                        id,
+                                        cid,
+                                        peers,
+                                        std::iter::empty(),
+                                    );
+                                }
+                                Err(err) => {
+                                    tracing::error!("{} store error {}", id, err);
+                                    let event = BitswapEvent::Complete(id, Err(err));
+                                    return Poll::Ready(NetworkBehaviourAction::GenerateEvent(
+                                        event,
+                                    ));
+                                }
+                            }
+                        }
+                    }
                     DbResponse::MissingBlocks(id, res) => match res {
                         Ok(missing) => {
                             MISSING_BLOCKS_TOTAL.inc_by(missing.len() as u64);
@@ -528,37 +2316,59 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                                 .inject_response(id, Response::MissingBlocks(missing));
                         }
                         Err(err) => {
-                            self.query_manager.cancel(id);
-                            let event = BitswapEvent::Complete(id, Err(err));
-                            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                            if let Some(event) = self.fail_query(id, err) {
+                                return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+                            }
                         }
                     },
                 }
             }
-            while let Some(query) = self.query_manager.next() {
+            while let Poll::Ready(Some(id)) = self.backoffs.poll_next_unpin(cx) {
+                exit = false;
+                self.query_manager.inject_response(id, Response::Backoff);
+            }
+            // don't start new requests while the store is falling behind
+            while self.db_pending.is_empty() {
+                let query = if let Some(query) = self.query_manager.next() {
+                    query
+                } else {
+                    break;
+                };
                 exit = false;
                 match query {
                     QueryEvent::Request(id, req) => match req {
+                        Request::Have(peer_id, _) | Request::Block(peer_id, _)
+                            if self.reputation.is_banned(&peer_id) =>
+                        {
+                            tracing::debug!("not asking banned peer {}", peer_id);
+                            self.query_manager
+                                .inject_response(id, Response::Have(peer_id, false));
+                        }
                         Request::Have(peer_id, cid) => {
                             let req = BitswapRequest {
                                 ty: RequestType::Have,
                                 cid,
                             };
-                            let rid = self.inner.send_request(&peer_id, req);
-                            self.requests.insert(BitswapId::Bitswap(rid), id);
+                            self.queue_request(peer_id, id, req);
                         }
                         Request::Block(peer_id, cid) => {
-                            let req = BitswapRequest {
-                                ty: RequestType::Block,
-                                cid,
-                            };
-                            let rid = self.inner.send_request(&peer_id, req);
-                            self.requests.insert(BitswapId::Bitswap(rid), id);
+                            let req = self.block_request(id, &peer_id, cid);
+                            self.queue_request(peer_id, id, req);
                         }
                         Request::MissingBlocks(cid) => {
-                            self.db_tx
-                                .unbounded_send(DbRequest::MissingBlocks(id, cid))
-                                .ok();
+                            self.send_db_request(DbRequest::MissingBlocks(id, cid));
+                        }
+                        Request::Contains(cid) => {
+                            self.send_db_request(DbRequest::Contains(id, cid));
+                        }
+                        Request::Broadcast(_, exclude) => {
+                            let peers = self.broadcast_peers(&exclude);
+                            self.query_manager
+                                .inject_response(id, Response::Broadcast(peers));
+                        }
+                        Request::Backoff(_, delay) => {
+                            self.backoffs
+                                .push(Delay::new(delay).map(move |_| id).boxed());
                         }
                     },
                     QueryEvent::Progress(id, missing) => {
@@ -566,6 +2376,7 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                         return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
                     }
                     QueryEvent::Complete(id, res) => {
+                        self.filters.remove(&id);
                         if res.is_err() {
                             BLOCK_NOT_FOUND.inc();
                         }
@@ -577,13 +2388,13 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                     }
                 }
             }
+            self.flush_requests();
             while let Poll::Ready(event) = self.inner.poll(cx, pp) {
                 exit = false;
                 let event = match event {
                     NetworkBehaviourAction::GenerateEvent(event) => event,
                     NetworkBehaviourAction::Dial { opts, handler } => {
-                        #[cfg(feature = "compat")]
-                        let handler = ConnectionHandler::select(handler, Default::default());
+                        let handler = self.connection_handler(handler);
                         return Poll::Ready(NetworkBehaviourAction::Dial { opts, handler });
                     }
                     NetworkBehaviourAction::NotifyHandler {
@@ -594,10 +2405,7 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                         return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
                             peer_id,
                             handler,
-                            #[cfg(not(feature = "compat"))]
-                            event,
-                            #[cfg(feature = "compat")]
-                            event: EitherOutput::First(event),
+                            event: Self::native_event(EitherOutput::First(HandlerIn::Inner(event))),
                         });
                     }
                     NetworkBehaviourAction::ReportObservedAddr { address, score } => {
@@ -622,11 +2430,11 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                             request_id: _,
                             request,
                             channel,
-                        } => self.inject_request(BitswapChannel::Bitswap(channel), request),
+                        } => self.inject_requests(peer, InboundChannel::Request(channel), request),
                         RequestResponseMessage::Response {
                             request_id,
                             response,
-                        } => self.inject_response(BitswapId::Bitswap(request_id), peer, response),
+                        } => self.inject_responses(MessageId::Request(request_id), peer, response),
                     },
                     RequestResponseEvent::ResponseSent { .. } => {}
                     RequestResponseEvent::OutboundFailure {
@@ -635,9 +2443,36 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                         error,
                     } => {
                         self.inject_outbound_failure(&peer, request_id, &error);
-                        #[cfg(feature = "compat")]
-                        if let OutboundFailure::UnsupportedProtocols = error {
-                            if let Some(id) = self.requests.remove(&BitswapId::Bitswap(request_id))
+                        self.dag_requests.remove(&MessageId::Request(request_id));
+                        let message_id = MessageId::Request(request_id);
+                        if let Some((id, block, _)) = self.push_manager.take_request(&message_id) {
+                            #[cfg(feature = "compat")]
+                            if let (OutboundFailure::UnsupportedProtocols, true) =
+                                (&error, self.config.enable_compat)
+                            {
+                                tracing::trace!("adding compat peer {}", peer);
+                                self.compat.insert(peer);
+                                let request = Self::push_request(&block);
+                                self.send_compat_push(id, peer, request, block);
+                                continue;
+                            }
+                            let err = libipld::error::Error::msg(format!(
+                                "pushing {} failed: {:?}",
+                                block.cid, error
+                            ));
+                            self.fail_push(id, err);
+                            continue;
+                        }
+                        for i in 0.. {
+                            let bitswap_id = BitswapId::Bitswap(MessageId::Request(request_id), i);
+                            let id = if let Some(id) = self.requests.remove(&bitswap_id) {
+                                id
+                            } else {
+                                break;
+                            };
+                            #[cfg(feature = "compat")]
+                            if let (OutboundFailure::UnsupportedProtocols, true) =
+                                (&error, self.config.enable_compat)
                             {
                                 if let Some(info) = self.query_manager.query_info(id) {
                                     let ty = match info.label {
@@ -649,17 +2484,11 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                                     self.requests.insert(BitswapId::Compat(info.cid), id);
                                     tracing::trace!("adding compat peer {}", peer);
                                     self.compat.insert(peer);
-                                    return Poll::Ready(NetworkBehaviourAction::NotifyHandler {
-                                        peer_id: peer,
-                                        handler: NotifyHandler::Any,
-                                        event: EitherOutput::Second(CompatMessage::Request(
-                                            request,
-                                        )),
-                                    });
+                                    self.compat_out
+                                        .push_back((peer, CompatMessage::Request(request)));
+                                    continue;
                                 }
                             }
-                        }
-                        if let Some(id) = self.requests.remove(&BitswapId::Bitswap(request_id)) {
                             self.query_manager
                                 .inject_response(id, Response::Have(peer, false));
                         }
@@ -674,6 +2503,18 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
                 }
             }
         }
+        self.update_pins();
+        if let Some(command) = self.commands.pop_front() {
+            return Poll::Ready(self.notify_handler(command));
+        }
+        if let Some(event) = self.poll_pipeline_out() {
+            return Poll::Ready(event);
+        }
+        // requests of queries that continued in this poll
+        #[cfg(feature = "compat")]
+        if let Some(event) = self.poll_compat_out() {
+            return Poll::Ready(event);
+        }
         Poll::Pending
     }
 }
@@ -682,7 +2523,7 @@ impl<P: StoreParams> NetworkBehaviour for Bitswap<P> {
 mod tests {
     use super::*;
     use async_std::task;
-    use futures::prelude::*;
+    use futures::channel::oneshot;
     use libipld::block::Block;
     use libipld::cbor::DagCborCodec;
     use libipld::ipld;
@@ -697,6 +2538,7 @@ mod tests {
     use libp2p::tcp::{self, async_io};
     use libp2p::yamux::YamuxConfig;
     use libp2p::{PeerId, Swarm, Transport};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
     use tracing_subscriber::fmt::TestWriter;
@@ -730,8 +2572,9 @@ mod tests {
         Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
     }
 
+    /// Blocks by cid and the number of inserts.
     #[derive(Clone, Default)]
-    struct Store(Arc<Mutex<FnvHashMap<Cid, Vec<u8>>>>);
+    struct Store(Arc<Mutex<FnvHashMap<Cid, Vec<u8>>>>, Arc<AtomicUsize>);
 
     impl BitswapStore for Store {
         type Params = DefaultParams;
@@ -742,12 +2585,16 @@ mod tests {
             Ok(self.0.lock().unwrap().get(cid).cloned())
         }
         fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+            self.1.fetch_add(1, Ordering::SeqCst);
             self.0
                 .lock()
                 .unwrap()
                 .insert(*block.cid(), block.data().to_vec());
             Ok(())
         }
+        fn size(&mut self, cid: &Cid) -> Result<Option<usize>> {
+            Ok(self.0.lock().unwrap().get(cid).map(Vec::len))
+        }
         fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
             let mut stack = vec![*cid];
             let mut missing = vec![];
@@ -763,6 +2610,97 @@ mod tests {
         }
     }
 
+    /// Store that yields before answering, so requests are actually in flight
+    /// concurrently.
+    struct AsyncStore(Store);
+
+    #[async_trait]
+    impl AsyncBitswapStore for AsyncStore {
+        type Params = DefaultParams;
+        async fn contains(&self, cid: &Cid) -> Result<bool> {
+            task::yield_now().await;
+            Ok(self.0 .0.lock().unwrap().contains_key(cid))
+        }
+        async fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            task::yield_now().await;
+            Ok(self.0 .0.lock().unwrap().get(cid).cloned())
+        }
+        async fn insert(&self, block: &Block<Self::Params>) -> Result<()> {
+            task::yield_now().await;
+            self.0.clone().insert(block)
+        }
+        async fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+            task::yield_now().await;
+            self.0.clone().missing_blocks(cid)
+        }
+    }
+
+    /// Store that fails to insert blocks.
+    struct FailingStore(Store);
+
+    impl BitswapStore for FailingStore {
+        type Params = DefaultParams;
+        fn contains(&mut self, cid: &Cid) -> Result<bool> {
+            self.0.contains(cid)
+        }
+        fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            self.0.get(cid)
+        }
+        fn insert(&mut self, _: &Block<Self::Params>) -> Result<()> {
+            Err(libipld::error::Error::msg("disk full"))
+        }
+        fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+            self.0.missing_blocks(cid)
+        }
+    }
+
+    /// Store whose reads of one block wait until another block was read, so that its
+    /// requests are answered out of order. Reads of the block never finish if no other
+    /// block is read.
+    struct GatedStore {
+        store: Store,
+        gated: Cid,
+        open: Mutex<Option<oneshot::Sender<()>>>,
+        gate: Mutex<Option<oneshot::Receiver<()>>>,
+    }
+
+    impl GatedStore {
+        fn new(store: Store, gated: Cid) -> Self {
+            let (open, gate) = oneshot::channel();
+            Self {
+                store,
+                gated,
+                open: Mutex::new(Some(open)),
+                gate: Mutex::new(Some(gate)),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl AsyncBitswapStore for GatedStore {
+        type Params = DefaultParams;
+        async fn contains(&self, cid: &Cid) -> Result<bool> {
+            self.store.clone().contains(cid)
+        }
+        async fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            if *cid == self.gated {
+                let gate = self.gate.lock().unwrap().take();
+                if let Some(gate) = gate {
+                    gate.await.ok();
+                }
+            } else if let Some(open) = self.open.lock().unwrap().take() {
+                open.send(()).ok();
+            }
+            self.store.clone().get(cid)
+        }
+        async fn insert(&self, block: &Block<Self::Params>) -> Result<()> {
+            self.store.clone().insert(block)
+        }
+        async fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+            self.store.clone().missing_blocks(cid)
+        }
+    }
+
     struct Peer {
         peer_id: PeerId,
         addr: Multiaddr,
@@ -772,13 +2710,24 @@ mod tests {
 
     impl Peer {
         fn new() -> Self {
-            let (peer_id, trans) = mk_transport();
+            Self::with_config(BitswapConfig::new())
+        }
+
+        fn with_config(config: BitswapConfig) -> Self {
             let store = Store::default();
-            let mut swarm = Swarm::with_async_std_executor(
-                trans,
-                Bitswap::new(BitswapConfig::new(), store.clone()),
-                peer_id,
-            );
+            Self::with_behaviour(store.clone(), Bitswap::new(config, store))
+        }
+
+        fn new_async() -> Self {
+            let store = Store::default();
+            let async_store = AsyncStore(store.clone());
+            Self::with_behaviour(store, Bitswap::new_async(BitswapConfig::new(), async_store))
+        }
+
+        fn with_behaviour(store: Store, mut behaviour: Bitswap<DefaultParams>) -> Self {
+            behaviour.collect_references();
+            let (peer_id, trans) = mk_transport();
+            let mut swarm = Swarm::with_async_std_executor(trans, behaviour, peer_id);
             Swarm::listen_on(&mut swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
             while swarm.next().now_or_never().is_some() {}
             let addr = Swarm::listeners(&swarm).next().unwrap().clone();
@@ -815,11 +2764,25 @@ mod tests {
             peer_id
         }
 
+        /// Dials a peer and waits until the connection is established.
+        async fn connect(&mut self, peer_id: PeerId, addr: Multiaddr) {
+            self.swarm
+                .behaviour_mut()
+                .add_address(&peer_id, addr.clone());
+            self.swarm.dial(addr).unwrap();
+            loop {
+                if let Some(SwarmEvent::ConnectionEstablished { .. }) = self.swarm.next().await {
+                    return;
+                }
+            }
+        }
+
         async fn next(&mut self) -> Option<BitswapEvent> {
             loop {
-                let ev = self.swarm.next().await?;
-                if let SwarmEvent::Behaviour(event) = ev {
-                    return Some(event);
+                match self.swarm.next().await? {
+                    SwarmEvent::Behaviour(BitswapEvent::ProtocolsLearned(..)) => {}
+                    SwarmEvent::Behaviour(event) => return Some(event),
+                    _ => {}
                 }
             }
         }
@@ -842,6 +2805,16 @@ mod tests {
         }
     }
 
+    /// Skips the progress events of a sync query until it completes.
+    async fn assert_sync_ok(peer: &mut Peer, id: QueryId) {
+        loop {
+            match peer.next().await {
+                Some(BitswapEvent::Progress(id2, _)) => assert_eq!(id2, id),
+                event => return assert_complete_ok(event, id),
+            }
+        }
+    }
+
     #[async_std::test]
     async fn test_bitswap_get() {
         tracing_try_init();
@@ -861,6 +2834,255 @@ mod tests {
         assert_complete_ok(peer2.next().await, id);
     }
 
+    #[async_std::test]
+    async fn test_bitswap_get_local() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer.store().insert(*block.cid(), block.data().to_vec());
+
+        let id = peer
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::empty());
+        assert_complete_ok(peer.next().await, id);
+
+        let block = create_block(ipld!(&b"hello"[..]));
+        let id = peer
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::empty());
+        if let Some(BitswapEvent::Complete(id2, Err(_))) = peer.next().await {
+            assert_eq!(id2, id);
+        } else {
+            panic!("expected block not found");
+        }
+    }
+
+    /// Native protocol negotiated between two peers.
+    const NATIVE: SupportedProtocol = SupportedProtocol::NativeCapabilities;
+
+    #[async_std::test]
+    async fn test_bitswap_peers() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+        assert!(peer2.swarm().behaviour().peers().is_empty());
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        let mut learned = false;
+        let mut complete = false;
+        while !learned || !complete {
+            match peer2.swarm().next().await {
+                Some(SwarmEvent::Behaviour(BitswapEvent::ProtocolsLearned(peer, protocols))) => {
+                    assert_eq!(peer, peer1);
+                    assert_eq!(protocols, vec![NATIVE]);
+                    learned = true;
+                }
+                Some(SwarmEvent::Behaviour(event)) => {
+                    assert_complete_ok(Some(event), id);
+                    complete = true;
+                }
+                _ => {}
+            }
+        }
+
+        let peers = peer2.swarm().behaviour().peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, peer1);
+        assert_eq!(peers[0].protocols, vec![NATIVE]);
+        assert_eq!(peers[0].capabilities, Capabilities::local());
+        assert_eq!(peers[0].in_flight, 0);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_single_fallback() {
+        tracing_try_init();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let data = Bytes::copy_from_slice(block.data());
+
+        // a peer that only speaks the single entry protocol
+        let (peer_id, trans) = mk_transport();
+        let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Single);
+        let protocols = std::iter::once((protocol, ProtocolSupport::Full));
+        let behaviour = RequestResponse::new(
+            BitswapCodec::<DefaultParams>::default(),
+            protocols,
+            Default::default(),
+        );
+        let mut swarm = Swarm::with_async_std_executor(trans, behaviour, peer_id);
+        Swarm::listen_on(&mut swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        while swarm.next().now_or_never().is_some() {}
+        let addr = Swarm::listeners(&swarm).next().unwrap().clone();
+        task::spawn(async move {
+            loop {
+                if let Some(SwarmEvent::Behaviour(RequestResponseEvent::Message {
+                    message:
+                        RequestResponseMessage::Request {
+                            request, channel, ..
+                        },
+                    ..
+                })) = swarm.next().await
+                {
+                    assert_eq!(request.requests.len(), 1);
+                    let response = match request.requests[0].ty {
+                        RequestType::Have => BitswapResponse::Have(true),
+                        _ => BitswapResponse::Block(data.clone()),
+                    };
+                    let responses = BitswapResponses {
+                        protocol: request.protocol,
+                        capabilities: Capabilities::empty(),
+                        responses: vec![response],
+                    };
+                    swarm.behaviour_mut().send_response(channel, responses).ok();
+                }
+            }
+        });
+
+        let mut peer = Peer::new();
+        peer.swarm().behaviour_mut().add_address(&peer_id, addr);
+        let id = peer
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer_id));
+        assert_complete_ok(peer.next().await, id);
+        let peers = peer.swarm().behaviour().peers();
+        assert_eq!(peers[0].protocols, vec![SupportedProtocol::Native]);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_broadcast() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::with_config(BitswapConfig {
+            broadcast_peers: 4,
+            ..BitswapConfig::new()
+        });
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let addr = peer1.addr.clone();
+        let peer_id = peer1.spawn("peer1");
+        peer2.connect(peer_id, addr).await;
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::empty());
+        assert_complete_ok(peer2.next().await, id);
+        assert!(peer2.store().contains_key(block.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_insert_failure() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let store = Store::default();
+        let behaviour = Bitswap::new(BitswapConfig::new(), FailingStore(store.clone()));
+        let mut peer2 = Peer::with_behaviour(store, behaviour);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+
+        if let Some(BitswapEvent::Complete(id2, Err(err))) = peer2.next().await {
+            assert_eq!(id2, id);
+            assert_eq!(err.to_string(), "disk full");
+        } else {
+            panic!("expected the query to fail");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_rejected_block() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+        peer2
+            .swarm()
+            .behaviour_mut()
+            .set_block_validator(crate::BlockRules::new().allow_codecs([0x55]));
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+
+        if let Some(BitswapEvent::Complete(id2, Err(_))) = peer2.next().await {
+            assert_eq!(id2, id);
+        } else {
+            panic!("expected the block to be rejected");
+        }
+        assert!(!peer2.store().contains_key(block.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_ban_invalid_block() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let config = BitswapConfig {
+            ban_threshold: 4,
+            ..BitswapConfig::new()
+        };
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), b"invalid".to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+
+        let mut banned = false;
+        let mut complete = false;
+        while !banned || !complete {
+            match peer2.next().await {
+                Some(BitswapEvent::PeerBanned(peer)) => {
+                    assert_eq!(peer, peer1);
+                    banned = true;
+                }
+                Some(BitswapEvent::Complete(id2, Err(_))) => {
+                    assert_eq!(id2, id);
+                    complete = true;
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+
+        // banned peers are not asked
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        if let Some(BitswapEvent::Complete(id2, Err(_))) = peer2.next().await {
+            assert_eq!(id2, id);
+        } else {
+            panic!("expected the query to fail");
+        }
+    }
+
     #[async_std::test]
     async fn test_bitswap_cancel_get() {
         tracing_try_init();
@@ -918,7 +3140,960 @@ mod tests {
     }
 
     #[async_std::test]
-    async fn test_bitswap_cancel_sync() {
+    async fn test_bitswap_sync_root() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let b0 = create_block(ipld!({
+            "n": 0,
+        }));
+        let b1 = create_block(ipld!({
+            "prev": b0.cid(),
+            "n": 1,
+        }));
+        peer1.store().insert(*b0.cid(), b0.data().to_vec());
+        peer1.store().insert(*b1.cid(), b1.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*b1.cid(), vec![peer1], std::iter::empty());
+
+        assert_progress(peer2.next().await, id, 1);
+        assert_progress(peer2.next().await, id, 1);
+        assert_complete_ok(peer2.next().await, id);
+        assert!(peer2.store().contains_key(b0.cid()));
+        assert!(peer2.store().contains_key(b1.cid()));
+    }
+
+    /// Chain of blocks, the last one is the root.
+    fn create_chain(n: u64) -> Vec<Block<DefaultParams>> {
+        let mut blocks = vec![create_block(ipld!({ "n": 0 }))];
+        for n in 1..n {
+            let prev = *blocks.last().unwrap().cid();
+            blocks.push(create_block(ipld!({ "prev": prev, "n": n })));
+        }
+        blocks
+    }
+
+    /// Config that sends and answers dag requests.
+    fn dag_config() -> BitswapConfig {
+        BitswapConfig {
+            dag_requests: true,
+            ..BitswapConfig::new()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_dag() {
+        tracing_try_init();
+        let mut peer1 = Peer::with_config(dag_config());
+        let mut peer2 = Peer::with_config(dag_config());
+        peer2.add_address(&peer1);
+
+        let blocks = create_chain(6);
+        for block in &blocks {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let root = *blocks.last().unwrap().cid();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(root, vec![peer1], std::iter::once(root));
+
+        // the protocol of peer1 is learned from the response for the root, the
+        // rest of the chain comes with the next block
+        assert_progress(peer2.next().await, id, 1);
+        assert_complete_ok(peer2.next().await, id);
+        for block in &blocks {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_dag_depth() {
+        tracing_try_init();
+        let mut peer1 = Peer::with_config(dag_config());
+        let mut peer2 = Peer::with_config(BitswapConfig {
+            dag_depth: Some(2),
+            ..dag_config()
+        });
+        peer2.add_address(&peer1);
+
+        let blocks = create_chain(6);
+        for block in &blocks {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let root = *blocks.last().unwrap().cid();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(root, vec![peer1], std::iter::once(root));
+
+        // every dag request returns a block and two levels of descendants
+        assert_progress(peer2.next().await, id, 1);
+        assert_progress(peer2.next().await, id, 1);
+        assert_complete_ok(peer2.next().await, id);
+        for block in &blocks {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_dag_not_served() {
+        tracing_try_init();
+        let mut peer1 = Peer::with_config(dag_config());
+        let mut peer2 = Peer::with_config(dag_config());
+        peer2.add_address(&peer1);
+        // dag requests can be turned off at runtime
+        let behaviour = peer1.swarm().behaviour_mut();
+        behaviour.set_config(BitswapConfig::new()).unwrap();
+
+        let blocks = create_chain(3);
+        for block in &blocks {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let root = *blocks.last().unwrap().cid();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(root, vec![peer1], std::iter::once(root));
+
+        // peer1 answers dag requests like block requests
+        assert_progress(peer2.next().await, id, 1);
+        assert_progress(peer2.next().await, id, 1);
+        assert_complete_ok(peer2.next().await, id);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_reconcile() {
+        tracing_try_init();
+        let mut peer1 = Peer::with_config(dag_config());
+        let mut peer2 = Peer::with_config(dag_config());
+        peer2.add_address(&peer1);
+
+        // the second version adds a block next to the first one
+        let blocks = create_chain(6);
+        let base = *blocks.last().unwrap().cid();
+        let leaf = create_block(ipld!({ "n": 100 }));
+        let root = create_block(ipld!({ "prev": base, "leaf": leaf.cid() }));
+        for block in blocks.iter().chain([&leaf, &root]) {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        for block in &blocks {
+            peer2.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .reconcile(*root.cid(), vec![peer1], base);
+        assert_sync_ok(&mut peer2, id).await;
+        assert!(peer2.store().contains_key(root.cid()));
+        assert!(peer2.store().contains_key(leaf.cid()));
+        // the blocks of the first version weren't sent again
+        assert_eq!(peer2.store.1.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_reconcile_without_dag_requests() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let blocks = create_chain(3);
+        for block in &blocks {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let root = *blocks.last().unwrap().cid();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .reconcile(root, vec![peer1], *blocks[0].cid());
+        assert_sync_ok(&mut peer2, id).await;
+        for block in &blocks {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_no_providers() {
+        tracing_try_init();
+        let mut peer = Peer::new();
+        let block = create_block(ipld!(&b"hello world"[..]));
+
+        let id =
+            peer.swarm()
+                .behaviour_mut()
+                .sync(*block.cid(), vec![], std::iter::once(*block.cid()));
+        if let Some(BitswapEvent::Complete(id2, Err(err))) = peer.next().await {
+            assert_eq!(id2, id);
+            assert!(err.is::<BlockNotFound>());
+        } else {
+            panic!("expected block not found");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_push_dag() {
+        tracing_try_init();
+        let store = Store::default();
+        let mut behaviour = Bitswap::new(BitswapConfig::new(), store.clone());
+        behaviour.set_push_policy(|_: &PeerId, _: &Block<DefaultParams>| true);
+        let peer1 = Peer::with_behaviour(store.clone(), behaviour);
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let b0 = create_block(ipld!({
+            "n": 0,
+        }));
+        let b1 = create_block(ipld!({
+            "prev": b0.cid(),
+            "n": 1,
+        }));
+        peer2.store().insert(*b0.cid(), b0.data().to_vec());
+        peer2.store().insert(*b1.cid(), b1.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2.swarm().behaviour_mut().push_dag(peer1, *b1.cid());
+        assert_complete_ok(peer2.next().await, id);
+        assert!(store.0.lock().unwrap().contains_key(b0.cid()));
+        assert!(store.0.lock().unwrap().contains_key(b1.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_push_refused() {
+        tracing_try_init();
+        let peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer2.store().insert(*block.cid(), block.data().to_vec());
+        let missing = create_block(ipld!(&b"hello"[..]));
+        let store = peer1.store.clone();
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2.swarm().behaviour_mut().push(peer1, *block.cid());
+        if let Some(BitswapEvent::Complete(id2, Err(err))) = peer2.next().await {
+            assert_eq!(id2, id);
+            assert!(err.is::<PushRejected>());
+        } else {
+            panic!("expected push to be refused");
+        }
+        assert!(!store.0.lock().unwrap().contains_key(block.cid()));
+
+        let id = peer2.swarm().behaviour_mut().push(peer1, *missing.cid());
+        if let Some(BitswapEvent::Complete(id2, Err(err))) = peer2.next().await {
+            assert_eq!(id2, id);
+            assert!(err.is::<BlockNotFound>());
+        } else {
+            panic!("expected block not found");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_push_unsupported() {
+        tracing_try_init();
+        // a peer that speaks a version without pushes
+        let (peer_id, trans) = mk_transport();
+        let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Batch);
+        let protocols = std::iter::once((protocol, ProtocolSupport::Full));
+        let behaviour = RequestResponse::new(
+            BitswapCodec::<DefaultParams>::default(),
+            protocols,
+            Default::default(),
+        );
+        let mut swarm = Swarm::with_async_std_executor(trans, behaviour, peer_id);
+        Swarm::listen_on(&mut swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+        while swarm.next().now_or_never().is_some() {}
+        let addr = Swarm::listeners(&swarm).next().unwrap().clone();
+        let received = Arc::new(AtomicUsize::new(0));
+        let received2 = received.clone();
+        task::spawn(async move {
+            loop {
+                if let Some(SwarmEvent::Behaviour(RequestResponseEvent::Message {
+                    message:
+                        RequestResponseMessage::Request {
+                            request, channel, ..
+                        },
+                    ..
+                })) = swarm.next().await
+                {
+                    // the block isn't sent before the peer announced pushes
+                    assert_eq!(request.requests[0].ty, RequestType::Have);
+                    received2.fetch_add(1, Ordering::SeqCst);
+                    let responses = BitswapResponses {
+                        protocol: request.protocol,
+                        capabilities: Capabilities::empty(),
+                        responses: vec![BitswapResponse::Have(true)],
+                    };
+                    swarm.behaviour_mut().send_response(channel, responses).ok();
+                }
+            }
+        });
+
+        let mut peer = Peer::new();
+        peer.swarm().behaviour_mut().add_address(&peer_id, addr);
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer.store().insert(*block.cid(), block.data().to_vec());
+        for _ in 0..2 {
+            let id = peer.swarm().behaviour_mut().push(peer_id, *block.cid());
+            loop {
+                match peer.next().await {
+                    Some(BitswapEvent::Complete(id2, Err(err))) => {
+                        assert_eq!(id2, id);
+                        assert!(err.is::<PushUnsupported>());
+                        break;
+                    }
+                    Some(BitswapEvent::ProtocolsLearned(_, _)) => {}
+                    event => panic!("expected push to be unsupported, got {:?}", event),
+                }
+            }
+        }
+        // once the version of the peer is known pushes fail right away
+        assert_eq!(received.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_async_store() {
+        tracing_try_init();
+        let mut peer1 = Peer::new_async();
+        let mut peer2 = Peer::new_async();
+        peer2.add_address(&peer1);
+
+        let b0 = create_block(ipld!({
+            "n": 0,
+        }));
+        let b1 = create_block(ipld!({
+            "prev": b0.cid(),
+            "n": 1,
+        }));
+        peer1.store().insert(*b0.cid(), b0.data().to_vec());
+        peer1.store().insert(*b1.cid(), b1.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*b1.cid(), vec![peer1], std::iter::once(*b1.cid()));
+
+        assert_progress(peer2.next().await, id, 1);
+        assert_complete_ok(peer2.next().await, id);
+        assert!(peer2.store().contains_key(b0.cid()));
+        assert!(peer2.store().contains_key(b1.cid()));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_set_config() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let config = BitswapConfig {
+            request_timeout: Duration::from_secs(0),
+            ..BitswapConfig::new()
+        };
+        assert!(peer2.swarm().behaviour_mut().set_config(config).is_err());
+        let config = BitswapConfig {
+            db_queue_capacity: 1,
+            ..BitswapConfig::new()
+        };
+        assert!(peer2.swarm().behaviour_mut().set_config(config).is_err());
+        let config = BitswapConfig {
+            request_timeout: Duration::from_secs(5),
+            connection_keep_alive: Duration::from_secs(1),
+            ban_threshold: 0,
+            ..BitswapConfig::new()
+        };
+        peer2.swarm().behaviour_mut().set_config(config).unwrap();
+        assert_eq!(peer2.swarm().behaviour().config(), &config);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        assert_complete_ok(peer2.next().await, id);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_max_concurrent_requests() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+        let config = BitswapConfig {
+            max_concurrent_requests: 1,
+            ..BitswapConfig::new()
+        };
+        peer2.swarm().behaviour_mut().set_config(config).unwrap();
+
+        let blocks: Vec<_> = (0..3u8).map(|i| create_block(ipld!(&[i; 8][..]))).collect();
+        for block in &blocks {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let mut ids: FnvHashSet<_> = blocks
+            .iter()
+            .map(|block| {
+                peer2
+                    .swarm()
+                    .behaviour_mut()
+                    .get(*block.cid(), std::iter::once(peer1))
+            })
+            .collect();
+        while !ids.is_empty() {
+            let event = peer2.swarm.next().await;
+            let in_flight = peer2.swarm().behaviour().in_flight();
+            assert!(in_flight.get(&peer1).copied().unwrap_or_default() <= 1);
+            if let Some(SwarmEvent::Behaviour(BitswapEvent::Complete(id, res))) = event {
+                assert!(res.is_ok());
+                assert!(ids.remove(&id));
+            }
+        }
+        for block in &blocks {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+    }
+
+    fn assert_complete_not_found(event: Option<BitswapEvent>, id: QueryId) {
+        if let Some(BitswapEvent::Complete(id2, Err(err))) = event {
+            assert_eq!(id2, id);
+            assert!(err.is::<BlockNotFound>());
+        } else {
+            panic!("{:?} is not a block not found event", event);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_protocol_prefix() {
+        tracing_try_init();
+        let names = ProtocolNames {
+            prefix: "/myapp".into(),
+            compat_prefix: "/myapp".into(),
+        };
+        let with_names = || {
+            let store = Store::default();
+            let behaviour = Bitswap::with_names(BitswapConfig::new(), names.clone(), store.clone());
+            Peer::with_behaviour(store, behaviour)
+        };
+        let mut peer1 = with_names();
+        let mut peer2 = with_names();
+        let mut peer3 = Peer::with_config(BitswapConfig {
+            enable_compat: false,
+            ..BitswapConfig::new()
+        });
+        peer2.add_address(&peer1);
+        peer3.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        assert_complete_ok(peer2.next().await, id);
+        let peers = peer2.swarm().behaviour().peers();
+        let names = peer2.swarm().behaviour().protocol_names();
+        assert!(peers[0].protocols[0]
+            .protocol_name(names)
+            .starts_with("/myapp/bitswap"));
+        assert_eq!(
+            SupportedProtocol::Compat.protocol_name(names),
+            "/myapp/ipfs/bitswap/1.2.0"
+        );
+
+        // peers with another prefix don't share a protocol
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        assert_complete_not_found(peer3.next().await, id);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_disable_native() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let config = BitswapConfig {
+            enable_compat: false,
+            ..BitswapConfig::new()
+        };
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let block1 = create_block(ipld!(&b"hello world"[..]));
+        let block2 = create_block(ipld!(&b"hello other world"[..]));
+        let block3 = create_block(ipld!(&b"hello third world"[..]));
+        for block in [&block1, &block2, &block3] {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1_id = peer1.peer_id;
+        let (mut config_tx, mut config_rx) = mpsc::channel::<(BitswapConfig, mpsc::Sender<()>)>(1);
+        let mut swarm1 = peer1.swarm;
+        task::spawn(async move {
+            loop {
+                futures::select! {
+                    _ = swarm1.select_next_some() => {}
+                    msg = config_rx.select_next_some() => {
+                        let (config, mut ack) = msg;
+                        swarm1.behaviour_mut().set_config(config).unwrap();
+                        ack.send(()).await.unwrap();
+                    }
+                }
+            }
+        });
+        let mut set_config = |config| {
+            let (ack_tx, mut ack_rx) = mpsc::channel(1);
+            config_tx.try_send((config, ack_tx)).unwrap();
+            async move { ack_rx.next().await }
+        };
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block1.cid(), std::iter::once(peer1_id));
+        assert_complete_ok(peer2.next().await, id);
+
+        // the open connection stops accepting native requests
+        let disabled = BitswapConfig {
+            enable_native: false,
+            ..BitswapConfig::new()
+        };
+        set_config(disabled).await.unwrap();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block2.cid(), std::iter::once(peer1_id));
+        assert_complete_not_found(peer2.next().await, id);
+
+        set_config(BitswapConfig::new()).await.unwrap();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block2.cid(), std::iter::once(peer1_id));
+        assert_complete_ok(peer2.next().await, id);
+
+        // without an enabled protocol nobody is asked
+        let config = BitswapConfig {
+            enable_native: false,
+            ..config
+        };
+        peer2.swarm().behaviour_mut().set_config(config).unwrap();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block3.cid(), std::iter::once(peer1_id));
+        assert_complete_not_found(peer2.next().await, id);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_shutdown() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        assert_complete_ok(peer2.next().await, id);
+
+        // pending pushes fail instead of being dropped
+        let push = peer2.swarm().behaviour_mut().push(peer1, *block.cid());
+        let store: Store = peer2.swarm().behaviour_mut().shutdown().await.unwrap();
+        assert!(store.0.lock().unwrap().contains_key(block.cid()));
+        match peer2.next().await {
+            Some(BitswapEvent::Complete(id, Err(err))) if id == push => {
+                assert!(err.downcast_ref::<ShutDown>().is_some());
+            }
+            event => panic!("{:?} is not a failed push", event),
+        }
+        assert!(peer2
+            .swarm()
+            .behaviour_mut()
+            .shutdown::<Store>()
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_shutdown_async_store() {
+        tracing_try_init();
+        let mut peer = Peer::new_async();
+        // the store is handed back as the type it was created with
+        let store = peer.swarm().behaviour_mut().shutdown::<Store>().await;
+        assert!(store
+            .err()
+            .unwrap()
+            .downcast_ref::<WrongStoreType>()
+            .is_some());
+        let mut peer = Peer::new_async();
+        let store = peer.swarm().behaviour_mut().shutdown::<AsyncStore>();
+        assert!(store.await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_small_db_queue() {
+        tracing_try_init();
+        let config = BitswapConfig {
+            db_queue_capacity: 0,
+            ..BitswapConfig::new()
+        };
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::with_config(config);
+        peer2.add_address(&peer1);
+
+        let leaves: Vec<_> = (0..16).map(|n| create_block(ipld!({ "n": n }))).collect();
+        let links: Vec<_> = leaves.iter().map(|b| Ipld::Link(*b.cid())).collect();
+        let root = create_block(Ipld::List(links));
+        for block in leaves.iter().chain(std::iter::once(&root)) {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*root.cid(), vec![peer1], std::iter::once(*root.cid()));
+
+        loop {
+            match peer2.next().await {
+                Some(BitswapEvent::Progress(_, _)) => continue,
+                event => {
+                    assert_complete_ok(event, id);
+                    break;
+                }
+            }
+        }
+        for block in &leaves {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_db_pending_bounded() {
+        tracing_try_init();
+        let config = BitswapConfig {
+            db_queue_capacity: 4,
+            ..BitswapConfig::new()
+        };
+        // nobody polls the db responses, so the db thread stalls once they fill up
+        let mut bitswap = Bitswap::<DefaultParams>::new(config, Store::default());
+        // no query waits for the blocks
+        let id = QueryId(u64::MAX);
+        let peer = PeerId::random();
+        for n in 0..1000 {
+            let block = create_block(ipld!({ "n": n }));
+            bitswap.send_db_request(DbRequest::Insert(id, peer, block));
+            bitswap.send_db_request(DbRequest::InsertDag(create_block(ipld!([n]))));
+            assert!(bitswap.db_pending.len() <= 4);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_sync_busy_peer() {
+        tracing_try_init();
+        // the provider sheds most requests and asks to retry them shortly after
+        let config = BitswapConfig {
+            db_queue_capacity: 0,
+            busy_retry_after: Duration::from_millis(10),
+            ..BitswapConfig::new()
+        };
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::new();
+        peer2.add_address(&peer1);
+
+        let leaves: Vec<_> = (0..4).map(|n| create_block(ipld!({ "n": n }))).collect();
+        let links: Vec<_> = leaves.iter().map(|b| Ipld::Link(*b.cid())).collect();
+        let root = create_block(Ipld::List(links));
+        for block in leaves.iter().chain(std::iter::once(&root)) {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*root.cid(), vec![peer1], std::iter::once(*root.cid()));
+
+        loop {
+            match peer2.next().await {
+                Some(BitswapEvent::Progress(_, _)) => continue,
+                event => {
+                    assert_complete_ok(event, id);
+                    break;
+                }
+            }
+        }
+        for block in &leaves {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_get_unauthorized() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::new();
+        let mut peer3 = Peer::new();
+        peer2.add_address(&peer1);
+        peer3.add_address(&peer1);
+
+        let refused = *peer2.swarm().local_peer_id();
+        peer1
+            .swarm()
+            .behaviour_mut()
+            .set_access_policy(move |peer: &PeerId, _: &Cid| *peer != refused);
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let peer1 = peer1.spawn("peer1");
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        if let Some(BitswapEvent::Complete(id2, Err(_))) = peer2.next().await {
+            assert_eq!(id2, id);
+        } else {
+            panic!("expected the block to be refused");
+        }
+        assert!(!peer2.store().contains_key(block.cid()));
+
+        let id = peer3
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        assert_complete_ok(peer3.next().await, id);
+    }
+
+    /// Pipelined protocol negotiated between two peers.
+    const PIPELINE: SupportedProtocol = SupportedProtocol::NativePipeline;
+
+    fn pipelining() -> BitswapConfig {
+        BitswapConfig {
+            pipelining: true,
+            ..BitswapConfig::new()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_pipeline_sync() {
+        tracing_try_init();
+        let mut peer1 = Peer::with_config(pipelining());
+        let mut peer2 = Peer::with_config(pipelining());
+
+        let leaves: Vec<_> = (0..32).map(|n| create_block(ipld!({ "n": n }))).collect();
+        let links: Vec<_> = leaves.iter().map(|b| Ipld::Link(*b.cid())).collect();
+        let root = create_block(Ipld::List(links));
+        for block in leaves.iter().chain(std::iter::once(&root)) {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let addr = peer1.addr.clone();
+        let peer1 = peer1.spawn("peer1");
+        // requests are only pipelined to connected peers
+        peer2.connect(peer1, addr).await;
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(*root.cid(), vec![peer1], std::iter::once(*root.cid()));
+        loop {
+            match peer2.next().await {
+                Some(BitswapEvent::Progress(_, _)) => continue,
+                event => {
+                    assert_complete_ok(event, id);
+                    break;
+                }
+            }
+        }
+        for block in &leaves {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+        let peers = peer2.swarm().behaviour().peers();
+        assert_eq!(peers[0].protocols, vec![PIPELINE]);
+        assert_eq!(peers[0].in_flight, 0);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_pipeline_push() {
+        tracing_try_init();
+        let store = Store::default();
+        let mut behaviour = Bitswap::new(pipelining(), store.clone());
+        behaviour.set_push_policy(|_: &PeerId, _: &Block<DefaultParams>| true);
+        let peer1 = Peer::with_behaviour(store.clone(), behaviour);
+        let mut peer2 = Peer::with_config(pipelining());
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer2.store().insert(*block.cid(), block.data().to_vec());
+        let addr = peer1.addr.clone();
+        let peer1 = peer1.spawn("peer1");
+        peer2.connect(peer1, addr).await;
+
+        // the pushed block is sent on the pipelined substream
+        let id = peer2.swarm().behaviour_mut().push(peer1, *block.cid());
+        assert_complete_ok(peer2.next().await, id);
+        assert!(store.0.lock().unwrap().contains_key(block.cid()));
+        let peers = peer2.swarm().behaviour().peers();
+        assert_eq!(peers[0].protocols, vec![PIPELINE]);
+        assert!(peers[0].capabilities.contains(Capabilities::PUSH));
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_pipeline_fallback() {
+        tracing_try_init();
+        let mut peer1 = Peer::new();
+        let mut peer2 = Peer::with_config(pipelining());
+
+        let block = create_block(ipld!(&b"hello world"[..]));
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let addr = peer1.addr.clone();
+        let peer1 = peer1.spawn("peer1");
+        peer2.connect(peer1, addr).await;
+
+        // peer1 refuses the pipelined substream, so the request is sent again
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        assert_complete_ok(peer2.next().await, id);
+        let peers = peer2.swarm().behaviour().peers();
+        assert_eq!(peers[0].protocols, vec![NATIVE]);
+
+        let block = create_block(ipld!(&b"hello again"[..]));
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        assert!(matches!(
+            peer2.next().await,
+            Some(BitswapEvent::Complete(id2, Err(_))) if id2 == id
+        ));
+    }
+
+    /// Pipelining peer whose reads of the block wait until another block was read.
+    fn gated_peer(gated: Cid) -> Peer {
+        let store = Store::default();
+        let gated = GatedStore::new(store.clone(), gated);
+        Peer::with_behaviour(store, Bitswap::new_async(pipelining(), gated))
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_pipeline_out_of_order() {
+        tracing_try_init();
+        let slow = create_block(ipld!(&b"slow"[..]));
+        let fast = create_block(ipld!(&b"fast"[..]));
+        let mut peer1 = gated_peer(*slow.cid());
+        let mut peer2 = Peer::with_config(pipelining());
+        for block in [&slow, &fast] {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let addr = peer1.addr.clone();
+        let peer1 = peer1.spawn("peer1");
+        peer2.connect(peer1, addr).await;
+
+        let slow_id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*slow.cid(), std::iter::once(peer1));
+        // the slow request is in flight before the fast one is sent
+        let res = async_std::future::timeout(Duration::from_millis(500), peer2.next()).await;
+        assert!(res.is_err());
+        let fast_id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*fast.cid(), std::iter::once(peer1));
+        assert_complete_ok(peer2.next().await, fast_id);
+        assert_complete_ok(peer2.next().await, slow_id);
+        let peers = peer2.swarm().behaviour().peers();
+        assert_eq!(peers[0].protocols, vec![PIPELINE]);
+        assert_eq!(peers[0].in_flight, 0);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_pipeline_timeout() {
+        tracing_try_init();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let mut peer1 = gated_peer(*block.cid());
+        let mut peer2 = Peer::with_config(BitswapConfig {
+            request_timeout: Duration::from_secs(1),
+            ..pipelining()
+        });
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let addr = peer1.addr.clone();
+        let peer1 = peer1.spawn("peer1");
+        peer2.connect(peer1, addr).await;
+
+        // peer1 never answers
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        assert!(matches!(
+            peer2.next().await,
+            Some(BitswapEvent::Complete(id2, Err(_))) if id2 == id
+        ));
+        let peers = peer2.swarm().behaviour().peers();
+        assert_eq!(peers[0].protocols, vec![PIPELINE]);
+        assert_eq!(peers[0].in_flight, 0);
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_pipeline_connection_closed() {
+        tracing_try_init();
+        let block = create_block(ipld!(&b"hello world"[..]));
+        let mut peer1 = gated_peer(*block.cid());
+        let mut peer2 = Peer::with_config(pipelining());
+        peer1.store().insert(*block.cid(), block.data().to_vec());
+        let addr = peer1.addr.clone();
+        let peer1 = peer1.spawn("peer1");
+        peer2.connect(peer1, addr).await;
+
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*block.cid(), std::iter::once(peer1));
+        let res = async_std::future::timeout(Duration::from_millis(500), peer2.next()).await;
+        assert!(res.is_err());
+        assert_eq!(peer2.swarm().behaviour().peers()[0].in_flight, 1);
+
+        // the request in flight fails with the connection
+        peer2.swarm().disconnect_peer_id(peer1).unwrap();
+        assert!(matches!(
+            peer2.next().await,
+            Some(BitswapEvent::Complete(id2, Err(_))) if id2 == id
+        ));
+        assert!(peer2.swarm().behaviour().in_flight().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bitswap_cancel_sync() {
         tracing_try_init();
         let mut peer1 = Peer::new();
         let mut peer2 = Peer::new();
@@ -939,6 +4114,47 @@ mod tests {
         assert!(res.is_none());
     }
 
+    #[cfg(feature = "compat")]
+    #[async_std::test]
+    async fn test_bitswap_compat_sync() {
+        tracing_try_init();
+        let config = BitswapConfig {
+            enable_native: false,
+            ..BitswapConfig::new()
+        };
+        let mut peer1 = Peer::with_config(config);
+        let mut peer2 = Peer::with_config(config);
+
+        let blocks = create_chain(8);
+        for block in &blocks {
+            peer1.store().insert(*block.cid(), block.data().to_vec());
+        }
+        let addr = peer1.addr.clone();
+        let peer1 = peer1.spawn("peer1");
+        // compat messages are only sent to connected peers
+        peer2.connect(peer1, addr).await;
+
+        // every block is a message on the substreams that stay open
+        let root = *blocks.last().unwrap().cid();
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .sync(root, vec![peer1], std::iter::once(root));
+        assert_sync_ok(&mut peer2, id).await;
+        for block in &blocks {
+            assert!(peer2.store().contains_key(block.cid()));
+        }
+        let peers = peer2.swarm().behaviour().peers();
+        assert_eq!(peers[0].protocols, vec![SupportedProtocol::Compat]);
+
+        let missing = create_block(ipld!(&b"missing"[..]));
+        let id = peer2
+            .swarm()
+            .behaviour_mut()
+            .get(*missing.cid(), std::iter::once(peer1));
+        assert_complete_not_found(peer2.next().await, id);
+    }
+
     #[cfg(feature = "compat")]
     #[async_std::test]
     async fn compat_test() {
@@ -0,0 +1,426 @@
+//! Connection handler of `/ipfs/bitswap/1.2.0`.
+//!
+//! go-bitswap opens one substream to a peer, sends all its messages on it and reads
+//! any number of messages from every substream the peer opens. Responses are sent on
+//! the responder's own substream, not on the one the requests came in on. The handler
+//! does the same, so that a block costs a message instead of a substream negotiation.
+use crate::compat::protocol::{encode_message, read_message};
+use crate::compat::{CompatMessage, CompatProtocol};
+use crate::handler::classify;
+use crate::reputation::Misbehaviour;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::io::AsyncWriteExt;
+use futures::stream::{self, BoxStream, SelectAll};
+use futures::{FutureExt, StreamExt};
+use libipld::Cid;
+use libp2p::core::upgrade::{NegotiationError, UpgradeError};
+use libp2p::swarm::handler::{
+    ConnectionEvent, ConnectionHandlerUpgrErr, DialUpgradeError, FullyNegotiatedInbound,
+    FullyNegotiatedOutbound,
+};
+use libp2p::swarm::{
+    ConnectionHandler, ConnectionHandlerEvent, KeepAlive, NegotiatedSubstream, SubstreamProtocol,
+};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::io;
+use std::iter;
+use std::task::{Context, Poll};
+
+/// Maximum number of inbound substreams that are read at the same time. go-bitswap
+/// opens a new one after the last one failed.
+const MAX_INBOUND_STREAMS: usize = 8;
+
+/// Number of times a message is written before it is dropped. A substream the remote
+/// closed fails on the next write, so the message is written again on a new one.
+const MAX_ATTEMPTS: u8 = 2;
+
+/// Number of times in a row opening the outbound substream may fail before the queued
+/// messages are dropped.
+const MAX_OPEN_ATTEMPTS: u8 = 3;
+
+/// Maximum number of messages waiting to be written. Inbound substreams aren't read
+/// while the queue is full, and messages beyond it are dropped.
+const MAX_QUEUED_MESSAGES: usize = 1024;
+
+/// Event emitted by the [`CompatHandler`].
+#[derive(Debug)]
+pub enum CompatEvent {
+    /// The remote sent a message.
+    Message(Vec<CompatMessage>),
+    /// The remote sent a message that violates the protocol.
+    Misbehaviour(Misbehaviour),
+    /// The requests for the cids were dropped without being sent.
+    Dropped(Vec<Cid>),
+    /// The pushed block was written and flushed to the substream.
+    Sent(Cid),
+}
+
+/// Encoded message waiting to be written.
+struct Frame {
+    bytes: Bytes,
+    /// Number of failed attempts.
+    attempts: u8,
+    /// Cid of the request, which is reported if the message is dropped.
+    request: Option<Cid>,
+    /// Whether the request pushes a block, which is reported once it is written.
+    push: bool,
+}
+
+enum Outbound {
+    Closed,
+    Opening,
+    Idle(NegotiatedSubstream),
+    Writing(BoxFuture<'static, io::Result<NegotiatedSubstream>>),
+    Unsupported,
+}
+
+/// Connection handler of the compat protocol.
+pub struct CompatHandler {
+    protocol: CompatProtocol,
+    outbound: Outbound,
+    /// Encoded messages waiting to be written.
+    queue: VecDeque<Frame>,
+    /// Message that is being written.
+    writing: Option<Frame>,
+    /// Number of failed attempts to open the outbound substream since it was last open.
+    open_failures: u8,
+    inbound: SelectAll<BoxStream<'static, io::Result<Vec<CompatMessage>>>>,
+    events: VecDeque<CompatEvent>,
+}
+
+impl CompatHandler {
+    /// Creates a handler that sends and accepts substreams of the protocol.
+    pub fn new(protocol: CompatProtocol) -> Self {
+        Self {
+            protocol,
+            outbound: Outbound::Closed,
+            queue: Default::default(),
+            writing: None,
+            open_failures: 0,
+            inbound: Default::default(),
+            events: Default::default(),
+        }
+    }
+
+    /// Reports the requests of messages that are dropped.
+    fn dropped(&mut self, requests: impl IntoIterator<Item = Option<Cid>>) {
+        let cids: Vec<Cid> = requests.into_iter().flatten().collect();
+        if !cids.is_empty() {
+            self.events.push_back(CompatEvent::Dropped(cids));
+        }
+    }
+
+    /// Writes the queued messages to the outbound substream.
+    fn poll_write(&mut self, cx: &mut Context<'_>) {
+        loop {
+            match std::mem::replace(&mut self.outbound, Outbound::Closed) {
+                Outbound::Idle(mut io) => {
+                    let frame = match self.queue.pop_front() {
+                        Some(next) => next,
+                        None => {
+                            self.outbound = Outbound::Idle(io);
+                            return;
+                        }
+                    };
+                    let bytes = frame.bytes.clone();
+                    self.writing = Some(frame);
+                    self.outbound = Outbound::Writing(
+                        async move {
+                            io.write_all(&bytes).await?;
+                            io.flush().await?;
+                            Ok(io)
+                        }
+                        .boxed(),
+                    );
+                }
+                Outbound::Writing(mut fut) => match fut.poll_unpin(cx) {
+                    Poll::Ready(Ok(io)) => {
+                        if let Some(Frame {
+                            request: Some(cid),
+                            push: true,
+                            ..
+                        }) = self.writing.take()
+                        {
+                            self.events.push_back(CompatEvent::Sent(cid));
+                        }
+                        self.outbound = Outbound::Idle(io);
+                    }
+                    Poll::Ready(Err(err)) => {
+                        tracing::debug!("compat substream failed: {}", err);
+                        if let Some(mut frame) = self.writing.take() {
+                            frame.attempts += 1;
+                            if frame.attempts < MAX_ATTEMPTS {
+                                self.queue.push_front(frame);
+                            } else {
+                                tracing::debug!("dropping compat message");
+                                self.dropped(iter::once(frame.request));
+                            }
+                        }
+                        // reopened by the next message
+                        return;
+                    }
+                    Poll::Pending => {
+                        self.outbound = Outbound::Writing(fut);
+                        return;
+                    }
+                },
+                outbound => {
+                    self.outbound = outbound;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for CompatHandler {
+    type InEvent = CompatMessage;
+    type OutEvent = CompatEvent;
+    type Error = Infallible;
+    type InboundProtocol = CompatProtocol;
+    type OutboundProtocol = CompatProtocol;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(self.protocol.clone(), ())
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        match self.outbound {
+            Outbound::Opening | Outbound::Writing(_) => KeepAlive::Yes,
+            _ if !self.queue.is_empty() => KeepAlive::Yes,
+            _ => KeepAlive::No,
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::OutEvent,
+            Self::Error,
+        >,
+    > {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::Custom(event));
+        }
+        self.poll_write(cx);
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::Custom(event));
+        }
+        if let Outbound::Closed = self.outbound {
+            if !self.queue.is_empty() {
+                self.outbound = Outbound::Opening;
+                return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                    protocol: SubstreamProtocol::new(self.protocol.clone(), ()),
+                });
+            }
+        }
+        // the remote's requests are answered on the outbound substream, so they aren't
+        // read while it is behind
+        while self.queue.len() < MAX_QUEUED_MESSAGES {
+            match self.inbound.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(msg))) => {
+                    return Poll::Ready(ConnectionHandlerEvent::Custom(CompatEvent::Message(msg)));
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    // the substream ends after the error
+                    tracing::debug!("compat substream failed: {}", err);
+                    if let Some(misbehaviour) = classify(&err) {
+                        return Poll::Ready(ConnectionHandlerEvent::Custom(
+                            CompatEvent::Misbehaviour(misbehaviour),
+                        ));
+                    }
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+        Poll::Pending
+    }
+
+    fn on_behaviour_event(&mut self, msg: Self::InEvent) {
+        let (request, push) = match &msg {
+            CompatMessage::Request(request) => (Some(request.cid), request.is_push()),
+            CompatMessage::Response(_, _) => (None, false),
+        };
+        if let Outbound::Unsupported = self.outbound {
+            tracing::debug!("dropping compat message to a peer without compat support");
+            return self.dropped(iter::once(request));
+        }
+        if self.queue.len() >= MAX_QUEUED_MESSAGES {
+            tracing::debug!("compat queue is full, dropping message");
+            return self.dropped(iter::once(request));
+        }
+        match encode_message(&msg) {
+            Ok(bytes) => self.queue.push_back(Frame {
+                bytes: bytes.into(),
+                attempts: 0,
+                request,
+                push,
+            }),
+            Err(err) => {
+                tracing::debug!("dropping compat message: {}", err);
+                self.dropped(iter::once(request));
+            }
+        }
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: stream,
+                ..
+            }) => {
+                if self.inbound.len() >= MAX_INBOUND_STREAMS {
+                    tracing::debug!("too many compat substreams, dropping the new one");
+                    return;
+                }
+                let reader = stream::try_unfold(stream, |mut io| async move {
+                    let msg = read_message(&mut io).await?;
+                    Ok(msg.map(|msg| (msg, io)))
+                });
+                self.inbound.push(reader.boxed());
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: stream,
+                ..
+            }) => {
+                self.open_failures = 0;
+                self.outbound = Outbound::Idle(stream);
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { error, .. }) => {
+                tracing::debug!("compat substream failed: {:?}", error);
+                let unsupported = matches!(
+                    error,
+                    ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(
+                        NegotiationError::Failed
+                    ))
+                );
+                self.open_failures += 1;
+                if !unsupported && self.open_failures < MAX_OPEN_ATTEMPTS {
+                    // reopened by `poll` while messages are queued
+                    self.outbound = Outbound::Closed;
+                    return;
+                }
+                self.outbound = if unsupported {
+                    Outbound::Unsupported
+                } else {
+                    Outbound::Closed
+                };
+                self.open_failures = 0;
+                let queue = std::mem::take(&mut self.queue);
+                self.dropped(queue.into_iter().map(|frame| frame.request));
+            }
+            ConnectionEvent::ListenUpgradeError(_) | ConnectionEvent::AddressChange(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::tests::create_cid;
+    use crate::protocol::{BitswapRequest, RequestType};
+    use futures::task::noop_waker_ref;
+
+    fn request(n: usize) -> CompatMessage {
+        CompatMessage::Request(BitswapRequest {
+            ty: RequestType::Have,
+            cid: create_cid(&n.to_be_bytes()),
+        })
+    }
+
+    fn poll_event(handler: &mut CompatHandler) -> Option<CompatEvent> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        loop {
+            match handler.poll(&mut cx) {
+                Poll::Ready(ConnectionHandlerEvent::Custom(event)) => return Some(event),
+                Poll::Ready(_) => {}
+                Poll::Pending => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_full_queue() {
+        let mut handler = CompatHandler::new(CompatProtocol::new(""));
+        for n in 0..=MAX_QUEUED_MESSAGES {
+            handler.on_behaviour_event(request(n));
+        }
+        let inbound = stream::iter(vec![Ok(vec![request(0)])]);
+        handler.inbound.push(inbound.boxed());
+
+        // the last request doesn't fit and the remote isn't read
+        match poll_event(&mut handler) {
+            Some(CompatEvent::Dropped(cids)) => {
+                assert_eq!(cids, vec![create_cid(&MAX_QUEUED_MESSAGES.to_be_bytes())]);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(poll_event(&mut handler).is_none());
+
+        // the queued requests are reported when the remote doesn't support the protocol
+        handler.on_connection_event(ConnectionEvent::DialUpgradeError(DialUpgradeError {
+            info: (),
+            error: ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(
+                NegotiationError::Failed,
+            )),
+        }));
+        match poll_event(&mut handler) {
+            Some(CompatEvent::Dropped(cids)) => assert_eq!(cids.len(), MAX_QUEUED_MESSAGES),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(matches!(
+            poll_event(&mut handler),
+            Some(CompatEvent::Message(_))
+        ));
+    }
+
+    #[test]
+    fn test_open_retry() {
+        let mut handler = CompatHandler::new(CompatProtocol::new(""));
+        handler.on_behaviour_event(request(0));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        for _ in 1..MAX_OPEN_ATTEMPTS {
+            assert!(matches!(
+                handler.poll(&mut cx),
+                Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { .. })
+            ));
+            // a timeout keeps the queue and opens another substream
+            handler.on_connection_event(ConnectionEvent::DialUpgradeError(DialUpgradeError {
+                info: (),
+                error: ConnectionHandlerUpgrErr::Timeout,
+            }));
+            assert_eq!(handler.queue.len(), 1);
+        }
+        assert!(matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { .. })
+        ));
+        // the request is dropped once too many attempts failed
+        handler.on_connection_event(ConnectionEvent::DialUpgradeError(DialUpgradeError {
+            info: (),
+            error: ConnectionHandlerUpgrErr::Timeout,
+        }));
+        match poll_event(&mut handler) {
+            Some(CompatEvent::Dropped(cids)) => {
+                assert_eq!(cids, vec![create_cid(&0usize.to_be_bytes())])
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+}
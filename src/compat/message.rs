@@ -1,6 +1,7 @@
 use crate::compat::other;
 use crate::compat::prefix::Prefix;
 use crate::protocol::{BitswapRequest, BitswapResponse, RequestType};
+use bytes::Bytes;
 use libipld::Cid;
 use prost::Message;
 use std::convert::TryFrom;
@@ -20,13 +21,29 @@ impl CompatMessage {
     pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
         let mut msg = bitswap_pb::Message::default();
         match self {
+            CompatMessage::Request(BitswapRequest {
+                ty: RequestType::Push(data),
+                cid,
+            }) => {
+                // bitswap 1.2.0 has no push request, the block is sent unsolicited
+                let payload = bitswap_pb::message::Block {
+                    prefix: Prefix::from(cid).to_bytes(),
+                    data: data.clone(),
+                };
+                msg.payload.push(payload);
+            }
             CompatMessage::Request(BitswapRequest { ty, cid }) => {
                 let mut wantlist = bitswap_pb::message::Wantlist::default();
                 let entry = bitswap_pb::message::wantlist::Entry {
                     block: cid.to_bytes(),
                     want_type: match ty {
                         RequestType::Have => bitswap_pb::message::wantlist::WantType::Have,
-                        RequestType::Block => bitswap_pb::message::wantlist::WantType::Block,
+                        RequestType::Block
+                        | RequestType::Push(_)
+                        | RequestType::Dag(_)
+                        | RequestType::Reconcile(_, _) => {
+                            bitswap_pb::message::wantlist::WantType::Block
+                        }
                     } as _,
                     send_dont_have: true,
                     cancel: false,
@@ -35,10 +52,19 @@ impl CompatMessage {
                 wantlist.entries.push(entry);
                 msg.wantlist = Some(wantlist);
             }
-            CompatMessage::Response(cid, BitswapResponse::Have(have)) => {
+            CompatMessage::Response(cid, res @ BitswapResponse::Have(_))
+            | CompatMessage::Response(cid, res @ BitswapResponse::HaveSize(_))
+            | CompatMessage::Response(cid, res @ BitswapResponse::Busy(_))
+            | CompatMessage::Response(cid, res @ BitswapResponse::Unauthorized)
+            | CompatMessage::Response(cid, res @ BitswapResponse::Error) => {
+                // bitswap 1.2.0 has no block sizes and can't tell why a block isn't sent
+                let have = matches!(
+                    res,
+                    BitswapResponse::Have(true) | BitswapResponse::HaveSize(_)
+                );
                 let block_presence = bitswap_pb::message::BlockPresence {
                     cid: cid.to_bytes(),
-                    r#type: if *have {
+                    r#type: if have {
                         bitswap_pb::message::BlockPresenceType::Have
                     } else {
                         bitswap_pb::message::BlockPresenceType::DontHave
@@ -46,10 +72,11 @@ impl CompatMessage {
                 };
                 msg.block_presences.push(block_presence);
             }
-            CompatMessage::Response(cid, BitswapResponse::Block(bytes)) => {
+            CompatMessage::Response(cid, BitswapResponse::Block(bytes))
+            | CompatMessage::Response(_, BitswapResponse::DagBlock(cid, bytes)) => {
                 let payload = bitswap_pb::message::Block {
                     prefix: Prefix::from(cid).to_bytes(),
-                    data: bytes.to_vec(),
+                    data: bytes.clone(),
                 };
                 msg.payload.push(payload);
             }
@@ -59,7 +86,8 @@ impl CompatMessage {
         Ok(bytes)
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> io::Result<Vec<Self>> {
+    /// Decodes a message. The block data shares the buffer.
+    pub fn from_bytes(bytes: Bytes) -> io::Result<Vec<Self>> {
         let msg = bitswap_pb::Message::decode(bytes)?;
         let mut parts = vec![];
         for entry in msg.wantlist.unwrap_or_default().entries {
@@ -87,7 +115,7 @@ impl CompatMessage {
             let cid = prefix.to_cid(&payload.data)?;
             parts.push(CompatMessage::Response(
                 cid,
-                BitswapResponse::Block(payload.data.to_vec()),
+                BitswapResponse::Block(payload.data),
             ));
         }
         for presence in msg.block_presences {
@@ -1,9 +1,11 @@
+mod handler;
 mod message;
-mod prefix;
+pub(crate) mod prefix;
 mod protocol;
 
+pub use handler::{CompatEvent, CompatHandler};
 pub use message::CompatMessage;
-pub use protocol::{CompatProtocol, InboundMessage};
+pub use protocol::CompatProtocol;
 
 fn other<E: std::error::Error + Send + Sync + 'static>(e: E) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, e)
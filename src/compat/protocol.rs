@@ -1,124 +1,137 @@
-use crate::compat::{other, CompatMessage};
-use futures::future::BoxFuture;
-use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use libp2p::core::{upgrade, InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use crate::compat::CompatMessage;
+use crate::protocol::{invalid_data, MessageTooLarge};
+use futures::future;
+use futures::io::{AsyncRead, AsyncReadExt};
+use libp2p::core::{InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use libp2p::swarm::NegotiatedSubstream;
 use std::{io, iter};
+use unsigned_varint::{aio, io::ReadError};
 
 // 2MB Block Size according to the specs at https://github.com/ipfs/specs/blob/main/BITSWAP.md
 const MAX_BUF_SIZE: usize = 2_097_152;
 
-#[derive(Clone, Debug, Default)]
-pub struct CompatProtocol;
-
-impl UpgradeInfo for CompatProtocol {
-    type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+/// Upgrade of `/ipfs/bitswap/1.2.0`. The name may be prefixed, like go-bitswap does for
+/// private networks.
+///
+/// The negotiated substream stays open and carries any number of length prefixed
+/// messages in the direction it was opened in, like go-bitswap does.
+#[derive(Clone, Debug)]
+pub struct CompatProtocol {
+    name: String,
+}
 
-    fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(b"/ipfs/bitswap/1.2.0")
+impl CompatProtocol {
+    /// Creates the protocol with a name prefix, which is empty by default.
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            name: format!("{}/ipfs/bitswap/1.2.0", prefix),
+        }
     }
 }
 
-impl<TSocket> InboundUpgrade<TSocket> for CompatProtocol
-where
-    TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-{
-    type Output = InboundMessage;
-    type Error = io::Error;
-    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
-
-    fn upgrade_inbound(self, mut socket: TSocket, _info: Self::Info) -> Self::Future {
-        Box::pin(async move {
-            tracing::trace!("upgrading inbound");
-            let packet = upgrade::read_length_prefixed(&mut socket, MAX_BUF_SIZE)
-                .await
-                .map_err(|err| {
-                    tracing::debug!(%err, "inbound upgrade error");
-                    other(err)
-                })?;
-            socket.close().await?;
-            tracing::trace!("inbound upgrade done, closing");
-            let message = CompatMessage::from_bytes(&packet).map_err(|e| {
-                tracing::debug!(%e, "inbound upgrade error");
-                e
-            })?;
-            tracing::trace!("inbound upgrade closed");
-            Ok(InboundMessage(message))
-        })
+impl Default for CompatProtocol {
+    fn default() -> Self {
+        Self::new("")
     }
 }
 
-impl UpgradeInfo for CompatMessage {
-    type Info = &'static [u8];
+impl UpgradeInfo for CompatProtocol {
+    type Info = String;
     type InfoIter = iter::Once<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(b"/ipfs/bitswap/1.2.0")
+        iter::once(self.name.clone())
     }
 }
 
-impl<TSocket> OutboundUpgrade<TSocket> for CompatMessage
-where
-    TSocket: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-{
-    type Output = ();
+impl InboundUpgrade<NegotiatedSubstream> for CompatProtocol {
+    type Output = NegotiatedSubstream;
     type Error = io::Error;
-    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
-
-    fn upgrade_outbound(self, mut socket: TSocket, _info: Self::Info) -> Self::Future {
-        Box::pin(async move {
-            let bytes = self.to_bytes()?;
-            upgrade::write_length_prefixed(&mut socket, bytes).await?;
-            socket.close().await?;
-            Ok(())
-        })
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: NegotiatedSubstream, _info: Self::Info) -> Self::Future {
+        future::ok(socket)
     }
 }
 
-#[derive(Debug)]
-pub struct InboundMessage(pub Vec<CompatMessage>);
+impl OutboundUpgrade<NegotiatedSubstream> for CompatProtocol {
+    type Output = NegotiatedSubstream;
+    type Error = io::Error;
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
 
-impl From<()> for InboundMessage {
-    fn from(_: ()) -> Self {
-        Self(Default::default())
+    fn upgrade_outbound(self, socket: NegotiatedSubstream, _info: Self::Info) -> Self::Future {
+        future::ok(socket)
     }
 }
 
+/// Reads the next message of a substream, or `None` if the remote closed it.
+pub async fn read_message<T: AsyncRead + Send + Unpin>(
+    io: &mut T,
+) -> io::Result<Option<Vec<CompatMessage>>> {
+    let len = match aio::read_usize(&mut *io).await {
+        Ok(len) => len,
+        Err(ReadError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(ReadError::Io(err)) => return Err(err),
+        Err(err) => return Err(invalid_data(err)),
+    };
+    if len > MAX_BUF_SIZE {
+        return Err(invalid_data(MessageTooLarge(len)));
+    }
+    let mut packet = vec![0; len];
+    io.read_exact(&mut packet).await?;
+    CompatMessage::from_bytes(packet.into()).map(Some)
+}
+
+/// Encodes a message with its length prefix.
+pub fn encode_message(message: &CompatMessage) -> io::Result<Vec<u8>> {
+    let bytes = message.to_bytes()?;
+    let mut buf = unsigned_varint::encode::usize_buffer();
+    let mut frame = unsigned_varint::encode::usize(bytes.len(), &mut buf).to_vec();
+    frame.extend_from_slice(&bytes);
+    Ok(frame)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::{BitswapRequest, RequestType};
-    use async_std::net::{TcpListener, TcpStream};
-    use futures::prelude::*;
+    use crate::protocol::{BitswapRequest, BitswapResponse, RequestType};
+    use futures::io::Cursor;
     use libipld::Cid;
-    use libp2p::core::upgrade;
 
     #[async_std::test]
-    async fn test_upgrade() {
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let listener_addr = listener.local_addr().unwrap();
-
-        let server = async move {
-            let incoming = listener.incoming().into_future().await.0.unwrap().unwrap();
-            upgrade::apply_inbound(incoming, CompatProtocol)
-                .await
-                .unwrap();
-        };
-
-        let client = async move {
-            let stream = TcpStream::connect(&listener_addr).await.unwrap();
-            upgrade::apply_outbound(
-                stream,
-                CompatMessage::Request(BitswapRequest {
-                    ty: RequestType::Have,
-                    cid: Cid::default(),
-                }),
-                upgrade::Version::V1,
-            )
-            .await
-            .unwrap();
-        };
-
-        future::select(Box::pin(server), Box::pin(client)).await;
+    async fn test_messages() {
+        let request = CompatMessage::Request(BitswapRequest {
+            ty: RequestType::Have,
+            cid: Cid::default(),
+        });
+        let response = CompatMessage::Response(Cid::default(), BitswapResponse::Have(false));
+        let mut buf = encode_message(&request).unwrap();
+        buf.extend(encode_message(&response).unwrap());
+
+        // messages follow each other on the substream
+        let mut io = Cursor::new(buf);
+        let msg = read_message(&mut io).await.unwrap();
+        assert_eq!(msg, Some(vec![request]));
+        let msg = read_message(&mut io).await.unwrap();
+        assert_eq!(msg, Some(vec![response]));
+        assert_eq!(read_message(&mut io).await.unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn test_message_too_large() {
+        let mut buf = unsigned_varint::encode::usize_buffer();
+        let frame = unsigned_varint::encode::usize(MAX_BUF_SIZE + 1, &mut buf);
+        let mut io = Cursor::new(frame.to_vec());
+        let err = read_message(&mut io).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut frame = encode_message(&CompatMessage::Request(BitswapRequest {
+            ty: RequestType::Block,
+            cid: Cid::default(),
+        }))
+        .unwrap();
+        frame.pop();
+        let mut io = Cursor::new(frame);
+        assert!(read_message(&mut io).await.is_err());
     }
 }
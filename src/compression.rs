@@ -0,0 +1,141 @@
+//! Lz4 compression of the blocks in responses.
+//!
+//! Peers that both serve the compression capability may send a block as a compressed
+//! entry: the type byte 3, the varint uncompressed size and the compressed data. Blocks
+//! are only compressed if they are large enough and compression makes them smaller. The
+//! cid is verified on the decompressed data like for any other block.
+use crate::protocol::{invalid_data, BitswapResponse, Entry};
+use crate::stats::COMPRESSION_RATIO;
+use bytes::Bytes;
+use libipld::store::StoreParams;
+use std::io::{self, Write};
+use thiserror::Error;
+
+/// Blocks smaller than this are sent uncompressed.
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Type byte of a compressed block entry.
+const COMPRESSED_BLOCK: u8 = 3;
+
+/// Response as it is written by the lz4 protocol.
+pub enum CompressedResponse<'a> {
+    Plain(&'a BitswapResponse),
+    /// Uncompressed size and compressed data of a block.
+    Block(usize, Vec<u8>),
+}
+
+impl<'a> Entry for CompressedResponse<'a> {
+    fn write_header(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Self::Plain(res) => res.write_header(w),
+            Self::Block(len, _) => {
+                w.write_all(&[COMPRESSED_BLOCK])?;
+                let mut buf = unsigned_varint::encode::usize_buffer();
+                w.write_all(unsigned_varint::encode::usize(*len, &mut buf))
+            }
+        }
+    }
+
+    fn payload(&self) -> &[u8] {
+        match self {
+            Self::Plain(res) => res.payload(),
+            Self::Block(_, data) => data,
+        }
+    }
+}
+
+/// Compresses a block response if it pays off.
+pub fn compress(res: &BitswapResponse) -> CompressedResponse<'_> {
+    if let BitswapResponse::Block(data) = res {
+        if data.len() >= COMPRESSION_THRESHOLD {
+            let compressed = lz4_flex::block::compress(data);
+            // the header is at most 5 bytes longer than the one of a plain block
+            if compressed.len() + 5 < data.len() {
+                COMPRESSION_RATIO.observe(data.len() as f64 / compressed.len() as f64);
+                return CompressedResponse::Block(data.len(), compressed);
+            }
+        }
+    }
+    CompressedResponse::Plain(res)
+}
+
+/// Decodes a response that may contain a compressed block.
+pub fn decompress<P: StoreParams>(bytes: Bytes) -> io::Result<BitswapResponse> {
+    if bytes.first() != Some(&COMPRESSED_BLOCK) {
+        return BitswapResponse::from_bytes(bytes);
+    }
+    let (len, compressed) = unsigned_varint::decode::usize(&bytes[1..]).map_err(invalid_data)?;
+    if len > P::MAX_BLOCK_SIZE {
+        return Err(invalid_data(DecompressError::TooLarge(len)));
+    }
+    let mut data = vec![0; len];
+    let n = lz4_flex::block::decompress_into(compressed, &mut data)
+        .map_err(|err| invalid_data(DecompressError::Lz4(err)))?;
+    if n != len {
+        return Err(invalid_data(DecompressError::SizeMismatch(len, n)));
+    }
+    Ok(BitswapResponse::Block(data.into()))
+}
+
+#[derive(Debug, Error)]
+enum DecompressError {
+    #[error("compressed block too large {0}")]
+    TooLarge(usize),
+    #[error("decompressed {1} bytes instead of {0}")]
+    SizeMismatch(usize, usize),
+    #[error(transparent)]
+    Lz4(lz4_flex::block::DecompressError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::store::DefaultParams;
+
+    fn encode(res: &CompressedResponse) -> Bytes {
+        let mut buf = vec![];
+        res.write_header(&mut buf).unwrap();
+        buf.extend_from_slice(res.payload());
+        buf.into()
+    }
+
+    #[test]
+    fn test_compress_decompress() {
+        let block = BitswapResponse::Block(vec![42; 4096].into());
+        let compressed = compress(&block);
+        assert!(matches!(compressed, CompressedResponse::Block(4096, _)));
+        let bytes = encode(&compressed);
+        assert!(bytes.len() < 4096);
+        assert_eq!(decompress::<DefaultParams>(bytes).unwrap(), block);
+
+        // small and incompressible blocks are sent as they are
+        let small = BitswapResponse::Block(vec![42; 16].into());
+        assert!(matches!(compress(&small), CompressedResponse::Plain(_)));
+        let random: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        let random = BitswapResponse::Block(random.into());
+        let bytes = encode(&compress(&random));
+        assert_eq!(decompress::<DefaultParams>(bytes).unwrap(), random);
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        let mut bytes = vec![COMPRESSED_BLOCK];
+        let mut buf = unsigned_varint::encode::usize_buffer();
+        let len = DefaultParams::MAX_BLOCK_SIZE + 1;
+        bytes.extend_from_slice(unsigned_varint::encode::usize(len, &mut buf));
+        assert!(decompress::<DefaultParams>(bytes.into()).is_err());
+
+        let mut bytes = encode(&compress(&BitswapResponse::Block(vec![1; 4096].into()))).to_vec();
+        bytes[1] += 1;
+        assert!(decompress::<DefaultParams>(bytes.into()).is_err());
+    }
+
+    #[test]
+    fn test_decompress_malformed() {
+        // literals that don't fit the announced length used to panic in lz4_flex
+        let bytes = Bytes::from_static(&[
+            3, 9, 236, 91, 153, 210, 34, 49, 197, 223, 44, 4, 87, 126, 94, 200, 115, 191,
+        ]);
+        assert!(decompress::<DefaultParams>(bytes).is_err());
+    }
+}
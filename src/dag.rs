@@ -0,0 +1,379 @@
+//! Dag requests.
+//!
+//! A dag request asks a peer for a block together with the descendants it has. The
+//! peer answers with the block followed by its descendants in breadth first order,
+//! each with its cid, until the depth limit is reached or the response message is
+//! full. The requester stores a descendant only if it hashes to its cid and is linked
+//! from a block that came before it, and continues the sync from the blocks that are
+//! still missing.
+//!
+//! A reconcile request carries a filter of the blocks the requester has, which are
+//! neither sent nor descended into. Neither are descendants the access policy refuses
+//! to serve to the requester.
+use crate::access::AccessPolicy;
+use crate::behaviour::AsyncBitswapStore;
+use crate::protocol::{BitswapResponse, MAX_BATCH_ENTRIES};
+use crate::push::References;
+use crate::reconcile::BloomFilter;
+use crate::stats::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::{store::StoreParams, Block, Cid};
+use libp2p::PeerId;
+use std::sync::Arc;
+
+/// Dag request of a peer, which the db worker answers.
+pub(crate) struct DagRequest<P: StoreParams> {
+    pub peer: PeerId,
+    pub root: Cid,
+    pub depth: Option<u64>,
+    /// Filter of a reconcile request.
+    pub filter: Option<BloomFilter>,
+    pub references: References<P>,
+    /// The root was already checked against the policy, descendants are checked here.
+    pub access_policy: Option<Arc<dyn AccessPolicy>>,
+}
+
+impl<P: StoreParams> DagRequest<P> {
+    /// Returns true if a descendant is sent and descended into.
+    fn expand(&self, cid: &Cid) -> bool {
+        let known = self
+            .filter
+            .as_ref()
+            .map(|f| f.contains(cid))
+            .unwrap_or_default();
+        let allowed = self
+            .access_policy
+            .as_ref()
+            .map(|policy| policy.allow(&self.peer, cid))
+            .unwrap_or(true);
+        !known && allowed
+    }
+}
+
+/// Reads a block and the descendants that are in the store. The block is answered
+/// like a block request, the descendants follow it as long as they fit in a message.
+/// Descendants in the filter or refused by the access policy are left out together
+/// with the blocks below them.
+pub(crate) async fn read_dag<S: AsyncBitswapStore>(
+    store: &S,
+    request: &DagRequest<S::Params>,
+) -> Vec<BitswapResponse> {
+    let DagRequest {
+        root,
+        depth,
+        references,
+        ..
+    } = *request;
+    let mut responses = vec![];
+    let mut seen = FnvHashSet::default();
+    seen.insert(root);
+    let mut level = vec![root];
+    let mut level_depth = 0;
+    while !level.is_empty() && responses.len() < MAX_BATCH_ENTRIES {
+        level.truncate(MAX_BATCH_ENTRIES - responses.len());
+        let blocks = match store.get_many(&level).await {
+            Ok(blocks) if blocks.len() == level.len() => Some(blocks),
+            Ok(_) => {
+                tracing::error!("get_many returned the wrong number of results");
+                None
+            }
+            Err(err) => {
+                tracing::error!("error reading blocks: {}", err);
+                None
+            }
+        };
+        let blocks = match blocks {
+            Some(blocks) => blocks,
+            None if level_depth == 0 => {
+                RESPONSES_TOTAL.with_label_values(&["error"]).inc();
+                return vec![BitswapResponse::Error];
+            }
+            // the descendants that were read are sent
+            None => break,
+        };
+        let expand = depth.map_or(true, |depth| level_depth < depth);
+        let mut next = vec![];
+        for (cid, data) in level.into_iter().zip(blocks) {
+            let data = match data {
+                Some(data) => data,
+                None if level_depth == 0 => {
+                    RESPONSES_TOTAL.with_label_values(&["dont_have"]).inc();
+                    return vec![BitswapResponse::Have(false)];
+                }
+                // descendants the store doesn't have are left out
+                None => continue,
+            };
+            let data = if expand {
+                let block = Block::<S::Params>::new_unchecked(cid, data);
+                let mut refs = FnvHashSet::default();
+                if let Err(err) = references(&block, &mut refs) {
+                    tracing::debug!("can't send references of {}: {}", cid, err);
+                }
+                next.extend(
+                    refs.into_iter()
+                        .filter(|cid| request.expand(cid) && seen.insert(*cid)),
+                );
+                block.into_inner().1
+            } else {
+                data
+            };
+            RESPONSES_TOTAL.with_label_values(&["block"]).inc();
+            SENT_BLOCK_BYTES.inc_by(data.len() as u64);
+            if level_depth == 0 {
+                responses.push(BitswapResponse::Block(data.into()));
+            } else {
+                responses.push(BitswapResponse::DagBlock(cid, data.into()));
+            }
+        }
+        level = next;
+        level_depth += 1;
+    }
+    responses
+}
+
+/// Checks that the descendants in a dag response are linked from the blocks that
+/// were received before them.
+pub(crate) struct DagVerifier<P: StoreParams> {
+    references: References<P>,
+    depth: Option<u64>,
+    /// Blocks that were received.
+    seen: FnvHashSet<Cid>,
+    /// Links of the received blocks that may follow, with their depth.
+    links: FnvHashMap<Cid, u64>,
+}
+
+impl<P: StoreParams> DagVerifier<P> {
+    /// Starts with the block that was asked for.
+    pub fn new(root: &Block<P>, depth: Option<u64>, references: References<P>) -> Self {
+        let mut verifier = Self {
+            references,
+            depth,
+            seen: Default::default(),
+            links: Default::default(),
+        };
+        verifier.seen.insert(*root.cid());
+        verifier.add_links(root, 0);
+        verifier
+    }
+
+    fn add_links(&mut self, block: &Block<P>, depth: u64) {
+        if self.depth.map_or(false, |max| depth >= max) {
+            return;
+        }
+        let mut refs = FnvHashSet::default();
+        if let Err(err) = (self.references)(block, &mut refs) {
+            tracing::debug!("can't verify references of {}: {}", block.cid(), err);
+        }
+        for cid in refs {
+            if !self.seen.contains(&cid) {
+                self.links.entry(cid).or_insert(depth + 1);
+            }
+        }
+    }
+
+    /// Returns true if a descendant is linked from a block received before it and
+    /// within the depth limit.
+    pub fn verify(&mut self, block: &Block<P>) -> bool {
+        let depth = if let Some(depth) = self.links.remove(block.cid()) {
+            depth
+        } else {
+            return false;
+        };
+        self.seen.insert(*block.cid());
+        self.add_links(block, depth);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::BitswapStore;
+    use crate::db::BlockingStore;
+    use libipld::cbor::DagCborCodec;
+    use libipld::ipld;
+    use libipld::ipld::Ipld;
+    use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
+    use libipld::Result;
+
+    fn create_block(ipld: Ipld) -> Block<DefaultParams> {
+        Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
+    }
+
+    fn references(block: &Block<DefaultParams>, refs: &mut FnvHashSet<Cid>) -> Result<()> {
+        block.references(refs)
+    }
+
+    #[derive(Default)]
+    struct Store(FnvHashMap<Cid, Vec<u8>>);
+
+    impl BitswapStore for Store {
+        type Params = DefaultParams;
+        fn contains(&mut self, cid: &Cid) -> Result<bool> {
+            Ok(self.0.contains_key(cid))
+        }
+        fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.get(cid).cloned())
+        }
+        fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+            self.0.insert(*block.cid(), block.data().to_vec());
+            Ok(())
+        }
+        fn missing_blocks(&mut self, _cid: &Cid) -> Result<Vec<Cid>> {
+            Ok(vec![])
+        }
+    }
+
+    /// Chain of blocks starting at the root, every block links to the next one.
+    fn create_chain(n: u64) -> Vec<Block<DefaultParams>> {
+        let mut blocks = vec![create_block(ipld!({ "n": 0 }))];
+        for i in 1..n {
+            let prev = *blocks.last().unwrap().cid();
+            blocks.push(create_block(ipld!({ "prev": prev, "n": i })));
+        }
+        blocks.reverse();
+        blocks
+    }
+
+    fn dag_request(root: Cid, depth: Option<u64>) -> DagRequest<DefaultParams> {
+        DagRequest {
+            peer: PeerId::random(),
+            root,
+            depth,
+            filter: None,
+            references,
+            access_policy: None,
+        }
+    }
+
+    fn read(store: &BlockingStore<Store>, root: Cid, depth: Option<u64>) -> Vec<BitswapResponse> {
+        futures::executor::block_on(read_dag(store, &dag_request(root, depth)))
+    }
+
+    #[test]
+    fn test_read_dag() {
+        let blocks = create_chain(4);
+        let mut store = Store::default();
+        for block in &blocks {
+            store.insert(block).unwrap();
+        }
+        let store = BlockingStore::new(store);
+        let root = *blocks[0].cid();
+
+        let responses = read(&store, root, None);
+        assert_eq!(responses.len(), 4);
+        assert_eq!(
+            responses[0],
+            BitswapResponse::Block(blocks[0].data().to_vec().into())
+        );
+        for (response, block) in responses[1..].iter().zip(&blocks[1..]) {
+            let data = block.data().to_vec().into();
+            assert_eq!(response, &BitswapResponse::DagBlock(*block.cid(), data));
+        }
+
+        assert_eq!(read(&store, root, Some(2)).len(), 3);
+        assert_eq!(read(&store, root, Some(0)).len(), 1);
+        let missing = *create_block(ipld!({ "n": 100 })).cid();
+        assert_eq!(
+            read(&store, missing, None),
+            vec![BitswapResponse::Have(false)]
+        );
+    }
+
+    #[test]
+    fn test_read_dag_fills_message() {
+        let blocks = create_chain(MAX_BATCH_ENTRIES as u64 + 4);
+        let mut store = Store::default();
+        for block in &blocks {
+            store.insert(block).unwrap();
+        }
+        let store = BlockingStore::new(store);
+        let responses = read(&store, *blocks[0].cid(), None);
+        assert_eq!(responses.len(), MAX_BATCH_ENTRIES);
+    }
+
+    #[test]
+    fn test_read_dag_skips_missing_descendants() {
+        let b0 = create_block(ipld!({ "n": 0 }));
+        let b1 = create_block(ipld!({ "n": 1 }));
+        let b2 = create_block(ipld!({ "a": b0.cid(), "b": b1.cid() }));
+        let mut store = Store::default();
+        store.insert(&b2).unwrap();
+        store.insert(&b1).unwrap();
+        let store = BlockingStore::new(store);
+        let responses = read(&store, *b2.cid(), None);
+        assert_eq!(responses.len(), 2);
+        assert!(matches!(&responses[1], BitswapResponse::DagBlock(cid, _) if cid == b1.cid()));
+    }
+
+    #[test]
+    fn test_read_dag_skips_filtered_descendants() {
+        let blocks = create_chain(4);
+        let mut store = Store::default();
+        for block in &blocks {
+            store.insert(block).unwrap();
+        }
+        let store = BlockingStore::new(store);
+        let filter = BloomFilter::new(&[*blocks[2].cid()], 1024);
+        let request = DagRequest {
+            filter: Some(filter),
+            ..dag_request(*blocks[0].cid(), None)
+        };
+        let responses = futures::executor::block_on(read_dag(&store, &request));
+        // the blocks below a filtered block aren't sent either
+        assert_eq!(responses.len(), 2);
+        assert!(
+            matches!(&responses[1], BitswapResponse::DagBlock(cid, _) if cid == blocks[1].cid())
+        );
+
+        // the root is sent even if it is in the filter
+        let filter = BloomFilter::new(&[*blocks[0].cid()], 1024);
+        let request = DagRequest {
+            filter: Some(filter),
+            ..dag_request(*blocks[0].cid(), None)
+        };
+        let responses = futures::executor::block_on(read_dag(&store, &request));
+        assert_eq!(responses.len(), 4);
+    }
+
+    #[test]
+    fn test_read_dag_skips_refused_descendants() {
+        let blocks = create_chain(4);
+        let mut store = Store::default();
+        for block in &blocks {
+            store.insert(block).unwrap();
+        }
+        let store = BlockingStore::new(store);
+        let refused = *blocks[2].cid();
+        let policy = move |_: &PeerId, cid: &Cid| *cid != refused;
+        let request = DagRequest {
+            access_policy: Some(Arc::new(policy)),
+            ..dag_request(*blocks[0].cid(), None)
+        };
+        let responses = futures::executor::block_on(read_dag(&store, &request));
+        // the blocks below a refused block aren't sent either
+        assert_eq!(responses.len(), 2);
+        assert!(
+            matches!(&responses[1], BitswapResponse::DagBlock(cid, _) if cid == blocks[1].cid())
+        );
+    }
+
+    #[test]
+    fn test_verify_dag() {
+        let blocks = create_chain(4);
+        let mut verifier = DagVerifier::new(&blocks[0], None, references);
+        // not linked from a received block yet
+        assert!(!verifier.verify(&blocks[2]));
+        assert!(verifier.verify(&blocks[1]));
+        // received twice
+        assert!(!verifier.verify(&blocks[1]));
+        assert!(verifier.verify(&blocks[2]));
+        assert!(!verifier.verify(&create_block(ipld!({ "n": 100 }))));
+
+        let mut verifier = DagVerifier::new(&blocks[0], Some(1), references);
+        assert!(verifier.verify(&blocks[1]));
+        // below the depth limit
+        assert!(!verifier.verify(&blocks[2]));
+    }
+}
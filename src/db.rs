@@ -0,0 +1,497 @@
+//! The db worker answers store requests on a dedicated thread.
+//!
+//! Requests that are queued at the same time are coalesced into batches. Requests
+//! from peers are answered concurrently. Inserts, missing blocks queries and local
+//! reads are processed in order, so that they always see the blocks that were
+//! inserted before they were issued.
+use crate::behaviour::{AsyncBitswapStore, BitswapChannel, BitswapStore};
+use crate::dag::{read_dag, DagRequest};
+use crate::protocol::{BitswapRequest, BitswapResponse, RequestType};
+use crate::push::References;
+use crate::query::QueryId;
+use crate::reconcile::{read_filter, BloomFilter};
+use crate::stats::*;
+use async_trait::async_trait;
+use futures::{
+    channel::{mpsc, oneshot},
+    sink::SinkExt,
+    stream::{FuturesUnordered, StreamExt},
+};
+use libipld::{store::StoreParams, Block, Cid, Result};
+use libp2p::PeerId;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Maximum number of requests that are coalesced into a single store call.
+const MAX_BATCH_SIZE: usize = 128;
+
+pub(crate) enum DbRequest<P: StoreParams> {
+    Bitswap(BitswapChannel, BitswapRequest),
+    /// Dag request of an inbound native message.
+    Dag(u64, DagRequest<P>),
+    Insert(QueryId, PeerId, Block<P>),
+    /// Descendant received in a dag response.
+    InsertDag(Block<P>),
+    MissingBlocks(QueryId, Cid),
+    Contains(QueryId, Cid),
+    Get(QueryId, Cid),
+    Push(Option<BitswapChannel>, Block<P>),
+    /// Collects the blocks of a dag that are in the store for a reconcile query, down
+    /// to the dag depth.
+    Filter(QueryId, Cid, Option<u64>, References<P>),
+}
+
+pub(crate) enum DbResponse {
+    Bitswap(BitswapChannel, BitswapResponse),
+    Dag(u64, Vec<BitswapResponse>),
+    Insert(QueryId, PeerId, Result<()>),
+    MissingBlocks(QueryId, Result<Vec<Cid>>),
+    Contains(QueryId, Result<bool>),
+    Get(QueryId, Cid, Result<Option<Vec<u8>>>),
+    Filter(QueryId, Result<BloomFilter>),
+}
+
+/// Adapts a [`BitswapStore`] to the [`AsyncBitswapStore`] interface.
+///
+/// The methods of the wrapped store are called on the db thread and complete
+/// the first time they are polled.
+pub struct BlockingStore<S>(Mutex<S>);
+
+impl<S> BlockingStore<S> {
+    /// Wraps a synchronous store.
+    pub fn new(store: S) -> Self {
+        Self(Mutex::new(store))
+    }
+
+    /// Returns the wrapped store.
+    pub fn into_inner(self) -> S {
+        self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, S> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[async_trait]
+impl<S: BitswapStore> AsyncBitswapStore for BlockingStore<S> {
+    type Params = S::Params;
+
+    async fn contains(&self, cid: &Cid) -> Result<bool> {
+        self.lock().contains(cid)
+    }
+
+    async fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.lock().get(cid)
+    }
+
+    async fn insert(&self, block: &Block<Self::Params>) -> Result<()> {
+        self.lock().insert(block)
+    }
+
+    async fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.lock().missing_blocks(cid)
+    }
+
+    async fn contains_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        self.lock().contains_many(cids)
+    }
+
+    async fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.lock().get_many(cids)
+    }
+
+    async fn insert_batch(&self, blocks: &[Block<Self::Params>]) -> Result<()> {
+        self.lock().insert_batch(blocks)
+    }
+
+    async fn size(&self, cid: &Cid) -> Result<Option<usize>> {
+        self.lock().size(cid)
+    }
+
+    async fn size_many(&self, cids: &[Cid]) -> Result<Vec<Option<usize>>> {
+        self.lock().size_many(cids)
+    }
+}
+
+/// Origin of a block that is inserted.
+enum Inserter {
+    /// Block received for a query.
+    Query(QueryId, PeerId),
+    /// Block pushed by a peer, answered on the channel when stored.
+    Push(Option<BitswapChannel>),
+    /// Descendant received in a dag response, which nobody waits for.
+    Dag,
+}
+
+/// Requests that are answered with a single store call.
+enum Batch<P: StoreParams> {
+    Have(Vec<(BitswapChannel, Cid)>),
+    Block(Vec<(BitswapChannel, Cid)>),
+    Dag(u64, DagRequest<P>),
+    Insert(Vec<Inserter>, Vec<Block<P>>),
+    MissingBlocks(QueryId, Cid),
+    Contains(QueryId, Cid),
+    Get(QueryId, Cid),
+    Filter(QueryId, Cid, Option<u64>, References<P>),
+}
+
+impl<P: StoreParams> Batch<P> {
+    /// Splits the requests from peers into a have batch, a block batch and dag requests,
+    /// which are answered concurrently. Appends inserts, missing blocks queries and
+    /// filters to the ordered queue, coalescing consecutive inserts.
+    fn coalesce(requests: Vec<DbRequest<P>>, queue: &mut VecDeque<Batch<P>>) -> Vec<Batch<P>> {
+        let mut haves = vec![];
+        let mut blocks = vec![];
+        let mut concurrent = vec![];
+        for request in requests {
+            match request {
+                DbRequest::Bitswap(channel, request) => match request.ty {
+                    RequestType::Have => haves.push((channel, request.cid)),
+                    RequestType::Block | RequestType::Dag(_) | RequestType::Reconcile(_, _) => {
+                        blocks.push((channel, request.cid))
+                    }
+                    RequestType::Push(_) => tracing::error!("push request not inserted"),
+                },
+                DbRequest::Dag(id, request) => concurrent.push(Batch::Dag(id, request)),
+                DbRequest::Insert(id, peer, block) => {
+                    Self::push_insert(queue, Inserter::Query(id, peer), block);
+                }
+                DbRequest::InsertDag(block) => {
+                    Self::push_insert(queue, Inserter::Dag, block);
+                }
+                DbRequest::Push(channel, block) => {
+                    Self::push_insert(queue, Inserter::Push(channel), block);
+                }
+                DbRequest::MissingBlocks(id, cid) => {
+                    queue.push_back(Batch::MissingBlocks(id, cid));
+                }
+                DbRequest::Contains(id, cid) => {
+                    queue.push_back(Batch::Contains(id, cid));
+                }
+                DbRequest::Get(id, cid) => {
+                    queue.push_back(Batch::Get(id, cid));
+                }
+                DbRequest::Filter(id, cid, depth, references) => {
+                    queue.push_back(Batch::Filter(id, cid, depth, references));
+                }
+            }
+        }
+        if !haves.is_empty() {
+            concurrent.push(Batch::Have(haves));
+        }
+        if !blocks.is_empty() {
+            concurrent.push(Batch::Block(blocks));
+        }
+        concurrent
+    }
+
+    /// Appends an insert to the ordered queue, coalescing it with a preceding insert.
+    fn push_insert(queue: &mut VecDeque<Batch<P>>, inserter: Inserter, block: Block<P>) {
+        if let Some(Batch::Insert(inserters, blocks)) = queue.back_mut() {
+            if blocks.len() < MAX_BATCH_SIZE {
+                inserters.push(inserter);
+                blocks.push(block);
+                return;
+            }
+        }
+        queue.push_back(Batch::Insert(vec![inserter], vec![block]));
+    }
+}
+
+impl Inserter {
+    fn response(self, res: Result<()>) -> Option<DbResponse> {
+        match self {
+            Self::Query(id, peer) => Some(DbResponse::Insert(id, peer, res)),
+            Self::Push(channel) => {
+                let stored = res.is_ok();
+                let label = if stored { "stored" } else { "refused" };
+                PUSHED_BLOCKS.with_label_values(&[label]).inc();
+                channel.map(|channel| DbResponse::Bitswap(channel, BitswapResponse::Have(stored)))
+            }
+            Self::Dag => None,
+        }
+    }
+}
+
+async fn handle_batch<S: AsyncBitswapStore>(
+    store: &S,
+    batch: Batch<S::Params>,
+) -> Vec<DbResponse> {
+    match batch {
+        Batch::Have(requests) => {
+            let cids: Vec<Cid> = requests.iter().map(|(_, cid)| *cid).collect();
+            let sizes = match store.size_many(&cids).await {
+                Ok(sizes) if sizes.len() == cids.len() => sizes,
+                Ok(_) => {
+                    tracing::error!("size_many returned the wrong number of results");
+                    vec![None; cids.len()]
+                }
+                Err(_) => vec![None; cids.len()],
+            };
+            // blocks of unknown size still need to be looked up
+            let unsized_cids: Vec<Cid> = cids
+                .iter()
+                .zip(&sizes)
+                .filter(|(_, size)| size.is_none())
+                .map(|(cid, _)| *cid)
+                .collect();
+            // `None` if the store failed to look up the block
+            let haves = if unsized_cids.is_empty() {
+                vec![]
+            } else {
+                match store.contains_many(&unsized_cids).await {
+                    Ok(haves) if haves.len() == unsized_cids.len() => {
+                        haves.into_iter().map(Some).collect()
+                    }
+                    Ok(_) => {
+                        tracing::error!("contains_many returned the wrong number of results");
+                        vec![None; unsized_cids.len()]
+                    }
+                    Err(err) => {
+                        tracing::error!("error looking up blocks: {}", err);
+                        vec![None; unsized_cids.len()]
+                    }
+                }
+            };
+            let mut haves = haves.into_iter();
+            requests
+                .into_iter()
+                .zip(sizes)
+                .map(|((channel, _), size)| {
+                    let response = match size {
+                        Some(size) => BitswapResponse::HaveSize(size),
+                        None => match haves.next().flatten() {
+                            Some(have) => BitswapResponse::Have(have),
+                            None => BitswapResponse::Error,
+                        },
+                    };
+                    match response {
+                        BitswapResponse::Have(false) => {
+                            RESPONSES_TOTAL.with_label_values(&["dont_have"]).inc();
+                        }
+                        BitswapResponse::Error => {
+                            RESPONSES_TOTAL.with_label_values(&["error"]).inc();
+                        }
+                        _ => RESPONSES_TOTAL.with_label_values(&["have"]).inc(),
+                    }
+                    tracing::trace!("{:?}", response);
+                    DbResponse::Bitswap(channel, response)
+                })
+                .collect()
+        }
+        Batch::Block(requests) => {
+            let cids: Vec<Cid> = requests.iter().map(|(_, cid)| *cid).collect();
+            let blocks = match store.get_many(&cids).await {
+                Ok(blocks) if blocks.len() == cids.len() => Ok(blocks),
+                Ok(_) => {
+                    tracing::error!("get_many returned the wrong number of results");
+                    Err(())
+                }
+                Err(err) => {
+                    tracing::error!("error reading blocks: {}", err);
+                    Err(())
+                }
+            };
+            let blocks = blocks.map_or_else(
+                |_| vec![Err(()); cids.len()],
+                |blocks| blocks.into_iter().map(Ok).collect(),
+            );
+            requests
+                .into_iter()
+                .zip(blocks)
+                .map(|((channel, _), block)| {
+                    let response = match block {
+                        Ok(Some(data)) => {
+                            RESPONSES_TOTAL.with_label_values(&["block"]).inc();
+                            SENT_BLOCK_BYTES.inc_by(data.len() as u64);
+                            tracing::trace!("block {}", data.len());
+                            BitswapResponse::Block(data.into())
+                        }
+                        Ok(None) => {
+                            RESPONSES_TOTAL.with_label_values(&["dont_have"]).inc();
+                            tracing::trace!("have false");
+                            BitswapResponse::Have(false)
+                        }
+                        Err(()) => {
+                            RESPONSES_TOTAL.with_label_values(&["error"]).inc();
+                            BitswapResponse::Error
+                        }
+                    };
+                    DbResponse::Bitswap(channel, response)
+                })
+                .collect()
+        }
+        Batch::Dag(id, request) => {
+            let responses = read_dag(store, &request).await;
+            vec![DbResponse::Dag(id, responses)]
+        }
+        Batch::Insert(inserters, blocks) => {
+            if let Err(err) = store.insert_batch(&blocks).await {
+                tracing::error!("error inserting blocks {}", err);
+            } else {
+                return inserters
+                    .into_iter()
+                    .filter_map(|inserter| inserter.response(Ok(())))
+                    .collect();
+            }
+            // retry one by one to find out which inserts failed
+            let mut responses = Vec::with_capacity(inserters.len());
+            for (inserter, block) in inserters.into_iter().zip(blocks) {
+                let res = store.insert(&block).await;
+                if let Err(err) = &res {
+                    tracing::error!("error inserting block {}: {}", block.cid(), err);
+                }
+                responses.extend(inserter.response(res));
+            }
+            responses
+        }
+        Batch::MissingBlocks(id, cid) => {
+            let res = store.missing_blocks(&cid).await;
+            vec![DbResponse::MissingBlocks(id, res)]
+        }
+        Batch::Contains(id, cid) => {
+            let res = store.contains(&cid).await;
+            vec![DbResponse::Contains(id, res)]
+        }
+        Batch::Get(id, cid) => {
+            let res = store.get(&cid).await;
+            vec![DbResponse::Get(id, cid, res)]
+        }
+        Batch::Filter(id, cid, depth, references) => {
+            let res = read_filter(store, cid, depth, references).await;
+            vec![DbResponse::Filter(id, res)]
+        }
+    }
+}
+
+/// Handle to the db thread.
+pub(crate) struct DbWorker {
+    /// Resolves to the store once all queued requests have been processed. Handing
+    /// back the store is the last thing the db thread does, so the thread is done once
+    /// it resolves.
+    pub store: oneshot::Receiver<Box<dyn Any + Send>>,
+    /// The db thread.
+    pub thread: std::thread::JoinHandle<()>,
+}
+
+/// Starts the db thread. When the request channel is closed all queued requests are
+/// processed before the store is converted with `into_store` and handed back.
+pub(crate) fn start_db_thread<S: AsyncBitswapStore>(
+    store: S,
+    capacity: usize,
+    into_store: fn(S) -> Box<dyn Any + Send>,
+) -> (
+    mpsc::Sender<DbRequest<S::Params>>,
+    mpsc::Receiver<DbResponse>,
+    DbWorker,
+) {
+    let (tx, requests) = mpsc::channel(capacity);
+    let (responses, rx) = mpsc::channel(capacity);
+    let (store_tx, store_rx) = oneshot::channel();
+    let thread = std::thread::spawn(move || {
+        futures::executor::block_on(run(&store, requests, responses));
+        store_tx.send(into_store(store)).ok();
+    });
+    let worker = DbWorker {
+        store: store_rx,
+        thread,
+    };
+    (tx, rx, worker)
+}
+
+async fn run<S: AsyncBitswapStore>(
+    store: &S,
+    mut requests: mpsc::Receiver<DbRequest<S::Params>>,
+    mut responses: mpsc::Sender<DbResponse>,
+) {
+    let mut concurrent = FuturesUnordered::new();
+    let mut ordered = FuturesUnordered::new();
+    let mut queue = VecDeque::new();
+    let mut closed = false;
+    loop {
+        if ordered.is_empty() {
+            if let Some(batch) = queue.pop_front() {
+                ordered.push(handle_batch(store, batch));
+            }
+        }
+        if closed && concurrent.is_empty() && ordered.is_empty() {
+            break;
+        }
+        let batch = futures::select! {
+            request = requests.next() => {
+                let request = if let Some(request) = request {
+                    DB_QUEUE_DEPTH.dec();
+                    request
+                } else {
+                    closed = true;
+                    continue;
+                };
+                // coalesce everything that is already queued
+                let mut batch = vec![request];
+                while batch.len() < MAX_BATCH_SIZE {
+                    match requests.try_next() {
+                        Ok(Some(request)) => {
+                            DB_QUEUE_DEPTH.dec();
+                            batch.push(request);
+                        }
+                        Ok(None) => {
+                            closed = true;
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                for batch in Batch::coalesce(batch, &mut queue) {
+                    concurrent.push(handle_batch(store, batch));
+                }
+                continue;
+            }
+            batch = concurrent.select_next_some() => batch,
+            batch = ordered.select_next_some() => batch,
+        };
+        for response in batch {
+            // fails after shutdown, when nobody is interested in responses anymore
+            responses.send(response).await.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::cbor::DagCborCodec;
+    use libipld::ipld;
+    use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
+
+    fn create_block(n: u64) -> Block<DefaultParams> {
+        Block::encode(DagCborCodec, Code::Blake3_256, &ipld!({ "n": n })).unwrap()
+    }
+
+    #[test]
+    fn test_coalesce_inserts() {
+        let mut queue = VecDeque::new();
+        let peer = PeerId::random();
+        let requests = vec![
+            DbRequest::Insert(QueryId(1), peer, create_block(0)),
+            DbRequest::Insert(QueryId(2), peer, create_block(1)),
+            DbRequest::MissingBlocks(QueryId(0), *create_block(1).cid()),
+            DbRequest::Insert(QueryId(3), peer, create_block(2)),
+        ];
+        assert!(Batch::coalesce(requests, &mut queue).is_empty());
+        assert_eq!(queue.len(), 3);
+        assert!(matches!(&queue[0], Batch::Insert(_, blocks) if blocks.len() == 2));
+        assert!(matches!(&queue[1], Batch::MissingBlocks(QueryId(0), _)));
+        assert!(matches!(&queue[2], Batch::Insert(_, blocks) if blocks.len() == 1));
+
+        let requests = vec![DbRequest::Insert(QueryId(4), peer, create_block(3))];
+        Batch::coalesce(requests, &mut queue);
+        assert_eq!(queue.len(), 3);
+        assert!(matches!(
+            &queue[2],
+            Batch::Insert(ids, _) if matches!(ids[1], Inserter::Query(QueryId(4), _))
+        ));
+    }
+}
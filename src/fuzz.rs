@@ -0,0 +1,53 @@
+//! Entry points of the fuzz targets in `fuzz/`. They decode arbitrary bytes, which has
+//! to fail with an error instead of a panic.
+use crate::protocol::{
+    BitswapCodec, BitswapProtocol, BitswapRequest, BitswapResponse, ProtocolId,
+    DEFAULT_PROTOCOL_PREFIX,
+};
+use bytes::Bytes;
+use futures::io::Cursor;
+use libipld::store::DefaultParams;
+use libp2p::request_response::RequestResponseCodec;
+
+const PROTOCOLS: &[BitswapProtocol] = &[
+    BitswapProtocol::Single,
+    BitswapProtocol::Batch,
+    BitswapProtocol::Capabilities,
+    BitswapProtocol::Pipeline,
+];
+
+/// Decodes a request entry and a response entry of the native protocol.
+pub fn native_entry(data: &[u8]) {
+    let bytes = Bytes::copy_from_slice(data);
+    let _ = BitswapRequest::from_bytes(bytes.clone());
+    #[cfg(feature = "compression")]
+    let _ = crate::compression::decompress::<DefaultParams>(bytes.clone());
+    let _ = BitswapResponse::from_bytes(bytes);
+}
+
+/// Reads a request message and a response message of the native protocol. The first
+/// byte selects the protocol version.
+pub fn native_message(data: &[u8]) {
+    let (version, data) = match data.split_first() {
+        Some((version, data)) => (PROTOCOLS[*version as usize % PROTOCOLS.len()], data),
+        None => return,
+    };
+    let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, version);
+    let mut codec = BitswapCodec::<DefaultParams>::default();
+    futures::executor::block_on(async {
+        let _ = codec.read_request(&protocol, &mut Cursor::new(data)).await;
+        let _ = codec.read_response(&protocol, &mut Cursor::new(data)).await;
+    });
+}
+
+/// Decodes a message of the compat protocol.
+#[cfg(feature = "compat")]
+pub fn compat_message(data: &[u8]) {
+    let _ = crate::compat::CompatMessage::from_bytes(Bytes::copy_from_slice(data));
+}
+
+/// Decodes the cid prefix of a compat block.
+#[cfg(feature = "compat")]
+pub fn prefix(data: &[u8]) {
+    let _ = crate::compat::prefix::Prefix::new(data);
+}
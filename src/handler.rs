@@ -0,0 +1,279 @@
+//! Wraps the connection handlers of the bitswap protocols.
+//!
+//! Failing to decode a message closes the connection inside the wrapped handler
+//! without telling the behaviour which peer sent it. The wrapper reports these
+//! errors, so that the behaviour can keep track of misbehaving peers.
+//!
+//! The wrapper also applies the request timeout and keep alive of the bitswap
+//! config, so that changing the config affects new connections. While a query
+//! needs a peer its connections are kept alive regardless of the idle timeout.
+//!
+//! A protocol that is disabled in the config stops being offered on inbound
+//! substreams, so that remotes see it as unsupported.
+use crate::protocol::MessageTooLarge;
+use crate::reputation::Misbehaviour;
+use futures::future::{self, Either, Ready};
+use libp2p::core::upgrade::{InboundUpgrade, UpgradeError, UpgradeInfo};
+use libp2p::swarm::handler::{
+    ConnectionEvent, ConnectionHandlerUpgrErr, DialUpgradeError, FullyNegotiatedInbound,
+    InboundUpgradeSend, ListenUpgradeError, OutboundUpgradeSend,
+};
+use libp2p::swarm::{
+    ConnectionHandler, ConnectionHandlerEvent, KeepAlive, NegotiatedSubstream, SubstreamProtocol,
+};
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::io;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Event emitted by the [`Handler`].
+#[derive(Debug)]
+pub enum HandlerEvent<E> {
+    /// Event of the wrapped handler.
+    Inner(E),
+    /// The remote sent a message that violates the protocol.
+    Misbehaviour(Misbehaviour),
+    /// The first substream of the wrapped handler's protocol was negotiated, so the
+    /// remote supports it.
+    Negotiated,
+}
+
+/// Event sent to the [`Handler`].
+#[derive(Debug)]
+pub enum HandlerIn<E> {
+    /// Event for the wrapped handler.
+    Inner(E),
+    /// Keeps the connection alive while true.
+    KeepAlive(bool),
+    /// Accepts inbound substreams of the wrapped handler's protocol while true.
+    Listen(bool),
+}
+
+/// Connection handler that reports protocol violations of the remote.
+pub struct Handler<H> {
+    inner: H,
+    events: VecDeque<Misbehaviour>,
+    request_timeout: Duration,
+    keep_alive: Duration,
+    idle_since: Option<Instant>,
+    pinned: bool,
+    listen: bool,
+    negotiated: Option<bool>,
+}
+
+impl<H> Handler<H> {
+    /// Wraps a connection handler. Inbound substreams are only accepted if `listen`
+    /// is true.
+    pub fn new(inner: H, request_timeout: Duration, keep_alive: Duration, listen: bool) -> Self {
+        Self {
+            inner,
+            events: Default::default(),
+            request_timeout,
+            keep_alive,
+            idle_since: None,
+            pinned: false,
+            listen,
+            negotiated: None,
+        }
+    }
+
+    /// Returns the wrapped connection handler.
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    fn inspect<E>(&mut self, error: &ConnectionHandlerUpgrErr<E>)
+    where
+        E: Borrow<io::Error>,
+    {
+        if let ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(err)) = error {
+            if let Some(misbehaviour) = classify(err.borrow()) {
+                self.events.push_back(misbehaviour);
+            }
+        }
+    }
+}
+
+/// Inbound upgrade that offers no protocols when it is disabled.
+pub struct OptionalInbound<U>(Option<U>);
+
+impl<U: InboundUpgradeSend> UpgradeInfo for OptionalInbound<U> {
+    type Info = U::Info;
+    type InfoIter = Vec<U::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        self.0
+            .iter()
+            .flat_map(|upgrade| upgrade.protocol_info())
+            .collect()
+    }
+}
+
+impl<U> InboundUpgrade<NegotiatedSubstream> for OptionalInbound<U>
+where
+    U: InboundUpgradeSend,
+    U::Error: From<io::Error>,
+{
+    type Output = U::Output;
+    type Error = U::Error;
+    type Future = Either<U::Future, Ready<Result<U::Output, U::Error>>>;
+
+    fn upgrade_inbound(self, socket: NegotiatedSubstream, info: Self::Info) -> Self::Future {
+        match self.0 {
+            Some(upgrade) => Either::Left(upgrade.upgrade_inbound(socket, info)),
+            // a disabled upgrade offers no protocol that could have been negotiated
+            None => {
+                let err = io::Error::new(io::ErrorKind::Other, "protocol wasn't offered");
+                Either::Right(future::err(err.into()))
+            }
+        }
+    }
+}
+
+/// Decides if an error reading a message was caused by the remote violating the protocol.
+pub(crate) fn classify(err: &io::Error) -> Option<Misbehaviour> {
+    if err.kind() != io::ErrorKind::InvalidData {
+        return None;
+    }
+    let too_large = err
+        .get_ref()
+        .map(|err| err.is::<MessageTooLarge>())
+        .unwrap_or_default();
+    if too_large {
+        Some(Misbehaviour::OversizeMessage)
+    } else {
+        Some(Misbehaviour::DecodeError)
+    }
+}
+
+impl<H> ConnectionHandler for Handler<H>
+where
+    H: ConnectionHandler,
+    <H::InboundProtocol as InboundUpgradeSend>::Error: Borrow<io::Error> + From<io::Error>,
+    <H::OutboundProtocol as OutboundUpgradeSend>::Error: Borrow<io::Error>,
+{
+    type InEvent = HandlerIn<H::InEvent>;
+    type OutEvent = HandlerEvent<H::OutEvent>;
+    type Error = H::Error;
+    type InboundProtocol = OptionalInbound<H::InboundProtocol>;
+    type OutboundProtocol = H::OutboundProtocol;
+    type InboundOpenInfo = H::InboundOpenInfo;
+    type OutboundOpenInfo = H::OutboundOpenInfo;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        let listen = self.listen;
+        self.inner
+            .listen_protocol()
+            .map_upgrade(|upgrade| OptionalInbound(Some(upgrade).filter(|_| listen)))
+            .with_timeout(self.request_timeout)
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if self.pinned {
+            return KeepAlive::Yes;
+        }
+        match self.inner.connection_keep_alive() {
+            KeepAlive::Yes => KeepAlive::Yes,
+            _ => KeepAlive::Until(self.idle_since.unwrap_or_else(Instant::now) + self.keep_alive),
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::OutEvent,
+            Self::Error,
+        >,
+    > {
+        if self.negotiated == Some(false) {
+            self.negotiated = Some(true);
+            return Poll::Ready(ConnectionHandlerEvent::Custom(HandlerEvent::Negotiated));
+        }
+        if let Some(misbehaviour) = self.events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::Custom(HandlerEvent::Misbehaviour(
+                misbehaviour,
+            )));
+        }
+        let poll = self.inner.poll(cx);
+        if let KeepAlive::Yes = self.inner.connection_keep_alive() {
+            self.idle_since = None;
+        } else if self.idle_since.is_none() {
+            self.idle_since = Some(Instant::now());
+        }
+        poll.map(|ev| match ev {
+            ConnectionHandlerEvent::OutboundSubstreamRequest { protocol } => {
+                ConnectionHandlerEvent::OutboundSubstreamRequest {
+                    protocol: protocol.with_timeout(self.request_timeout),
+                }
+            }
+            ev => ev.map_custom(HandlerEvent::Inner),
+        })
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::InEvent) {
+        match event {
+            HandlerIn::Inner(event) => self.inner.on_behaviour_event(event),
+            HandlerIn::KeepAlive(pinned) => self.pinned = pinned,
+            HandlerIn::Listen(listen) => self.listen = listen,
+        }
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        match &event {
+            ConnectionEvent::ListenUpgradeError(ListenUpgradeError { error, .. }) => {
+                self.inspect(error)
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { error, .. }) => {
+                self.inspect(error)
+            }
+            ConnectionEvent::FullyNegotiatedInbound(_)
+            | ConnectionEvent::FullyNegotiatedOutbound(_) => {
+                // reported once by `poll`
+                self.negotiated.get_or_insert(false);
+            }
+            _ => {}
+        }
+        let event = match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound { protocol, info }) => {
+                ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound { protocol, info })
+            }
+            ConnectionEvent::ListenUpgradeError(ListenUpgradeError { info, error }) => {
+                ConnectionEvent::ListenUpgradeError(ListenUpgradeError { info, error })
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(ev) => {
+                ConnectionEvent::FullyNegotiatedOutbound(ev)
+            }
+            ConnectionEvent::DialUpgradeError(ev) => ConnectionEvent::DialUpgradeError(ev),
+            ConnectionEvent::AddressChange(ev) => ConnectionEvent::AddressChange(ev),
+        };
+        self.inner.on_connection_event(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let err = io::Error::new(io::ErrorKind::InvalidData, MessageTooLarge(10));
+        assert_eq!(classify(&err), Some(Misbehaviour::OversizeMessage));
+        let err = io::Error::new(io::ErrorKind::InvalidData, "bad cid");
+        assert_eq!(classify(&err), Some(Misbehaviour::DecodeError));
+        let err = io::Error::from(io::ErrorKind::UnexpectedEof);
+        assert_eq!(classify(&err), None);
+    }
+}
@@ -3,12 +3,34 @@
 #![deny(warnings)]
 #![allow(clippy::derive_partial_eq_without_eq)]
 
+mod access;
 mod behaviour;
 #[cfg(feature = "compat")]
 mod compat;
+#[cfg(feature = "compression")]
+mod compression;
+mod dag;
+mod db;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+mod handler;
+mod pipeline;
 mod protocol;
+mod push;
 mod query;
+mod reconcile;
+mod reputation;
 mod stats;
+mod validator;
 
-pub use crate::behaviour::{Bitswap, BitswapConfig, BitswapEvent, BitswapStore, Channel};
+pub use crate::access::AccessPolicy;
+pub use crate::behaviour::{
+    AsyncBitswapStore, Bitswap, BitswapConfig, BitswapEvent, BitswapStore, Channel, ConfigError,
+    PeerInfo, ProtocolDisabled, ProtocolNames, ShutDown, SupportedProtocol, WrongStoreType,
+};
+pub use crate::db::BlockingStore;
+pub use crate::protocol::Capabilities;
+pub use crate::push::{PushPolicy, PushRejected, PushUnsupported};
 pub use crate::query::QueryId;
+pub use crate::validator::{BlockRules, BlockValidator, ValidationError};
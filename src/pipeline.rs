@@ -0,0 +1,976 @@
+//! Pipelined requests over a long lived substream.
+//!
+//! Request-response opens and negotiates a new substream for every message, which
+//! dominates the transfer time of small blocks on high latency links. The pipelined
+//! protocol opens one substream per connection and direction and keeps it open. The
+//! peer that opened it sends requests, the other peer answers on the same substream.
+//!
+//! Every message is prefixed with a varint id that the response repeats, so that any
+//! number of requests can be in flight and responses may arrive in any order. The
+//! message itself is encoded like a message of the `Capabilities` version, so it starts
+//! with the capabilities of its sender and may use every optional feature, pushes
+//! included.
+use crate::handler::classify;
+use crate::protocol::{
+    BitswapCodec, BitswapProtocol, BitswapRequest, BitswapRequests, BitswapResponses, Capabilities,
+    ProtocolId, RequestType, MAX_BATCH_ENTRIES,
+};
+use crate::reputation::Misbehaviour;
+use async_trait::async_trait;
+use fnv::FnvHashMap;
+use futures::future::{self, BoxFuture};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::stream::{self, BoxStream};
+use futures::{FutureExt, StreamExt};
+use futures_timer::Delay;
+use libipld::store::StoreParams;
+use libp2p::core::upgrade::{
+    InboundUpgrade, NegotiationError, OutboundUpgrade, UpgradeError, UpgradeInfo,
+};
+use libp2p::request_response::RequestResponseCodec;
+use libp2p::swarm::handler::{
+    ConnectionEvent, ConnectionHandlerUpgrErr, DialUpgradeError, FullyNegotiatedInbound,
+    FullyNegotiatedOutbound,
+};
+use libp2p::swarm::{
+    ConnectionHandler, ConnectionHandlerEvent, KeepAlive, NegotiatedSubstream, SubstreamProtocol,
+};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::io;
+use std::marker::PhantomData;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use unsigned_varint::{aio, io::ReadError};
+
+/// Maximum number of inbound requests whose responses weren't written yet. The
+/// inbound substream isn't read while the behaviour or the remote is this far behind.
+const MAX_INBOUND_PENDING: usize = 64;
+
+/// Maximum number of block bytes the responses to inbound requests may hold before
+/// they are written. Every request that wasn't answered yet counts with the largest
+/// response it can get, so the responses buffered for a connection stay below this
+/// plus one message.
+const MAX_INBOUND_BYTES: usize = 8 * 1024 * 1024;
+
+/// Upgrade negotiating a pipelined substream.
+#[derive(Clone, Debug)]
+pub struct PipelineProtocol {
+    protocol: ProtocolId,
+}
+
+impl PipelineProtocol {
+    /// Offers the pipelined version of the protocol with the given prefix.
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            protocol: ProtocolId::new(prefix, BitswapProtocol::Pipeline),
+        }
+    }
+}
+
+impl UpgradeInfo for PipelineProtocol {
+    type Info = ProtocolId;
+    type InfoIter = std::iter::Once<ProtocolId>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        std::iter::once(self.protocol.clone())
+    }
+}
+
+impl InboundUpgrade<NegotiatedSubstream> for PipelineProtocol {
+    type Output = (NegotiatedSubstream, ProtocolId);
+    type Error = io::Error;
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_inbound(self, socket: NegotiatedSubstream, info: Self::Info) -> Self::Future {
+        future::ok((socket, info))
+    }
+}
+
+impl OutboundUpgrade<NegotiatedSubstream> for PipelineProtocol {
+    type Output = (NegotiatedSubstream, ProtocolId);
+    type Error = io::Error;
+    type Future = future::Ready<Result<Self::Output, Self::Error>>;
+
+    fn upgrade_outbound(self, socket: NegotiatedSubstream, info: Self::Info) -> Self::Future {
+        future::ok((socket, info))
+    }
+}
+
+/// Message sent on a pipelined substream. The peer that opened the substream writes
+/// requests and reads responses, the other peer reads requests and writes responses.
+#[async_trait]
+trait Message: Send + Sized + 'static {
+    async fn read<P, T>(
+        codec: &mut BitswapCodec<P>,
+        protocol: &ProtocolId,
+        io: &mut T,
+    ) -> io::Result<Self>
+    where
+        P: StoreParams,
+        T: AsyncRead + Send + Unpin;
+
+    async fn write<P: StoreParams>(
+        self,
+        codec: &mut BitswapCodec<P>,
+        protocol: &ProtocolId,
+        io: &mut Vec<u8>,
+    ) -> io::Result<()>;
+
+    /// Capabilities the sender announced.
+    fn capabilities(&self) -> Capabilities;
+
+    /// Number of block bytes the message carries.
+    fn payload_len(&self) -> usize;
+}
+
+#[async_trait]
+impl Message for BitswapRequests {
+    async fn read<P, T>(
+        codec: &mut BitswapCodec<P>,
+        protocol: &ProtocolId,
+        io: &mut T,
+    ) -> io::Result<Self>
+    where
+        P: StoreParams,
+        T: AsyncRead + Send + Unpin,
+    {
+        codec.read_request(protocol, io).await
+    }
+
+    async fn write<P: StoreParams>(
+        self,
+        codec: &mut BitswapCodec<P>,
+        protocol: &ProtocolId,
+        io: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        codec.write_request(protocol, io, self).await
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn payload_len(&self) -> usize {
+        self.requests
+            .iter()
+            .map(|request| request.payload().len())
+            .sum()
+    }
+}
+
+#[async_trait]
+impl Message for BitswapResponses {
+    async fn read<P, T>(
+        codec: &mut BitswapCodec<P>,
+        protocol: &ProtocolId,
+        io: &mut T,
+    ) -> io::Result<Self>
+    where
+        P: StoreParams,
+        T: AsyncRead + Send + Unpin,
+    {
+        codec.read_response(protocol, io).await
+    }
+
+    async fn write<P: StoreParams>(
+        self,
+        codec: &mut BitswapCodec<P>,
+        protocol: &ProtocolId,
+        io: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        codec.write_response(protocol, io, self).await
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn payload_len(&self) -> usize {
+        self.responses
+            .iter()
+            .map(|response| response.payload().len())
+            .sum()
+    }
+}
+
+/// Largest number of block bytes the response to the requests can carry.
+fn max_response_len<P: StoreParams>(requests: &BitswapRequests) -> usize {
+    if requests.requests.iter().any(BitswapRequest::is_dag) {
+        // the descendants fill the response message
+        return MAX_BATCH_ENTRIES * P::MAX_BLOCK_SIZE;
+    }
+    let blocks = requests
+        .requests
+        .iter()
+        .filter(|request| request.ty == RequestType::Block)
+        .count();
+    blocks * P::MAX_BLOCK_SIZE
+}
+
+/// Reads the id of the next message, or `None` if the substream was closed.
+async fn read_id<T: AsyncRead + Send + Unpin>(io: &mut T) -> io::Result<Option<u64>> {
+    match aio::read_u64(io).await {
+        Ok(id) => Ok(Some(id)),
+        Err(ReadError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(ReadError::Io(err)) => Err(err),
+        Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+    }
+}
+
+/// Reads the next message with its id.
+async fn read_frame<P, M, T>(
+    codec: &mut BitswapCodec<P>,
+    protocol: &ProtocolId,
+    io: &mut T,
+) -> io::Result<Option<(u64, M)>>
+where
+    P: StoreParams,
+    M: Message,
+    T: AsyncRead + Send + Unpin,
+{
+    let id = match read_id(io).await? {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    Ok(Some((id, M::read(codec, protocol, io).await?)))
+}
+
+/// Encodes a message with its id. Frames are encoded before they are written, so that
+/// a message that fails to encode doesn't corrupt the substream.
+async fn encode_frame<P: StoreParams, M: Message>(
+    codec: &mut BitswapCodec<P>,
+    protocol: &ProtocolId,
+    id: u64,
+    msg: M,
+) -> io::Result<Vec<u8>> {
+    let mut buf = unsigned_varint::encode::u64_buffer();
+    let mut frame = unsigned_varint::encode::u64(id, &mut buf).to_vec();
+    msg.write(codec, protocol, &mut frame).await?;
+    Ok(frame)
+}
+
+type BoxWrite = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Pipelined substream, split into a stream of received messages of type `R` and a
+/// queue of messages of type `W` to send.
+struct Substream<P, R, W> {
+    protocol: ProtocolId,
+    /// Capabilities the remote announced in the last message it sent.
+    remote: Capabilities,
+    reader: BoxStream<'static, io::Result<(u64, R)>>,
+    writer: Writer,
+    queue: VecDeque<(u64, W)>,
+    /// Block bytes of the queued messages and of the message being written.
+    unwritten_bytes: usize,
+    /// Block bytes of the message being written.
+    writing_bytes: usize,
+    _marker: PhantomData<P>,
+}
+
+/// Substream opened by the local peer.
+type OutboundSubstream<P> = Substream<P, BitswapResponses, BitswapRequests>;
+
+/// Substream opened by the remote.
+type InboundSubstream<P> = Substream<P, BitswapRequests, BitswapResponses>;
+
+enum Writer {
+    Idle(BoxWrite),
+    Writing(BoxFuture<'static, io::Result<BoxWrite>>),
+    Closed,
+}
+
+impl<P: StoreParams, R: Message, W: Message> Substream<P, R, W> {
+    fn new<S>(stream: S, protocol: ProtocolId) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (reader, writer) = stream.split();
+        let state = (reader, BitswapCodec::<P>::default(), protocol.clone());
+        let reader = stream::try_unfold(state, |(mut io, mut codec, protocol)| async move {
+            let frame = read_frame(&mut codec, &protocol, &mut io).await?;
+            Ok(frame.map(|frame| (frame, (io, codec, protocol))))
+        })
+        .boxed();
+        Self {
+            protocol,
+            remote: Capabilities::empty(),
+            reader,
+            writer: Writer::Idle(Box::new(writer)),
+            queue: Default::default(),
+            unwritten_bytes: 0,
+            writing_bytes: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    fn send(&mut self, id: u64, msg: W) {
+        self.unwritten_bytes += msg.payload_len();
+        self.queue.push_back((id, msg));
+    }
+
+    /// Returns the number of messages that weren't written yet.
+    fn unwritten(&self) -> usize {
+        let writing = matches!(self.writer, Writer::Writing(_)) as usize;
+        self.queue.len() + writing
+    }
+
+    /// Writes the queued messages.
+    fn poll_write(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match std::mem::replace(&mut self.writer, Writer::Closed) {
+                Writer::Idle(mut io) => {
+                    let (id, msg) = match self.queue.pop_front() {
+                        Some(next) => next,
+                        None => {
+                            self.writer = Writer::Idle(io);
+                            return Poll::Ready(Ok(()));
+                        }
+                    };
+                    self.writing_bytes = msg.payload_len();
+                    let mut codec = BitswapCodec::<P>::with_remote(self.remote);
+                    let protocol = self.protocol.clone();
+                    self.writer = Writer::Writing(
+                        async move {
+                            match encode_frame(&mut codec, &protocol, id, msg).await {
+                                Ok(frame) => {
+                                    io.write_all(&frame).await?;
+                                    io.flush().await?;
+                                }
+                                Err(err) => tracing::debug!("dropping pipelined message: {}", err),
+                            }
+                            Ok(io)
+                        }
+                        .boxed(),
+                    );
+                }
+                Writer::Writing(mut fut) => match fut.poll_unpin(cx) {
+                    Poll::Ready(Ok(io)) => {
+                        self.unwritten_bytes -= std::mem::take(&mut self.writing_bytes);
+                        self.writer = Writer::Idle(io);
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => {
+                        self.writer = Writer::Writing(fut);
+                        return Poll::Pending;
+                    }
+                },
+                Writer::Closed => {
+                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                }
+            }
+        }
+    }
+
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<(u64, R)>>> {
+        let frame = self.reader.poll_next_unpin(cx);
+        if let Poll::Ready(Some(Ok((_, msg)))) = &frame {
+            self.remote = msg.capabilities();
+        }
+        frame
+    }
+}
+
+/// Event sent to the [`PipelineHandler`].
+#[derive(Debug)]
+pub enum PipelineIn {
+    /// Sends requests to the remote. The id is chosen by the behaviour and has to be
+    /// unique on the connection.
+    Request(u64, BitswapRequests),
+    /// Answers the requests that were emitted with the id.
+    Response(u64, BitswapResponses),
+}
+
+/// Reason requests sent to the [`PipelineHandler`] failed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PipelineFailure {
+    /// The remote doesn't support pipelining.
+    Unsupported,
+    /// The remote didn't respond in time.
+    Timeout,
+    /// The substream was closed before the remote responded.
+    Closed,
+}
+
+/// Event emitted by the [`PipelineHandler`].
+#[derive(Debug)]
+pub enum PipelineEvent {
+    /// The remote sent requests. They are answered with the id, which is unique on the
+    /// connection.
+    Request(u64, BitswapRequests),
+    /// The remote answered the requests with the id.
+    Response(u64, BitswapResponses),
+    /// The requests with the ids won't be answered.
+    Failed(Vec<u64>, PipelineFailure),
+    /// A substream was negotiated with the version.
+    Negotiated(BitswapProtocol),
+    /// The remote sent a message that violates the protocol.
+    Misbehaviour(Misbehaviour),
+}
+
+/// Inbound request that wasn't answered yet.
+struct InboundRequest {
+    /// Id the remote sent the request with.
+    remote_id: u64,
+    /// The request is dropped after this, like the remote does.
+    deadline: Instant,
+    /// Inbound substream the request was read from.
+    substream: u64,
+    /// Largest number of block bytes the response can carry.
+    max_response_len: usize,
+}
+
+enum Outbound<P> {
+    Closed,
+    Opening,
+    Open(OutboundSubstream<P>),
+    Unsupported,
+}
+
+/// Connection handler of the pipelined protocol.
+pub struct PipelineHandler<P> {
+    protocol: PipelineProtocol,
+    request_timeout: Duration,
+    outbound: Outbound<P>,
+    /// Requests waiting for the outbound substream to open.
+    pending: VecDeque<(u64, BitswapRequests)>,
+    /// Deadlines of the requests that weren't answered yet.
+    deadlines: FnvHashMap<u64, Instant>,
+    timer: Option<(Instant, Delay)>,
+    inbound: Option<InboundSubstream<P>>,
+    /// Number of inbound substreams the remote opened.
+    inbound_substreams: u64,
+    /// Inbound requests by the id they were emitted with. They count against the
+    /// limits until they are answered or time out, even if their substream is gone.
+    inbound_ids: FnvHashMap<u64, InboundRequest>,
+    /// Sum of the largest responses the inbound requests can get.
+    inbound_reserved: usize,
+    next_inbound_id: u64,
+    events: VecDeque<PipelineEvent>,
+}
+
+impl<P: StoreParams> PipelineHandler<P> {
+    /// Creates a handler whose requests fail if they aren't answered within
+    /// `request_timeout`.
+    pub fn new(protocol: PipelineProtocol, request_timeout: Duration) -> Self {
+        Self {
+            protocol,
+            request_timeout,
+            outbound: Outbound::Closed,
+            pending: Default::default(),
+            deadlines: Default::default(),
+            timer: None,
+            inbound: None,
+            inbound_substreams: 0,
+            inbound_ids: Default::default(),
+            inbound_reserved: 0,
+            next_inbound_id: 0,
+            events: Default::default(),
+        }
+    }
+
+    /// Fails all requests that weren't answered yet.
+    fn fail_all(&mut self, failure: PipelineFailure) {
+        self.pending.clear();
+        let ids: Vec<u64> = self.deadlines.drain().map(|(id, _)| id).collect();
+        if !ids.is_empty() {
+            self.events.push_back(PipelineEvent::Failed(ids, failure));
+        }
+    }
+
+    /// Reports a read error of the remote.
+    fn read_error(&mut self, err: &io::Error) {
+        tracing::debug!("pipelined substream failed: {}", err);
+        if let Some(misbehaviour) = classify(err) {
+            self.events
+                .push_back(PipelineEvent::Misbehaviour(misbehaviour));
+        }
+    }
+
+    fn poll_timeouts(&mut self, cx: &mut Context<'_>) {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        if !expired.is_empty() {
+            for id in &expired {
+                self.deadlines.remove(id);
+            }
+            self.pending.retain(|(id, _)| !expired.contains(id));
+            self.events
+                .push_back(PipelineEvent::Failed(expired, PipelineFailure::Timeout));
+        }
+        let reserved = &mut self.inbound_reserved;
+        self.inbound_ids.retain(|_, request| {
+            let pending = request.deadline > now;
+            if !pending {
+                *reserved -= request.max_response_len;
+            }
+            pending
+        });
+        let inbound = self.inbound_ids.values().map(|request| &request.deadline);
+        let next = match self.deadlines.values().chain(inbound).min() {
+            Some(next) => *next,
+            None => {
+                self.timer = None;
+                return;
+            }
+        };
+        match &mut self.timer {
+            Some((deadline, timer)) if *deadline == next => {
+                // the deadline is in the future, so the timer wakes the task later
+                let _ = timer.poll_unpin(cx);
+            }
+            timer => {
+                let mut delay = Delay::new(next - now);
+                let _ = delay.poll_unpin(cx);
+                *timer = Some((next, delay));
+            }
+        }
+    }
+
+    fn poll_outbound(&mut self, cx: &mut Context<'_>) {
+        let substream = match &mut self.outbound {
+            Outbound::Open(substream) => substream,
+            _ => return,
+        };
+        for (id, requests) in self.pending.drain(..) {
+            substream.send(id, requests);
+        }
+        let mut error = None;
+        if let Poll::Ready(Err(err)) = substream.poll_write(cx) {
+            tracing::debug!("pipelined substream failed: {}", err);
+            error = Some(None);
+        }
+        while error.is_none() {
+            match substream.poll_read(cx) {
+                Poll::Ready(Some(Ok((id, responses)))) => {
+                    // late responses of requests that timed out are dropped
+                    if self.deadlines.remove(&id).is_some() {
+                        self.events
+                            .push_back(PipelineEvent::Response(id, responses));
+                    }
+                }
+                Poll::Ready(Some(Err(err))) => error = Some(Some(err)),
+                Poll::Ready(None) => error = Some(None),
+                Poll::Pending => break,
+            }
+        }
+        if let Some(error) = error {
+            if let Some(err) = error {
+                self.read_error(&err);
+            }
+            // reopened by the next request
+            self.outbound = Outbound::Closed;
+            self.fail_all(PipelineFailure::Closed);
+        }
+    }
+
+    fn poll_inbound(&mut self, cx: &mut Context<'_>) {
+        let substream = match &mut self.inbound {
+            Some(substream) => substream,
+            None => return,
+        };
+        let mut error = None;
+        if let Poll::Ready(Err(err)) = substream.poll_write(cx) {
+            tracing::debug!("pipelined substream failed: {}", err);
+            error = Some(None);
+        }
+        // responses that weren't written yet count, so that a remote that doesn't read
+        // can't make the queue grow
+        while error.is_none()
+            && self.inbound_ids.len() + substream.unwritten() < MAX_INBOUND_PENDING
+            && self.inbound_reserved + substream.unwritten_bytes < MAX_INBOUND_BYTES
+        {
+            match substream.poll_read(cx) {
+                Poll::Ready(Some(Ok((remote_id, requests)))) => {
+                    let id = self.next_inbound_id;
+                    self.next_inbound_id += 1;
+                    let request = InboundRequest {
+                        remote_id,
+                        deadline: Instant::now() + self.request_timeout,
+                        substream: self.inbound_substreams,
+                        max_response_len: max_response_len::<P>(&requests),
+                    };
+                    self.inbound_reserved += request.max_response_len;
+                    self.inbound_ids.insert(id, request);
+                    self.events.push_back(PipelineEvent::Request(id, requests));
+                }
+                Poll::Ready(Some(Err(err))) => error = Some(Some(err)),
+                Poll::Ready(None) => error = Some(None),
+                Poll::Pending => break,
+            }
+        }
+        if let Some(error) = error {
+            if let Some(err) = error {
+                self.read_error(&err);
+            }
+            self.inbound = None;
+        }
+    }
+
+    /// Reads requests from a substream the remote opened. The requests of the previous
+    /// one are answered by the behaviour, but the responses are dropped.
+    fn set_inbound(&mut self, substream: InboundSubstream<P>) {
+        self.inbound = Some(substream);
+        self.inbound_substreams += 1;
+    }
+}
+
+impl<P: StoreParams> ConnectionHandler for PipelineHandler<P> {
+    type InEvent = PipelineIn;
+    type OutEvent = PipelineEvent;
+    type Error = Infallible;
+    type InboundProtocol = PipelineProtocol;
+    type OutboundProtocol = PipelineProtocol;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        SubstreamProtocol::new(self.protocol.clone(), ())
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        if self.deadlines.is_empty() && self.inbound_ids.is_empty() {
+            KeepAlive::No
+        } else {
+            KeepAlive::Yes
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::OutEvent,
+            Self::Error,
+        >,
+    > {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ConnectionHandlerEvent::Custom(event));
+        }
+        self.poll_timeouts(cx);
+        if let Outbound::Closed = self.outbound {
+            if !self.pending.is_empty() {
+                self.outbound = Outbound::Opening;
+                return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest {
+                    protocol: SubstreamProtocol::new(self.protocol.clone(), ()),
+                });
+            }
+        }
+        self.poll_outbound(cx);
+        self.poll_inbound(cx);
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(ConnectionHandlerEvent::Custom(event)),
+            None => Poll::Pending,
+        }
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::InEvent) {
+        match event {
+            PipelineIn::Request(id, requests) => {
+                if let Outbound::Unsupported = self.outbound {
+                    self.events.push_back(PipelineEvent::Failed(
+                        vec![id],
+                        PipelineFailure::Unsupported,
+                    ));
+                    return;
+                }
+                self.deadlines
+                    .insert(id, Instant::now() + self.request_timeout);
+                self.pending.push_back((id, requests));
+            }
+            PipelineIn::Response(id, responses) => {
+                let request = if let Some(request) = self.inbound_ids.remove(&id) {
+                    request
+                } else {
+                    return;
+                };
+                self.inbound_reserved -= request.max_response_len;
+                if let Some(substream) = &mut self.inbound {
+                    if request.substream == self.inbound_substreams {
+                        substream.send(request.remote_id, responses);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound {
+                protocol: (stream, protocol),
+                ..
+            }) => {
+                // the remote opens a new substream after the last one failed
+                self.events
+                    .push_back(PipelineEvent::Negotiated(protocol.version));
+                self.set_inbound(Substream::new(stream, protocol));
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(FullyNegotiatedOutbound {
+                protocol: (stream, protocol),
+                ..
+            }) => {
+                self.events
+                    .push_back(PipelineEvent::Negotiated(protocol.version));
+                self.outbound = Outbound::Open(Substream::new(stream, protocol));
+            }
+            ConnectionEvent::DialUpgradeError(DialUpgradeError { error, .. }) => match error {
+                ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(
+                    NegotiationError::Failed,
+                )) => {
+                    self.outbound = Outbound::Unsupported;
+                    self.fail_all(PipelineFailure::Unsupported);
+                }
+                ConnectionHandlerUpgrErr::Timeout | ConnectionHandlerUpgrErr::Timer => {
+                    self.outbound = Outbound::Closed;
+                    self.fail_all(PipelineFailure::Timeout);
+                }
+                _ => {
+                    self.outbound = Outbound::Closed;
+                    self.fail_all(PipelineFailure::Closed);
+                }
+            },
+            ConnectionEvent::ListenUpgradeError(_) | ConnectionEvent::AddressChange(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::tests::create_cid;
+    use crate::protocol::{
+        BitswapRequest, BitswapResponse, Capabilities, RequestType, DEFAULT_PROTOCOL_PREFIX,
+    };
+    use futures::io::Cursor;
+    use futures::task::noop_waker_ref;
+    use libipld::store::DefaultParams;
+    use std::pin::Pin;
+
+    fn protocol() -> ProtocolId {
+        ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Pipeline)
+    }
+
+    fn requests(ty: RequestType, data: &[u8]) -> BitswapRequests {
+        BitswapRequests {
+            protocol: BitswapProtocol::Pipeline,
+            capabilities: Capabilities::local(),
+            requests: vec![BitswapRequest {
+                ty,
+                cid: create_cid(data),
+            }],
+        }
+    }
+
+    #[async_std::test]
+    async fn test_frames() {
+        let requests = requests(RequestType::Block, &b"pipelined"[..]);
+        let responses = BitswapResponses {
+            protocol: BitswapProtocol::Pipeline,
+            capabilities: Capabilities::local(),
+            responses: vec![
+                BitswapResponse::Block(b"pipelined"[..].into()),
+                BitswapResponse::Busy(Duration::from_millis(10)),
+            ],
+        };
+        let mut codec = BitswapCodec::<DefaultParams>::with_remote(Capabilities::local());
+        let mut buf = encode_frame(&mut codec, &protocol(), 300, requests.clone())
+            .await
+            .unwrap();
+        let frame = encode_frame(&mut codec, &protocol(), 7, responses.clone())
+            .await
+            .unwrap();
+        let mut io = Cursor::new(buf.clone());
+        let msg = read_frame(&mut codec, &protocol(), &mut io).await.unwrap();
+        assert_eq!(msg, Some((300, requests)));
+        let msg: Option<(u64, BitswapRequests)> =
+            read_frame(&mut codec, &protocol(), &mut io).await.unwrap();
+        assert!(msg.is_none());
+
+        // responses follow each other on the substream
+        buf = frame.clone();
+        buf.extend_from_slice(&frame);
+        let mut io = Cursor::new(buf);
+        for _ in 0..2 {
+            let msg = read_frame(&mut codec, &protocol(), &mut io).await.unwrap();
+            assert_eq!(msg, Some((7, responses.clone())));
+        }
+        let msg: Option<(u64, BitswapResponses)> =
+            read_frame(&mut codec, &protocol(), &mut io).await.unwrap();
+        assert!(msg.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_truncated_frame() {
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let mut frame = encode_frame(
+            &mut codec,
+            &protocol(),
+            1,
+            requests(RequestType::Have, &b"truncated"[..]),
+        )
+        .await
+        .unwrap();
+        frame.pop();
+        let mut io = Cursor::new(frame);
+        let msg: io::Result<Option<(u64, BitswapRequests)>> =
+            read_frame(&mut codec, &protocol(), &mut io).await;
+        assert!(msg.is_err());
+    }
+
+    /// Substream of a remote that sends requests but never reads the responses.
+    struct StalledRemote(Cursor<Vec<u8>>);
+
+    impl AsyncRead for StalledRemote {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for StalledRemote {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    /// Returns the ids of the requests the handler emits until it has nothing to do.
+    fn poll_requests(handler: &mut PipelineHandler<DefaultParams>) -> Vec<u64> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut ids = vec![];
+        while let Poll::Ready(event) = handler.poll(&mut cx) {
+            match event {
+                ConnectionHandlerEvent::Custom(PipelineEvent::Request(id, _)) => ids.push(id),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        ids
+    }
+
+    /// Returns a substream of a remote that sends `n` requests of the type but never
+    /// reads the responses.
+    async fn stalled_remote(ty: RequestType, n: usize) -> InboundSubstream<DefaultParams> {
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let mut buf = vec![];
+        for id in 0..n as u64 {
+            let requests = requests(ty.clone(), &id.to_be_bytes());
+            buf.extend(
+                encode_frame(&mut codec, &protocol(), id, requests)
+                    .await
+                    .unwrap(),
+            );
+        }
+        Substream::new(StalledRemote(Cursor::new(buf)), protocol())
+    }
+
+    fn handler() -> PipelineHandler<DefaultParams> {
+        PipelineHandler::new(
+            PipelineProtocol::new(DEFAULT_PROTOCOL_PREFIX),
+            Duration::from_secs(10),
+        )
+    }
+
+    fn respond(handler: &mut PipelineHandler<DefaultParams>, id: u64, response: BitswapResponse) {
+        let responses = BitswapResponses {
+            protocol: BitswapProtocol::Pipeline,
+            capabilities: Capabilities::local(),
+            responses: vec![response],
+        };
+        handler.on_behaviour_event(PipelineIn::Response(id, responses));
+    }
+
+    #[async_std::test]
+    async fn test_unwritten_responses_backpressure() {
+        let mut handler = handler();
+        let remote = stalled_remote(RequestType::Have, 2 * MAX_INBOUND_PENDING).await;
+        handler.set_inbound(remote);
+
+        let ids = poll_requests(&mut handler);
+        assert_eq!(ids.len(), MAX_INBOUND_PENDING);
+
+        // the responses are stuck in the queue, so no more requests are read
+        for id in ids {
+            respond(&mut handler, id, BitswapResponse::Have(false));
+        }
+        assert!(poll_requests(&mut handler).is_empty());
+        assert_eq!(
+            handler.inbound.as_ref().unwrap().unwritten(),
+            MAX_INBOUND_PENDING
+        );
+    }
+
+    #[async_std::test]
+    async fn test_unwritten_bytes_backpressure() {
+        let mut handler = handler();
+        let remote = stalled_remote(RequestType::Block, MAX_INBOUND_PENDING).await;
+        handler.set_inbound(remote);
+
+        // every block request counts with a block of the maximum size
+        let ids = poll_requests(&mut handler);
+        let max_blocks = MAX_INBOUND_BYTES / DefaultParams::MAX_BLOCK_SIZE;
+        assert_eq!(ids.len(), max_blocks);
+
+        // the blocks are stuck in the queue, so no more requests are read
+        for id in ids {
+            let block = vec![0; DefaultParams::MAX_BLOCK_SIZE];
+            respond(&mut handler, id, BitswapResponse::Block(block.into()));
+        }
+        assert!(poll_requests(&mut handler).is_empty());
+        assert_eq!(handler.inbound_reserved, 0);
+        assert_eq!(
+            handler.inbound.as_ref().unwrap().unwritten_bytes,
+            MAX_INBOUND_BYTES
+        );
+    }
+
+    #[async_std::test]
+    async fn test_new_substream_keeps_pending_requests() {
+        let mut handler = handler();
+        let remote = stalled_remote(RequestType::Have, MAX_INBOUND_PENDING).await;
+        handler.set_inbound(remote);
+        let ids = poll_requests(&mut handler);
+        assert_eq!(ids.len(), MAX_INBOUND_PENDING);
+
+        // the requests of the old substream still count until they are answered
+        let remote = stalled_remote(RequestType::Have, MAX_INBOUND_PENDING).await;
+        handler.set_inbound(remote);
+        assert!(poll_requests(&mut handler).is_empty());
+
+        // their responses can't be sent on the new substream
+        for id in ids {
+            respond(&mut handler, id, BitswapResponse::Have(false));
+        }
+        assert_eq!(handler.inbound.as_ref().unwrap().unwritten(), 0);
+        assert_eq!(poll_requests(&mut handler).len(), MAX_INBOUND_PENDING);
+    }
+}
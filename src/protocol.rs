@@ -1,4 +1,6 @@
+use crate::reconcile::{BloomFilter, MAX_FILTER_BYTES};
 use async_trait::async_trait;
+use bytes::Bytes;
 use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use libipld::cid::Cid;
 use libipld::store::StoreParams;
@@ -6,162 +8,741 @@ use libp2p::request_response::{ProtocolName, RequestResponseCodec};
 use std::convert::TryFrom;
 use std::io::{self, Write};
 use std::marker::PhantomData;
+use std::time::Duration;
 use thiserror::Error;
 use unsigned_varint::{aio, io::ReadError};
 
 // version codec hash size (u64 varint is max 10 bytes) + digest
 const MAX_CID_SIZE: usize = 4 * 10 + 64;
+// u32 varint is max 5 bytes
+const MAX_LEN_SIZE: usize = 5;
 
-#[derive(Clone, Debug)]
-pub struct BitswapProtocol;
+/// Maximum number of entries in a batched message.
+pub const MAX_BATCH_ENTRIES: usize = 16;
 
-impl ProtocolName for BitswapProtocol {
+/// Optional features of the native protocol. Peers that negotiate the
+/// `Capabilities` or `Pipeline` version announce the features they serve at the start
+/// of every message, the older versions serve none of them.
+///
+/// A message only uses the features both peers serve. Every implementation of these
+/// versions reads all request types defined by them, so requests don't need to wait
+/// for the capabilities of the peer.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Blocks in responses may be lz4 compressed.
+    pub const COMPRESSION: Self = Self(1 << 0);
+    /// Positive have responses may carry the block size.
+    pub const SIZE: Self = Self(1 << 1);
+    /// A block may be requested together with its descendants.
+    pub const DAG: Self = Self(1 << 2);
+    /// Requests may be answered with busy, unauthorized and error responses.
+    pub const STATUS: Self = Self(1 << 3);
+    /// Dag requests may carry a filter of the blocks the requester has.
+    pub const RECONCILE: Self = Self(1 << 4);
+    /// Requests may push a block to the peer.
+    pub const PUSH: Self = Self(1 << 5);
+
+    /// No optional features.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Capabilities with the given bits. Bits of unknown features are kept, but never
+    /// used.
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the bits of the capabilities.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns true if all features of `other` are included.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the features included in both.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Features served by this implementation.
+    pub const fn local() -> Self {
+        let all =
+            Self(Self::SIZE.0 | Self::DAG.0 | Self::STATUS.0 | Self::RECONCILE.0 | Self::PUSH.0);
+        if cfg!(feature = "compression") {
+            Self(all.0 | Self::COMPRESSION.0)
+        } else {
+            all
+        }
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BitswapProtocol {
+    /// Every message carries a single entry.
+    Single,
+    /// Messages carry up to `MAX_BATCH_ENTRIES` length prefixed entries.
+    Batch,
+    /// Like `Batch`, but every message starts with the capabilities of its sender.
+    Capabilities,
+    /// Like `Capabilities`, but messages carry an id and are sent over a long lived
+    /// substream.
+    Pipeline,
+}
+
+impl BitswapProtocol {
+    fn max_entries(self) -> usize {
+        match self {
+            Self::Single => 1,
+            _ => MAX_BATCH_ENTRIES,
+        }
+    }
+
+    /// Maximum size of a message containing entries of at most `max_entry` bytes.
+    fn max_message_size(self, max_entry: usize) -> usize {
+        match self {
+            Self::Single => max_entry,
+            _ => MAX_BATCH_ENTRIES * (max_entry + MAX_LEN_SIZE),
+        }
+    }
+
+    /// Returns true if messages start with the capabilities of their sender.
+    fn announces(self) -> bool {
+        matches!(self, Self::Capabilities | Self::Pipeline)
+    }
+
+    /// Request types a peer reads on this version.
+    fn requests(self) -> Capabilities {
+        if self.announces() {
+            Capabilities::DAG | Capabilities::RECONCILE | Capabilities::PUSH
+        } else {
+            Capabilities::empty()
+        }
+    }
+
+    /// Name of the version, which follows the protocol prefix.
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Single => "/bitswap/1.0.0",
+            Self::Batch => "/bitswap/1.1.0",
+            Self::Capabilities => "/bitswap/1.2.0",
+            Self::Pipeline => "/bitswap/2.0.0",
+        }
+    }
+}
+
+/// Maximum size of a request entry using the request types of `caps`.
+fn max_request_entry<P: StoreParams>(caps: Capabilities) -> usize {
+    if caps.contains(Capabilities::PUSH) {
+        P::MAX_BLOCK_SIZE + MAX_CID_SIZE + 1
+    } else if caps.contains(Capabilities::RECONCILE) {
+        // the hash count and depth varints are followed by the filter bits
+        MAX_CID_SIZE + 1 + 5 + 10 + MAX_FILTER_BYTES
+    } else if caps.contains(Capabilities::DAG) {
+        // the depth varint
+        MAX_CID_SIZE + 1 + 10
+    } else {
+        MAX_CID_SIZE + 1
+    }
+}
+
+/// Maximum size of a response entry using the response types of `caps`.
+fn max_response_entry<P: StoreParams>(caps: Capabilities) -> usize {
+    if caps.contains(Capabilities::DAG) {
+        // descendants are sent with their cid
+        P::MAX_BLOCK_SIZE + MAX_CID_SIZE + 1
+    } else {
+        P::MAX_BLOCK_SIZE + 1
+    }
+}
+
+/// Prefix of the native protocol names, unless configured otherwise.
+pub const DEFAULT_PROTOCOL_PREFIX: &str = "/ipfs-embed";
+
+/// Version of the native protocol together with its name. Peers only talk to each
+/// other if they use the same prefix.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProtocolId {
+    pub version: BitswapProtocol,
+    name: String,
+}
+
+impl ProtocolId {
+    /// Names a version of the protocol, for example `/ipfs-embed/bitswap/1.0.0` for
+    /// the prefix `/ipfs-embed` and version `Single`.
+    pub fn new(prefix: &str, version: BitswapProtocol) -> Self {
+        Self {
+            version,
+            name: format!("{}{}", prefix, version.suffix()),
+        }
+    }
+}
+
+impl ProtocolName for ProtocolId {
     fn protocol_name(&self) -> &[u8] {
-        b"/ipfs-embed/bitswap/1.0.0"
+        self.name.as_bytes()
     }
 }
 
+/// Requests sent in one message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BitswapRequests {
+    /// Protocol the message was received with. Messages are sent with the negotiated
+    /// protocol, so it is ignored when sending.
+    pub protocol: BitswapProtocol,
+    /// Capabilities the sender announced, empty for the older versions. Messages are
+    /// sent with the local capabilities, so it is ignored when sending.
+    pub capabilities: Capabilities,
+    pub requests: Vec<BitswapRequest>,
+}
+
+/// Responses to the requests of a message, in the same order.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BitswapResponses {
+    /// Protocol the message was received with. Messages are sent with the negotiated
+    /// protocol, so it is ignored when sending.
+    pub protocol: BitswapProtocol,
+    /// Capabilities the sender announced, empty for the older versions. Messages are
+    /// sent with the local capabilities, so it is ignored when sending.
+    pub capabilities: Capabilities,
+    pub responses: Vec<BitswapResponse>,
+}
+
+/// Codec of the native protocol.
+///
+/// Messages are read entry by entry and every entry is read into a buffer of its own
+/// size, so a codec doesn't hold on to a buffer of the maximum message size. Entries
+/// are written without copying their block data into a message buffer.
+///
+/// The same codec reads a request and writes the response to it, so the response only
+/// uses the capabilities the request announced.
 #[derive(Clone)]
 pub struct BitswapCodec<P> {
+    /// Capabilities of the peer whose request was read last.
+    remote: Capabilities,
     _marker: PhantomData<P>,
-    buffer: Vec<u8>,
 }
 
-impl<P: StoreParams> Default for BitswapCodec<P> {
-    fn default() -> Self {
-        let capacity = usize::max(P::MAX_BLOCK_SIZE, MAX_CID_SIZE) + 1;
-        debug_assert!(capacity <= u32::MAX as usize);
+impl<P: StoreParams> BitswapCodec<P> {
+    /// Creates a codec that writes responses for a peer with the given capabilities.
+    pub(crate) fn with_remote(remote: Capabilities) -> Self {
+        debug_assert!(
+            BitswapProtocol::Batch.max_message_size(max_request_entry::<P>(Capabilities::local()))
+                <= u32::MAX as usize
+        );
         Self {
+            remote,
             _marker: PhantomData,
-            buffer: Vec::with_capacity(capacity),
         }
     }
 }
 
+impl<P: StoreParams> Default for BitswapCodec<P> {
+    fn default() -> Self {
+        Self::with_remote(Capabilities::empty())
+    }
+}
+
+/// Reads the capabilities a message starts with. Older versions don't serve any.
+async fn read_capabilities<T>(protocol: BitswapProtocol, io: &mut T) -> io::Result<Capabilities>
+where
+    T: AsyncRead + Send + Unpin,
+{
+    if !protocol.announces() {
+        return Ok(Capabilities::empty());
+    }
+    let bits = aio::read_u64(&mut *io).await.map_err(|e| match e {
+        ReadError::Io(e) => e,
+        err => invalid_data(err),
+    })?;
+    Ok(Capabilities::from_bits(bits))
+}
+
+/// Writes the local capabilities if the protocol announces them.
+async fn write_capabilities<T>(protocol: BitswapProtocol, io: &mut T) -> io::Result<()>
+where
+    T: AsyncWrite + Send + Unpin,
+{
+    if protocol.announces() {
+        let mut buf = unsigned_varint::encode::u64_buffer();
+        let bits = Capabilities::local().bits();
+        io.write_all(unsigned_varint::encode::u64(bits, &mut buf))
+            .await?;
+    }
+    Ok(())
+}
+
 #[async_trait]
 impl<P: StoreParams> RequestResponseCodec for BitswapCodec<P> {
-    type Protocol = BitswapProtocol;
-    type Request = BitswapRequest;
-    type Response = BitswapResponse;
+    type Protocol = ProtocolId;
+    type Request = BitswapRequests;
+    type Response = BitswapResponses;
 
-    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
     where
         T: AsyncRead + Send + Unpin,
     {
-        let msg_len = u32_to_usize(aio::read_u32(&mut *io).await.map_err(|e| match e {
-            ReadError::Io(e) => e,
-            err => other(err),
-        })?);
-        if msg_len > MAX_CID_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(msg_len)));
+        let capabilities = read_capabilities(protocol.version, io).await?;
+        self.remote = capabilities;
+        let readable = protocol.version.requests();
+        let max_entry = max_request_entry::<P>(readable);
+        let requests =
+            read_entries(protocol.version, io, max_entry, BitswapRequest::from_bytes).await?;
+        if !readable.contains(Capabilities::PUSH) && requests.iter().any(BitswapRequest::is_push) {
+            return Err(invalid_data(UnknownMessageType(PUSH_REQUEST)));
+        }
+        if requests.iter().any(BitswapRequest::is_dag) {
+            if !readable.contains(Capabilities::DAG) {
+                return Err(invalid_data(UnknownMessageType(DAG_REQUEST)));
+            }
+            if !readable.contains(Capabilities::RECONCILE)
+                && requests.iter().any(BitswapRequest::is_reconcile)
+            {
+                return Err(invalid_data(UnknownMessageType(RECONCILE_REQUEST)));
+            }
+            // the descendants fill the response message
+            if requests.len() > 1 {
+                return Err(invalid_data(InvalidEntryCount(requests.len())));
+            }
         }
-        self.buffer.resize(msg_len, 0);
-        io.read_exact(&mut self.buffer).await?;
-        let request = BitswapRequest::from_bytes(&self.buffer).map_err(invalid_data)?;
-        Ok(request)
+        Ok(BitswapRequests {
+            protocol: protocol.version,
+            capabilities,
+            requests,
+        })
     }
 
     async fn read_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Send + Unpin,
     {
-        let msg_len = u32_to_usize(aio::read_u32(&mut *io).await.map_err(|e| match e {
-            ReadError::Io(e) => e,
-            err => other(err),
-        })?);
-        if msg_len > P::MAX_BLOCK_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(msg_len)));
+        let capabilities = read_capabilities(protocol.version, io).await?;
+        // the request announced the local capabilities
+        let usable = capabilities.intersection(Capabilities::local());
+        let max_entry = max_response_entry::<P>(usable);
+        #[cfg(feature = "compression")]
+        let read: fn(Bytes) -> io::Result<BitswapResponse> =
+            if usable.contains(Capabilities::COMPRESSION) {
+                crate::compression::decompress::<P>
+            } else {
+                BitswapResponse::from_bytes
+            };
+        #[cfg(not(feature = "compression"))]
+        let read = BitswapResponse::from_bytes;
+        let responses = read_entries(protocol.version, io, max_entry, read).await?;
+        for response in &responses {
+            if let BitswapResponse::HaveSize(size) = response {
+                if !usable.contains(Capabilities::SIZE) {
+                    return Err(invalid_data(UnknownMessageType(HAVE_SIZE)));
+                }
+                if *size > P::MAX_BLOCK_SIZE {
+                    return Err(invalid_data(InvalidBlockSize(*size)));
+                }
+            }
+        }
+        if !usable.contains(Capabilities::DAG)
+            && responses.iter().any(BitswapResponse::is_dag_block)
+        {
+            return Err(invalid_data(UnknownMessageType(DAG_BLOCK)));
         }
-        self.buffer.resize(msg_len, 0);
-        io.read_exact(&mut self.buffer).await?;
-        let response = BitswapResponse::from_bytes(&self.buffer).map_err(invalid_data)?;
-        Ok(response)
+        if !usable.contains(Capabilities::STATUS) {
+            if let Some(tag) = responses.iter().find_map(BitswapResponse::status_tag) {
+                return Err(invalid_data(UnknownMessageType(tag)));
+            }
+        }
+        Ok(BitswapResponses {
+            protocol: protocol.version,
+            capabilities,
+            responses,
+        })
     }
 
     async fn write_request<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
-        req: Self::Request,
+        mut req: Self::Request,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Send + Unpin,
     {
-        self.buffer.clear();
-        req.write_to(&mut self.buffer)?;
-        if self.buffer.len() > MAX_CID_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(self.buffer.len())));
-        }
-        let mut buf = unsigned_varint::encode::u32_buffer();
-        let msg_len = unsigned_varint::encode::u32(self.buffer.len() as u32, &mut buf);
-        io.write_all(msg_len).await?;
-        io.write_all(&self.buffer).await?;
-        Ok(())
+        let readable = protocol.version.requests();
+        let max_entry = max_request_entry::<P>(readable);
+        if !readable.contains(Capabilities::PUSH) && req.requests.iter().any(|r| r.is_push()) {
+            // pushes are only sent to peers that announced them, the block isn't dropped
+            return Err(invalid_input(UnknownMessageType(PUSH_REQUEST)));
+        }
+        if !readable.contains(Capabilities::RECONCILE) {
+            // the provider sends the descendants the requester already has too
+            for request in &mut req.requests {
+                if let RequestType::Reconcile(depth, _) = request.ty {
+                    request.ty = RequestType::Dag(depth);
+                }
+            }
+        }
+        if !readable.contains(Capabilities::DAG) {
+            // the response to a block request is the root of the dag response
+            for request in &mut req.requests {
+                if request.is_dag() {
+                    request.ty = RequestType::Block;
+                }
+            }
+        }
+        write_capabilities(protocol.version, io).await?;
+        write_entries(protocol.version, io, max_entry, &req.requests).await
     }
 
     async fn write_response<T>(
         &mut self,
-        _: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
-        res: Self::Response,
+        mut res: Self::Response,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Send + Unpin,
     {
-        self.buffer.clear();
-        res.write_to(&mut self.buffer)?;
-        if self.buffer.len() > P::MAX_BLOCK_SIZE + 1 {
-            return Err(invalid_data(MessageTooLarge(self.buffer.len())));
-        }
-        let mut buf = unsigned_varint::encode::u32_buffer();
-        let msg_len = unsigned_varint::encode::u32(self.buffer.len() as u32, &mut buf);
-        io.write_all(msg_len).await?;
-        io.write_all(&self.buffer).await?;
-        Ok(())
+        let usable = if protocol.version.announces() {
+            self.remote.intersection(Capabilities::local())
+        } else {
+            Capabilities::empty()
+        };
+        let max_entry = max_response_entry::<P>(usable);
+        if !usable.contains(Capabilities::DAG) {
+            res.responses.retain(|response| !response.is_dag_block());
+        }
+        if !usable.contains(Capabilities::STATUS) {
+            // older peers can't tell why a block isn't sent
+            for response in &mut res.responses {
+                if response.status_tag().is_some() {
+                    *response = BitswapResponse::Have(false);
+                }
+            }
+        }
+        if !usable.contains(Capabilities::SIZE) {
+            for response in &mut res.responses {
+                if let BitswapResponse::HaveSize(_) = response {
+                    *response = BitswapResponse::Have(true);
+                }
+            }
+        }
+        write_capabilities(protocol.version, io).await?;
+        #[cfg(feature = "compression")]
+        if usable.contains(Capabilities::COMPRESSION) {
+            let responses: Vec<_> = res
+                .responses
+                .iter()
+                .map(crate::compression::compress)
+                .collect();
+            return write_entries(protocol.version, io, max_entry, &responses).await;
+        }
+        write_entries(protocol.version, io, max_entry, &res.responses).await
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Entry of a message. It is encoded as a small header followed by the block data, so
+/// that the data can be written without copying it.
+pub(crate) trait Entry {
+    /// Writes everything but the block data.
+    fn write_header(&self, w: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Returns the block data.
+    fn payload(&self) -> &[u8];
+}
+
+impl Entry for BitswapRequest {
+    fn write_header(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        BitswapRequest::write_header(self, w)
+    }
+
+    fn payload(&self) -> &[u8] {
+        BitswapRequest::payload(self)
+    }
+}
+
+impl Entry for BitswapResponse {
+    fn write_header(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        BitswapResponse::write_header(self, w)
+    }
+
+    fn payload(&self) -> &[u8] {
+        BitswapResponse::payload(self)
+    }
+}
+
+async fn read_len<T>(io: &mut T) -> io::Result<usize>
+where
+    T: AsyncRead + Send + Unpin,
+{
+    let len = aio::read_u32(&mut *io).await.map_err(|e| match e {
+        ReadError::Io(e) => e,
+        err => other(err),
+    })?;
+    Ok(u32_to_usize(len))
+}
+
+async fn read_bytes<T>(io: &mut T, len: usize) -> io::Result<Bytes>
+where
+    T: AsyncRead + Send + Unpin,
+{
+    let mut buf = vec![0; len];
+    io.read_exact(&mut buf).await?;
+    Ok(buf.into())
+}
+
+fn len_size(len: usize) -> usize {
+    let mut buf = unsigned_varint::encode::usize_buffer();
+    unsigned_varint::encode::usize(len, &mut buf).len()
+}
+
+/// Reads the entries of a length prefixed message. A single entry message is the
+/// entry itself, a batch is a sequence of length prefixed entries.
+async fn read_entries<T, E>(
+    protocol: BitswapProtocol,
+    io: &mut T,
+    max_entry: usize,
+    read: fn(Bytes) -> io::Result<E>,
+) -> io::Result<Vec<E>>
+where
+    T: AsyncRead + Send + Unpin,
+{
+    let msg_len = read_len(io).await?;
+    if msg_len > protocol.max_message_size(max_entry) {
+        return Err(invalid_data(MessageTooLarge(msg_len)));
+    }
+    if protocol == BitswapProtocol::Single {
+        if msg_len == 0 {
+            return Err(invalid_data(InvalidEntryCount(0)));
+        }
+        return Ok(vec![read(read_bytes(io, msg_len).await?)?]);
+    }
+    let mut remaining = msg_len;
+    let mut entries = vec![];
+    while remaining > 0 {
+        if entries.len() == MAX_BATCH_ENTRIES {
+            return Err(invalid_data(InvalidEntryCount(entries.len() + 1)));
+        }
+        let len = read_len(io).await?;
+        remaining = remaining
+            .checked_sub(len_size(len))
+            .ok_or_else(|| invalid_data(InvalidEntryLength(len)))?;
+        if len == 0 || len > remaining || len > max_entry {
+            return Err(invalid_data(InvalidEntryLength(len)));
+        }
+        entries.push(read(read_bytes(io, len).await?)?);
+        remaining -= len;
+    }
+    if entries.is_empty() {
+        return Err(invalid_data(InvalidEntryCount(0)));
+    }
+    Ok(entries)
+}
+
+/// Writes the entries as a length prefixed message.
+async fn write_entries<T, E>(
+    protocol: BitswapProtocol,
+    io: &mut T,
+    max_entry: usize,
+    entries: &[E],
+) -> io::Result<()>
+where
+    T: AsyncWrite + Send + Unpin,
+    E: Entry,
+{
+    if entries.is_empty() || entries.len() > protocol.max_entries() {
+        return Err(invalid_input(InvalidEntryCount(entries.len())));
+    }
+    let mut headers = Vec::with_capacity(entries.len());
+    let mut msg_len = 0;
+    for entry in entries {
+        let mut header = Vec::with_capacity(MAX_CID_SIZE + 1);
+        entry.write_header(&mut header)?;
+        let len = header.len() + entry.payload().len();
+        if len > max_entry {
+            return Err(invalid_input(MessageTooLarge(len)));
+        }
+        if protocol != BitswapProtocol::Single {
+            msg_len += len_size(len);
+        }
+        msg_len += len;
+        headers.push(header);
+    }
+    let mut buf = unsigned_varint::encode::u32_buffer();
+    io.write_all(unsigned_varint::encode::u32(msg_len as u32, &mut buf))
+        .await?;
+    for (entry, header) in entries.iter().zip(headers) {
+        if protocol != BitswapProtocol::Single {
+            let len = header.len() + entry.payload().len();
+            io.write_all(unsigned_varint::encode::u32(len as u32, &mut buf))
+                .await?;
+        }
+        io.write_all(&header).await?;
+        io.write_all(entry.payload()).await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum RequestType {
     Have,
     Block,
+    /// Asks the peer to store the block with the given data.
+    Push(Bytes),
+    /// Asks for the block and the descendants the peer has, up to the given depth
+    /// below the block or as many as fit in the response message.
+    Dag(Option<u64>),
+    /// Like `Dag`, but leaves out the descendants in the filter and the blocks below
+    /// them.
+    Reconcile(Option<u64>, BloomFilter),
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Type byte of a push request.
+const PUSH_REQUEST: u8 = 2;
+
+/// Type byte of a dag request.
+const DAG_REQUEST: u8 = 3;
+
+/// Type byte of a reconcile request, which is followed by the cid, the number of
+/// hashes of the filter, zero or the depth limit plus one and the filter bits.
+const RECONCILE_REQUEST: u8 = 4;
+
+/// Type byte of a positive have response with the block size.
+const HAVE_SIZE: u8 = 4;
+
+/// Type byte of a descendant in a dag response.
+const DAG_BLOCK: u8 = 5;
+
+/// Type byte of a busy response.
+const BUSY: u8 = 6;
+
+/// Type byte of an unauthorized response.
+const UNAUTHORIZED: u8 = 7;
+
+/// Type byte of an error response.
+const ERROR: u8 = 8;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct BitswapRequest {
     pub ty: RequestType,
     pub cid: Cid,
 }
 
 impl BitswapRequest {
-    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
-        match self {
-            BitswapRequest {
-                ty: RequestType::Have,
-                cid,
-            } => {
-                w.write_all(&[0])?;
-                cid.write_bytes(&mut *w).map_err(other)?;
+    /// Writes the request without the pushed block data.
+    pub fn write_header<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let ty = match self.ty {
+            RequestType::Have => 0,
+            RequestType::Block => 1,
+            RequestType::Push(_) => PUSH_REQUEST,
+            RequestType::Dag(_) => DAG_REQUEST,
+            RequestType::Reconcile(_, _) => RECONCILE_REQUEST,
+        };
+        w.write_all(&[ty])?;
+        self.cid.write_bytes(&mut *w).map_err(other)?;
+        let mut buf = unsigned_varint::encode::u64_buffer();
+        match &self.ty {
+            RequestType::Dag(Some(depth)) => {
+                w.write_all(unsigned_varint::encode::u64(*depth, &mut buf))?;
             }
-            BitswapRequest {
-                ty: RequestType::Block,
-                cid,
-            } => {
-                w.write_all(&[1])?;
-                cid.write_bytes(&mut *w).map_err(other)?;
+            RequestType::Reconcile(depth, filter) => {
+                w.write_all(unsigned_varint::encode::u64(
+                    filter.hashes() as u64,
+                    &mut buf,
+                ))?;
+                let depth = depth.map(|depth| depth.saturating_add(1)).unwrap_or(0);
+                w.write_all(unsigned_varint::encode::u64(depth, &mut buf))?;
             }
+            _ => {}
         }
         Ok(())
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
-        let ty = match bytes[0] {
+    /// Returns true if the request pushes a block.
+    pub fn is_push(&self) -> bool {
+        matches!(self.ty, RequestType::Push(_))
+    }
+
+    /// Returns true if the request asks for the descendants of the block too.
+    pub fn is_dag(&self) -> bool {
+        matches!(self.ty, RequestType::Dag(_) | RequestType::Reconcile(_, _))
+    }
+
+    /// Returns true if the request carries a filter of the blocks the requester has.
+    pub fn is_reconcile(&self) -> bool {
+        matches!(self.ty, RequestType::Reconcile(_, _))
+    }
+
+    /// Returns the pushed block data or the filter bits.
+    pub fn payload(&self) -> &[u8] {
+        match &self.ty {
+            RequestType::Push(data) => data,
+            RequestType::Reconcile(_, filter) => filter.bits(),
+            _ => &[],
+        }
+    }
+
+    /// Decodes a request. The pushed block data shares the buffer.
+    pub fn from_bytes(bytes: Bytes) -> io::Result<Self> {
+        let tag = *bytes
+            .first()
+            .ok_or_else(|| invalid_data(InvalidEntryLength(0)))?;
+        let ty = match tag {
             0 => RequestType::Have,
             1 => RequestType::Block,
+            PUSH_REQUEST => {
+                let mut reader = &bytes[1..];
+                let cid = Cid::read_bytes(&mut reader).map_err(invalid_data)?;
+                let header_len = bytes.len() - reader.len();
+                let ty = RequestType::Push(bytes.slice(header_len..));
+                return Ok(Self { ty, cid });
+            }
+            DAG_REQUEST => {
+                let mut reader = &bytes[1..];
+                let cid = Cid::read_bytes(&mut reader).map_err(invalid_data)?;
+                let depth = if reader.is_empty() {
+                    None
+                } else {
+                    let (depth, rest) =
+                        unsigned_varint::decode::u64(reader).map_err(invalid_data)?;
+                    if !rest.is_empty() {
+                        return Err(invalid_data(InvalidEntryLength(bytes.len())));
+                    }
+                    Some(depth)
+                };
+                let ty = RequestType::Dag(depth);
+                return Ok(Self { ty, cid });
+            }
+            RECONCILE_REQUEST => {
+                let mut reader = &bytes[1..];
+                let cid = Cid::read_bytes(&mut reader).map_err(invalid_data)?;
+                let (hashes, rest) = unsigned_varint::decode::u32(reader).map_err(invalid_data)?;
+                let (depth, rest) = unsigned_varint::decode::u64(rest).map_err(invalid_data)?;
+                let depth = depth.checked_sub(1);
+                let filter =
+                    BloomFilter::from_parts(hashes, bytes.slice(bytes.len() - rest.len()..))?;
+                let ty = RequestType::Reconcile(depth, filter);
+                return Ok(Self { ty, cid });
+            }
             c => return Err(invalid_data(UnknownMessageType(c))),
         };
         let cid = Cid::try_from(&bytes[1..]).map_err(invalid_data)?;
@@ -172,41 +753,124 @@ impl BitswapRequest {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BitswapResponse {
     Have(bool),
-    Block(Vec<u8>),
+    /// Positive have response with the size of the block. Versions that don't carry
+    /// sizes send it as `Have(true)`.
+    HaveSize(usize),
+    Block(Bytes),
+    /// Descendant of the block asked for by a dag request, which follows the
+    /// response for the block itself.
+    DagBlock(Cid, Bytes),
+    /// The peer is too busy to answer, the request may be retried after the given
+    /// time. Versions without status responses send it as `Have(false)`.
+    Busy(Duration),
+    /// The peer doesn't serve the block to the requester. Versions without status
+    /// responses send it as `Have(false)`.
+    Unauthorized,
+    /// The peer failed to read the block from its store. Versions without status
+    /// responses send it as `Have(false)`.
+    Error,
 }
 
 impl BitswapResponse {
-    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+    /// Writes the response without the block data.
+    pub fn write_header<W: Write>(&self, w: &mut W) -> io::Result<()> {
         match self {
-            BitswapResponse::Have(have) => {
-                if *have {
-                    w.write_all(&[0])?;
-                } else {
-                    w.write_all(&[2])?;
-                }
+            BitswapResponse::Have(true) => w.write_all(&[0]),
+            BitswapResponse::Block(_) => w.write_all(&[1]),
+            BitswapResponse::Have(false) => w.write_all(&[2]),
+            BitswapResponse::HaveSize(size) => {
+                w.write_all(&[HAVE_SIZE])?;
+                let mut buf = unsigned_varint::encode::usize_buffer();
+                w.write_all(unsigned_varint::encode::usize(*size, &mut buf))
             }
-            BitswapResponse::Block(data) => {
-                w.write_all(&[1])?;
-                w.write_all(data)?;
+            BitswapResponse::DagBlock(cid, _) => {
+                w.write_all(&[DAG_BLOCK])?;
+                cid.write_bytes(&mut *w).map_err(other)?;
+                Ok(())
             }
-        };
-        Ok(())
+            BitswapResponse::Busy(retry_after) => {
+                w.write_all(&[BUSY])?;
+                let millis = u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX);
+                let mut buf = unsigned_varint::encode::u64_buffer();
+                w.write_all(unsigned_varint::encode::u64(millis, &mut buf))
+            }
+            BitswapResponse::Unauthorized => w.write_all(&[UNAUTHORIZED]),
+            BitswapResponse::Error => w.write_all(&[ERROR]),
+        }
+    }
+
+    /// Returns the block data.
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            BitswapResponse::Block(data) | BitswapResponse::DagBlock(_, data) => data,
+            _ => &[],
+        }
+    }
+
+    /// Returns true if the response is a descendant in a dag response.
+    pub fn is_dag_block(&self) -> bool {
+        matches!(self, BitswapResponse::DagBlock(_, _))
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
-        let res = match bytes[0] {
-            0 | 2 => BitswapResponse::Have(bytes[0] == 0),
-            1 => BitswapResponse::Block(bytes[1..].to_vec()),
+    /// Returns the type byte of a busy, unauthorized or error response.
+    pub fn status_tag(&self) -> Option<u8> {
+        match self {
+            BitswapResponse::Busy(_) => Some(BUSY),
+            BitswapResponse::Unauthorized => Some(UNAUTHORIZED),
+            BitswapResponse::Error => Some(ERROR),
+            _ => None,
+        }
+    }
+
+    /// Decodes a response. The block data shares the buffer.
+    pub fn from_bytes(bytes: Bytes) -> io::Result<Self> {
+        let tag = *bytes
+            .first()
+            .ok_or_else(|| invalid_data(InvalidEntryLength(0)))?;
+        let res = match tag {
+            0 | 2 => BitswapResponse::Have(tag == 0),
+            1 => BitswapResponse::Block(bytes.slice(1..)),
+            HAVE_SIZE => {
+                let (size, rest) =
+                    unsigned_varint::decode::usize(&bytes[1..]).map_err(invalid_data)?;
+                if !rest.is_empty() {
+                    return Err(invalid_data(InvalidEntryLength(bytes.len())));
+                }
+                BitswapResponse::HaveSize(size)
+            }
+            DAG_BLOCK => {
+                let mut reader = &bytes[1..];
+                let cid = Cid::read_bytes(&mut reader).map_err(invalid_data)?;
+                let header_len = bytes.len() - reader.len();
+                BitswapResponse::DagBlock(cid, bytes.slice(header_len..))
+            }
+            BUSY => {
+                let (millis, rest) =
+                    unsigned_varint::decode::u64(&bytes[1..]).map_err(invalid_data)?;
+                if !rest.is_empty() {
+                    return Err(invalid_data(InvalidEntryLength(bytes.len())));
+                }
+                BitswapResponse::Busy(Duration::from_millis(millis))
+            }
+            UNAUTHORIZED | ERROR if bytes.len() > 1 => {
+                return Err(invalid_data(InvalidEntryLength(bytes.len())));
+            }
+            UNAUTHORIZED => BitswapResponse::Unauthorized,
+            ERROR => BitswapResponse::Error,
             c => return Err(invalid_data(UnknownMessageType(c))),
         };
         Ok(res)
     }
 }
 
-fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+pub(crate) fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
     io::Error::new(io::ErrorKind::InvalidData, e)
 }
 
+fn invalid_input<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
 fn other<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
     io::Error::new(io::ErrorKind::Other, e)
 }
@@ -222,12 +886,26 @@ pub struct UnknownMessageType(u8);
 
 #[derive(Debug, Error)]
 #[error("message too large {0}")]
-pub struct MessageTooLarge(usize);
+pub struct MessageTooLarge(pub(crate) usize);
+
+#[derive(Debug, Error)]
+#[error("invalid number of message entries {0}")]
+pub struct InvalidEntryCount(usize);
+
+#[derive(Debug, Error)]
+#[error("invalid message entry length {0}")]
+pub struct InvalidEntryLength(usize);
+
+#[derive(Debug, Error)]
+#[error("block size {0} exceeds the maximum block size")]
+pub struct InvalidBlockSize(usize);
 
 #[cfg(test)]
 pub(crate) mod tests {
     use super::*;
+    use futures::io::Cursor;
     use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
     use multihash::MultihashDigest;
 
     pub fn create_cid(bytes: &[u8]) -> Cid {
@@ -235,6 +913,17 @@ pub(crate) mod tests {
         Cid::new_v1(0x55, digest)
     }
 
+    fn filter() -> BloomFilter {
+        BloomFilter::new(&[create_cid(&b"have"[..])], 1024)
+    }
+
+    fn encode<E: Entry>(entry: &E) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MAX_CID_SIZE + 1);
+        entry.write_header(&mut buf).unwrap();
+        buf.extend_from_slice(entry.payload());
+        buf
+    }
+
     #[test]
     fn test_request_encode_decode() {
         let requests = [
@@ -246,12 +935,30 @@ pub(crate) mod tests {
                 ty: RequestType::Block,
                 cid: create_cid(&b"block_request"[..]),
             },
+            BitswapRequest {
+                ty: RequestType::Push(Bytes::from_static(b"push_request")),
+                cid: create_cid(&b"push_request"[..]),
+            },
+            BitswapRequest {
+                ty: RequestType::Dag(None),
+                cid: create_cid(&b"dag_request"[..]),
+            },
+            BitswapRequest {
+                ty: RequestType::Dag(Some(3)),
+                cid: create_cid(&b"dag_request"[..]),
+            },
+            BitswapRequest {
+                ty: RequestType::Reconcile(None, filter()),
+                cid: create_cid(&b"reconcile_request"[..]),
+            },
+            BitswapRequest {
+                ty: RequestType::Reconcile(Some(0), filter()),
+                cid: create_cid(&b"reconcile_request"[..]),
+            },
         ];
-        let mut buf = Vec::with_capacity(MAX_CID_SIZE + 1);
         for request in &requests {
-            buf.clear();
-            request.write_to(&mut buf).unwrap();
-            assert_eq!(&BitswapRequest::from_bytes(&buf).unwrap(), request);
+            let buf = encode(request);
+            assert_eq!(&BitswapRequest::from_bytes(buf.into()).unwrap(), request);
         }
     }
 
@@ -260,13 +967,345 @@ pub(crate) mod tests {
         let responses = [
             BitswapResponse::Have(true),
             BitswapResponse::Have(false),
-            BitswapResponse::Block(b"block_response".to_vec()),
+            BitswapResponse::Block(Bytes::from_static(b"block_response")),
+            BitswapResponse::HaveSize(1 << 20),
+            BitswapResponse::DagBlock(
+                create_cid(&b"dag_block"[..]),
+                Bytes::from_static(b"dag_block"),
+            ),
+            BitswapResponse::Busy(Duration::from_millis(1500)),
+            BitswapResponse::Unauthorized,
+            BitswapResponse::Error,
         ];
-        let mut buf = Vec::with_capacity(13 + 1);
         for response in &responses {
-            buf.clear();
-            response.write_to(&mut buf).unwrap();
-            assert_eq!(&BitswapResponse::from_bytes(&buf).unwrap(), response);
+            let buf = encode(response);
+            assert_eq!(&BitswapResponse::from_bytes(buf.into()).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn test_decode_empty_entry() {
+        assert!(BitswapRequest::from_bytes(Bytes::new()).is_err());
+        assert!(BitswapResponse::from_bytes(Bytes::new()).is_err());
+    }
+
+    /// Writes the responses to a peer with the given capabilities and reads them back.
+    async fn roundtrip(
+        protocol: BitswapProtocol,
+        remote: Capabilities,
+        responses: Vec<BitswapResponse>,
+    ) -> io::Result<BitswapResponses> {
+        let mut codec = BitswapCodec::<DefaultParams>::with_remote(remote);
+        let mut io = Cursor::new(vec![]);
+        let res = BitswapResponses {
+            protocol,
+            capabilities: Capabilities::local(),
+            responses,
+        };
+        let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, protocol);
+        codec.write_response(&protocol, &mut io, res).await?;
+        io.set_position(0);
+        codec.read_response(&protocol, &mut io).await
+    }
+
+    fn request(requests: Vec<BitswapRequest>) -> BitswapRequests {
+        BitswapRequests {
+            protocol: BitswapProtocol::Capabilities,
+            capabilities: Capabilities::local(),
+            requests,
+        }
+    }
+
+    /// Writes a batch without leaving out what the version can't carry.
+    async fn write_batch<E: Entry>(entries: &[E]) -> Cursor<Vec<u8>> {
+        let mut io = Cursor::new(vec![]);
+        write_entries(BitswapProtocol::Batch, &mut io, usize::MAX, entries)
+            .await
+            .unwrap();
+        io.set_position(0);
+        io
+    }
+
+    #[async_std::test]
+    async fn test_codec_roundtrip() {
+        let responses = vec![
+            BitswapResponse::Have(true),
+            BitswapResponse::Block(vec![7; DefaultParams::MAX_BLOCK_SIZE].into()),
+            BitswapResponse::Have(false),
+        ];
+        let none = Capabilities::empty();
+        let res = roundtrip(BitswapProtocol::Batch, none, responses.clone()).await;
+        assert_eq!(res.unwrap().responses, responses);
+
+        let res = roundtrip(BitswapProtocol::Single, none, responses[1..2].to_vec()).await;
+        assert_eq!(res.unwrap().responses, &responses[1..2]);
+
+        let res = roundtrip(BitswapProtocol::Single, none, responses).await;
+        assert!(res.is_err());
+        assert!(roundtrip(BitswapProtocol::Batch, none, vec![])
+            .await
+            .is_err());
+        let too_large = BitswapResponse::Block(vec![0; DefaultParams::MAX_BLOCK_SIZE + 1].into());
+        assert!(roundtrip(BitswapProtocol::Batch, none, vec![too_large])
+            .await
+            .is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[async_std::test]
+    async fn test_codec_lz4_roundtrip() {
+        let responses = vec![
+            BitswapResponse::Have(true),
+            BitswapResponse::Block(vec![7; DefaultParams::MAX_BLOCK_SIZE].into()),
+            BitswapResponse::Block(Bytes::from_static(b"block_response")),
+        ];
+        let caps = Capabilities::COMPRESSION;
+        let res = roundtrip(BitswapProtocol::Capabilities, caps, responses.clone()).await;
+        assert_eq!(res.unwrap().responses, responses);
+    }
+
+    #[async_std::test]
+    async fn test_codec_capabilities() {
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Capabilities);
+        let have = BitswapRequest {
+            ty: RequestType::Have,
+            cid: create_cid(&b"have_request"[..]),
+        };
+        let mut io = Cursor::new(vec![]);
+        let req = request(vec![have.clone()]);
+        codec.write_request(&protocol, &mut io, req).await.unwrap();
+        io.set_position(0);
+        let req = codec.read_request(&protocol, &mut io).await.unwrap();
+        assert_eq!(req.capabilities, Capabilities::local());
+        assert_eq!(req.requests, vec![have.clone()]);
+
+        // unknown features are kept, but the response only uses the known ones
+        let bits = (1 << 40) | Capabilities::SIZE.bits();
+        let mut buf = unsigned_varint::encode::u64_buffer();
+        let mut msg = unsigned_varint::encode::u64(bits, &mut buf).to_vec();
+        msg.extend_from_slice(write_batch(&[have]).await.get_ref());
+        let req = codec
+            .read_request(&protocol, &mut Cursor::new(msg))
+            .await
+            .unwrap();
+        assert_eq!(req.capabilities.bits(), bits);
+        let res = BitswapResponses {
+            protocol: BitswapProtocol::Capabilities,
+            capabilities: Capabilities::local(),
+            responses: vec![BitswapResponse::HaveSize(300), BitswapResponse::Error],
+        };
+        let mut io = Cursor::new(vec![]);
+        codec.write_response(&protocol, &mut io, res).await.unwrap();
+        io.set_position(0);
+        let res = codec.read_response(&protocol, &mut io).await.unwrap();
+        assert_eq!(res.capabilities, Capabilities::local());
+        assert_eq!(
+            res.responses,
+            vec![BitswapResponse::HaveSize(300), BitswapResponse::Have(false)]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_codec_sizes() {
+        let responses = vec![BitswapResponse::HaveSize(300), BitswapResponse::Have(false)];
+        let caps = Capabilities::SIZE;
+        let res = roundtrip(BitswapProtocol::Capabilities, caps, responses.clone()).await;
+        assert_eq!(res.unwrap().responses, responses);
+
+        // peers without sizes only learn that the peer has the block
+        let expected = vec![BitswapResponse::Have(true), BitswapResponse::Have(false)];
+        let none = Capabilities::empty();
+        let res = roundtrip(BitswapProtocol::Capabilities, none, responses.clone()).await;
+        assert_eq!(res.unwrap().responses, expected);
+        let res = roundtrip(BitswapProtocol::Batch, caps, responses).await;
+        assert_eq!(res.unwrap().responses, expected);
+
+        // a block can't be larger than the maximum block size
+        let responses = vec![BitswapResponse::HaveSize(DefaultParams::MAX_BLOCK_SIZE + 1)];
+        let err = roundtrip(BitswapProtocol::Capabilities, caps, responses)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[async_std::test]
+    async fn test_codec_status() {
+        let responses = vec![
+            BitswapResponse::Busy(Duration::from_secs(1)),
+            BitswapResponse::Unauthorized,
+            BitswapResponse::Error,
+            BitswapResponse::Have(true),
+        ];
+        let caps = Capabilities::STATUS;
+        let res = roundtrip(BitswapProtocol::Capabilities, caps, responses.clone()).await;
+        assert_eq!(res.unwrap().responses, responses);
+
+        // peers without status responses only learn that the block isn't sent
+        let caps = Capabilities::SIZE | Capabilities::DAG;
+        let res = roundtrip(BitswapProtocol::Capabilities, caps, responses).await;
+        assert_eq!(
+            res.unwrap().responses,
+            vec![
+                BitswapResponse::Have(false),
+                BitswapResponse::Have(false),
+                BitswapResponse::Have(false),
+                BitswapResponse::Have(true),
+            ]
+        );
+
+        // a status response the version can't carry is rejected
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let batch = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Batch);
+        let mut io = write_batch(&[BitswapResponse::Unauthorized]).await;
+        assert!(codec.read_response(&batch, &mut io).await.is_err());
+
+        let trailing = Bytes::from_static(&[UNAUTHORIZED, 0]);
+        assert!(BitswapResponse::from_bytes(trailing).is_err());
+    }
+
+    #[test]
+    fn test_protocol_id() {
+        let single = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Single);
+        assert_eq!(single.protocol_name(), b"/ipfs-embed/bitswap/1.0.0");
+        let batch = ProtocolId::new("/myapp", BitswapProtocol::Batch);
+        assert_eq!(batch.protocol_name(), b"/myapp/bitswap/1.1.0");
+        let caps = ProtocolId::new("/myapp", BitswapProtocol::Capabilities);
+        assert_eq!(caps.protocol_name(), b"/myapp/bitswap/1.2.0");
+    }
+
+    #[async_std::test]
+    async fn test_codec_dag() {
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let dag = BitswapRequest {
+            ty: RequestType::Dag(Some(1)),
+            cid: create_cid(&b"dag_request"[..]),
+        };
+        let block = BitswapRequest {
+            ty: RequestType::Block,
+            cid: dag.cid,
+        };
+
+        let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Capabilities);
+        let mut io = Cursor::new(vec![]);
+        let req = request(vec![dag.clone()]);
+        codec.write_request(&protocol, &mut io, req).await.unwrap();
+        io.set_position(0);
+        let req = codec.read_request(&protocol, &mut io).await.unwrap();
+        assert_eq!(req.requests, vec![dag.clone()]);
+
+        // the descendants fill the response message
+        let mut io = Cursor::new(vec![]);
+        let req = request(vec![dag.clone(), block.clone()]);
+        codec.write_request(&protocol, &mut io, req).await.unwrap();
+        io.set_position(0);
+        assert!(codec.read_request(&protocol, &mut io).await.is_err());
+
+        // older versions ask for the block only
+        let batch = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Batch);
+        let mut io = Cursor::new(vec![]);
+        let req = request(vec![dag.clone()]);
+        codec.write_request(&batch, &mut io, req).await.unwrap();
+        io.set_position(0);
+        let req = codec.read_request(&batch, &mut io).await.unwrap();
+        assert_eq!(req.requests, vec![block]);
+
+        let mut io = write_batch(&[dag]).await;
+        assert!(codec.read_request(&batch, &mut io).await.is_err());
+
+        let responses = vec![
+            BitswapResponse::Block(Bytes::from_static(b"root")),
+            BitswapResponse::DagBlock(
+                create_cid(&b"dag_block"[..]),
+                Bytes::from_static(b"dag_block"),
+            ),
+        ];
+        let caps = Capabilities::DAG;
+        let res = roundtrip(BitswapProtocol::Capabilities, caps, responses.clone()).await;
+        assert_eq!(res.unwrap().responses, responses);
+        let caps = Capabilities::SIZE;
+        let res = roundtrip(BitswapProtocol::Capabilities, caps, responses.clone()).await;
+        assert_eq!(res.unwrap().responses, &responses[..1]);
+    }
+
+    #[async_std::test]
+    async fn test_codec_reconcile() {
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let cid = create_cid(&b"reconcile_request"[..]);
+        let reconcile = BitswapRequest {
+            ty: RequestType::Reconcile(Some(2), filter()),
+            cid,
+        };
+
+        let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Capabilities);
+        let mut io = Cursor::new(vec![]);
+        let req = request(vec![reconcile.clone()]);
+        codec.write_request(&protocol, &mut io, req).await.unwrap();
+        io.set_position(0);
+        let req = codec.read_request(&protocol, &mut io).await.unwrap();
+        assert_eq!(req.requests, vec![reconcile.clone()]);
+
+        // older versions ask for the block only
+        let batch = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Batch);
+        let mut io = Cursor::new(vec![]);
+        let req = request(vec![reconcile.clone()]);
+        codec.write_request(&batch, &mut io, req).await.unwrap();
+        io.set_position(0);
+        let req = codec.read_request(&batch, &mut io).await.unwrap();
+        let expected = BitswapRequest {
+            ty: RequestType::Block,
+            cid,
+        };
+        assert_eq!(req.requests, vec![expected]);
+
+        let mut io = write_batch(&[reconcile]).await;
+        assert!(codec.read_request(&batch, &mut io).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_codec_push() {
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let cid = create_cid(&b"push_request"[..]);
+        let push = BitswapRequest {
+            ty: RequestType::Push(Bytes::from(vec![0; 1024])),
+            cid,
+        };
+
+        let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Capabilities);
+        let mut io = Cursor::new(vec![]);
+        let req = request(vec![push.clone()]);
+        codec.write_request(&protocol, &mut io, req).await.unwrap();
+        io.set_position(0);
+        let req = codec.read_request(&protocol, &mut io).await.unwrap();
+        assert_eq!(req.requests, vec![push.clone()]);
+
+        // older versions can't send a push
+        let batch = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Batch);
+        let mut io = Cursor::new(vec![]);
+        let req = request(vec![push.clone()]);
+        assert!(codec.write_request(&batch, &mut io, req).await.is_err());
+
+        // request entries of older versions can't carry a block
+        for push in [
+            push,
+            BitswapRequest {
+                ty: RequestType::Push(Bytes::new()),
+                cid,
+            },
+        ] {
+            let mut io = write_batch(&[push]).await;
+            assert!(codec.read_request(&batch, &mut io).await.is_err());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_codec_invalid_batch() {
+        let mut codec = BitswapCodec::<DefaultParams>::default();
+        let protocol = ProtocolId::new(DEFAULT_PROTOCOL_PREFIX, BitswapProtocol::Batch);
+        // empty message, entry longer than the message, empty entry
+        for msg in [&[0][..], &[2, 4, 0], &[2, 0, 0]] {
+            let mut io = Cursor::new(msg.to_vec());
+            assert!(codec.read_response(&protocol, &mut io).await.is_err());
         }
     }
 }
@@ -0,0 +1,281 @@
+//! Pushing blocks to peers.
+//!
+//! A pushed block is sent as a push request and the receiver answers with whether it
+//! stored the block. When pushing a dag the references of every acknowledged block
+//! are pushed next, until the whole dag is acknowledged.
+use crate::query::QueryId;
+use bytes::Bytes;
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::{store::StoreParams, Block, Cid, Result};
+use libp2p::PeerId;
+use std::hash::Hash;
+use thiserror::Error;
+
+/// Decides if a block pushed by a peer is stored.
+///
+/// The block has already been checked to hash to its cid and to pass the
+/// [`BlockValidator`](crate::BlockValidator).
+pub trait PushPolicy<P: StoreParams>: Send + Sync + 'static {
+    /// Returns true if the block should be inserted into the store.
+    fn accept(&self, peer: &PeerId, block: &Block<P>) -> bool;
+}
+
+impl<P, F> PushPolicy<P> for F
+where
+    P: StoreParams,
+    F: Fn(&PeerId, &Block<P>) -> bool + Send + Sync + 'static,
+{
+    fn accept(&self, peer: &PeerId, block: &Block<P>) -> bool {
+        self(peer, block)
+    }
+}
+
+/// Error returned when a peer doesn't accept a pushed block.
+#[derive(Debug, Error)]
+#[error("peer {0} rejected block {1}")]
+pub struct PushRejected(pub PeerId, pub Cid);
+
+/// Error returned when a peer doesn't serve pushed blocks, because it only speaks
+/// protocol versions that can't carry them or doesn't announce the push capability.
+#[derive(Debug, Error)]
+#[error("peer {0} doesn't support pushes")]
+pub struct PushUnsupported(pub PeerId);
+
+/// Collects the references of a block.
+pub type References<P> = fn(&Block<P>, &mut FnvHashSet<Cid>) -> Result<()>;
+
+/// Block that is pushed to a peer. The requests that send it share its data.
+#[derive(Clone, Debug)]
+pub struct PushedBlock {
+    /// Cid of the block.
+    pub cid: Cid,
+    /// Data of the block.
+    pub data: Bytes,
+    /// References that are pushed once the peer stored the block.
+    references: FnvHashSet<Cid>,
+}
+
+struct Push<P: StoreParams> {
+    peer: PeerId,
+    references: Option<References<P>>,
+    seen: FnvHashSet<Cid>,
+    in_flight: usize,
+}
+
+/// Keeps track of the blocks that are pushed to peers. Push requests are identified
+/// by the id of the message that sent them.
+pub struct PushManager<P: StoreParams, K> {
+    pushes: FnvHashMap<QueryId, Push<P>>,
+    /// The block a request belongs to and whether it only asked for the capabilities
+    /// of the peer.
+    requests: FnvHashMap<K, (QueryId, PushedBlock, bool)>,
+}
+
+impl<P: StoreParams, K> Default for PushManager<P, K> {
+    fn default() -> Self {
+        Self {
+            pushes: Default::default(),
+            requests: Default::default(),
+        }
+    }
+}
+
+impl<P: StoreParams, K: Clone + Eq + Hash> PushManager<P, K> {
+    /// Starts pushing a block to a peer. If `references` is given the blocks it
+    /// references are pushed too. The block needs to be read from the store before it
+    /// can be sent.
+    pub fn push(&mut self, id: QueryId, peer: PeerId, cid: Cid, references: Option<References<P>>) {
+        let mut seen = FnvHashSet::default();
+        seen.insert(cid);
+        self.pushes.insert(
+            id,
+            Push {
+                peer,
+                references,
+                seen,
+                in_flight: 1,
+            },
+        );
+    }
+
+    /// Returns the peer of an in progress push.
+    pub fn peer(&self, id: QueryId) -> Option<PeerId> {
+        self.pushes.get(&id).map(|push| push.peer)
+    }
+
+    /// Returns the peers that blocks are pushed to.
+    pub fn peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.pushes.values().map(|push| push.peer)
+    }
+
+    /// Returns the peer of every push request that wasn't answered yet.
+    pub fn request_peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.requests
+            .values()
+            .filter_map(move |(id, _, _)| self.peer(*id))
+    }
+
+    /// Prepares a block read from the store to be pushed. Its references are collected
+    /// right away, so the data can be handed to the requests without copying it.
+    pub fn block(&self, id: QueryId, block: Block<P>) -> PushedBlock {
+        let mut references = FnvHashSet::default();
+        if let Some(references_of) = self.pushes.get(&id).and_then(|push| push.references) {
+            if let Err(err) = references_of(&block, &mut references) {
+                tracing::debug!("can't push references of {}: {}", block.cid(), err);
+            }
+        }
+        let (cid, data) = block.into_inner();
+        PushedBlock {
+            cid,
+            data: data.into(),
+            references,
+        }
+    }
+
+    /// Records the push request that sends a block.
+    pub fn sent(&mut self, request_id: K, id: QueryId, block: PushedBlock) {
+        self.requests.insert(request_id, (id, block, false));
+    }
+
+    /// Records the request that asks a peer for its capabilities before a block is
+    /// pushed to it.
+    pub fn probed(&mut self, request_id: K, id: QueryId, block: PushedBlock) {
+        self.requests.insert(request_id, (id, block, true));
+    }
+
+    /// Returns the push and block a request belongs to, and whether the request only
+    /// asked for the capabilities of the peer.
+    pub fn take_request(&mut self, request_id: &K) -> Option<(QueryId, PushedBlock, bool)> {
+        self.requests.remove(request_id)
+    }
+
+    /// Removes the requests whose ids match and returns what they belong to.
+    #[cfg(feature = "compat")]
+    pub fn take_requests(
+        &mut self,
+        matches: impl Fn(&K) -> bool,
+    ) -> Vec<(QueryId, PushedBlock, bool)> {
+        let ids: Vec<K> = self
+            .requests
+            .keys()
+            .filter(|id| matches(id))
+            .cloned()
+            .collect();
+        ids.iter()
+            .filter_map(|id| self.requests.remove(id))
+            .collect()
+    }
+
+    /// Marks a block as stored by the peer. Returns the references that need to be
+    /// pushed next.
+    pub fn ack(&mut self, id: QueryId, block: &PushedBlock) -> Vec<Cid> {
+        let push = if let Some(push) = self.pushes.get_mut(&id) {
+            push
+        } else {
+            return vec![];
+        };
+        push.in_flight -= 1;
+        let mut next = vec![];
+        for cid in &block.references {
+            if push.seen.insert(*cid) {
+                next.push(*cid);
+            }
+        }
+        push.in_flight += next.len();
+        next
+    }
+
+    /// Removes a push that has no blocks in flight. Returns true if it was removed.
+    pub fn complete(&mut self, id: QueryId) -> bool {
+        if self.pushes.get(&id).map(|push| push.in_flight) == Some(0) {
+            self.pushes.remove(&id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Stops a push. Returns true if it was in progress.
+    pub fn cancel(&mut self, id: QueryId) -> bool {
+        self.requests.retain(|_, (push, _, _)| *push != id);
+        self.pushes.remove(&id).is_some()
+    }
+
+    /// Stops all pushes. Returns the ids of the stopped pushes.
+    pub fn cancel_all(&mut self) -> Vec<QueryId> {
+        self.requests.clear();
+        self.pushes.drain().map(|(id, _)| id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::cbor::DagCborCodec;
+    use libipld::ipld;
+    use libipld::ipld::Ipld;
+    use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
+
+    fn create_block(ipld: Ipld) -> Block<DefaultParams> {
+        Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
+    }
+
+    #[test]
+    fn test_push_dag() {
+        let b0 = create_block(ipld!({ "n": 0 }));
+        let b1 = create_block(ipld!({ "prev": b0.cid(), "n": 1 }));
+        let b2 = create_block(ipld!({ "prev": b1.cid(), "prev2": b0.cid(), "n": 2 }));
+        let mut mgr: PushManager<_, u64> = PushManager::default();
+        let id = QueryId(0);
+
+        mgr.push(
+            id,
+            PeerId::random(),
+            *b2.cid(),
+            Some(|block, refs| block.references(refs)),
+        );
+        let b2 = mgr.block(id, b2);
+        let mut next = mgr.ack(id, &b2);
+        next.sort();
+        let mut expected = vec![*b0.cid(), *b1.cid()];
+        expected.sort();
+        assert_eq!(next, expected);
+        assert!(!mgr.complete(id));
+        let b1 = mgr.block(id, b1);
+        assert!(mgr.ack(id, &b1).is_empty());
+        assert!(!mgr.complete(id));
+        let b0 = mgr.block(id, b0);
+        assert!(mgr.ack(id, &b0).is_empty());
+        assert!(mgr.complete(id));
+        assert!(mgr.peer(id).is_none());
+    }
+
+    #[test]
+    fn test_push_block() {
+        let b0 = create_block(ipld!({ "n": 0 }));
+        let b1 = create_block(ipld!({ "prev": b0.cid(), "n": 1 }));
+        let mut mgr: PushManager<_, u64> = PushManager::default();
+        let id = QueryId(0);
+
+        mgr.push(id, PeerId::random(), *b1.cid(), None);
+        let b1 = mgr.block(id, b1);
+        assert!(mgr.ack(id, &b1).is_empty());
+        assert!(mgr.complete(id));
+    }
+
+    #[test]
+    fn test_push_shares_data() {
+        let b0 = create_block(ipld!({ "n": 0 }));
+        let mut mgr: PushManager<_, u64> = PushManager::default();
+        let id = QueryId(0);
+
+        mgr.push(id, PeerId::random(), *b0.cid(), None);
+        let block = mgr.block(id, b0.clone());
+        assert_eq!(&block.data[..], b0.data());
+        // a request and its resends point at the same data
+        mgr.sent(0, id, block.clone());
+        let (_, resent, _) = mgr.take_request(&0).unwrap();
+        assert_eq!(resent.data.as_ptr(), block.data.as_ptr());
+    }
+}
@@ -4,10 +4,17 @@ use libipld::Cid;
 use libp2p::PeerId;
 use prometheus::HistogramTimer;
 use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of times a get query asks a peer again after a busy or error response.
+const MAX_RETRIES: usize = 3;
+
+/// Time a get query waits before asking a peer again after an error response.
+const ERROR_RETRY_AFTER: Duration = Duration::from_millis(500);
 
 /// Query id.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct QueryId(u64);
+pub struct QueryId(pub(crate) u64);
 
 impl std::fmt::Display for QueryId {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -24,6 +31,12 @@ pub enum Request {
     Block(PeerId, Cid),
     /// Missing blocks query.
     MissingBlocks(Cid),
+    /// Local store lookup.
+    Contains(Cid),
+    /// Asks for connected peers to send have queries to, excluding the given peers.
+    Broadcast(Cid, Vec<PeerId>),
+    /// Waits before asking a peer again.
+    Backoff(PeerId, Duration),
 }
 
 impl std::fmt::Display for Request {
@@ -32,6 +45,9 @@ impl std::fmt::Display for Request {
             Self::Have(_, _) => write!(f, "have"),
             Self::Block(_, _) => write!(f, "block"),
             Self::MissingBlocks(_) => write!(f, "missing-blocks"),
+            Self::Contains(_) => write!(f, "contains"),
+            Self::Broadcast(_, _) => write!(f, "broadcast"),
+            Self::Backoff(_, delay) => write!(f, "backoff {:?}", delay),
         }
     }
 }
@@ -41,18 +57,40 @@ impl std::fmt::Display for Request {
 pub enum Response {
     /// Have query.
     Have(PeerId, bool),
+    /// Positive have query response with the size of the block.
+    HaveSize(PeerId, usize),
     /// Block query.
     Block(PeerId, bool),
     /// Missing blocks query.
     MissingBlocks(Vec<Cid>),
+    /// Local store lookup.
+    Contains(bool),
+    /// Peers to broadcast have queries to.
+    Broadcast(Vec<PeerId>),
+    /// Have or block query the peer is too busy to answer, it may be asked again after
+    /// the given time.
+    Busy(PeerId, Duration),
+    /// Have or block query the peer refused to answer.
+    Unauthorized(PeerId),
+    /// Have or block query the peer failed to answer.
+    Error(PeerId),
+    /// Backoff query.
+    Backoff,
 }
 
 impl std::fmt::Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::Have(_, have) => write!(f, "have {}", have),
+            Self::HaveSize(_, size) => write!(f, "have-size {}", size),
             Self::Block(_, block) => write!(f, "block {}", block),
             Self::MissingBlocks(missing) => write!(f, "missing-blocks {}", missing.len()),
+            Self::Contains(contains) => write!(f, "contains {}", contains),
+            Self::Broadcast(peers) => write!(f, "broadcast {}", peers.len()),
+            Self::Busy(_, retry_after) => write!(f, "busy {:?}", retry_after),
+            Self::Unauthorized(_) => write!(f, "unauthorized"),
+            Self::Error(_) => write!(f, "error"),
+            Self::Backoff => write!(f, "backoff"),
         }
     }
 }
@@ -78,6 +116,8 @@ pub struct Header {
     pub parent: Option<QueryId>,
     /// Cid.
     pub cid: Cid,
+    /// Peer a have or block request is sent to.
+    pub peer: Option<PeerId>,
     /// Timer.
     pub timer: HistogramTimer,
     /// Type.
@@ -108,9 +148,51 @@ enum State {
 
 #[derive(Debug, Default)]
 struct GetState {
+    local: Option<QueryId>,
     have: FnvHashSet<QueryId>,
     block: Option<QueryId>,
     providers: Vec<PeerId>,
+    /// Block sizes announced by providers.
+    sizes: FnvHashMap<PeerId, usize>,
+    initial: Vec<PeerId>,
+    asked: FnvHashSet<PeerId>,
+    broadcast: Option<QueryId>,
+    broadcasted: bool,
+    /// Number of times a peer was asked again.
+    retries: FnvHashMap<PeerId, usize>,
+}
+
+impl GetState {
+    /// Returns the block size announced by most providers.
+    fn size(&self) -> Option<usize> {
+        let mut counts = FnvHashMap::<usize, usize>::default();
+        for size in self.sizes.values() {
+            *counts.entry(*size).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .max_by_key(|(size, count)| (*count, std::cmp::Reverse(*size)))
+            .map(|(size, _)| size)
+    }
+
+    /// Takes the provider that the block is requested from next. Providers that
+    /// announced the size most providers agree on are asked first, then the ones that
+    /// didn't announce a size and last the ones that announced another size.
+    fn next_provider(&mut self) -> Option<PeerId> {
+        let size = self.size();
+        let rank = |peer: &PeerId| match self.sizes.get(peer) {
+            Some(s) if Some(*s) == size => 0,
+            None => 1,
+            Some(_) => 2,
+        };
+        // among equally ranked providers the one that answered last is asked first
+        let (i, _) = self
+            .providers
+            .iter()
+            .enumerate()
+            .min_by_key(|(i, peer)| (rank(peer), std::cmp::Reverse(*i)))?;
+        Some(self.providers.remove(i))
+    }
 }
 
 #[derive(Debug, Default)]
@@ -130,9 +212,25 @@ pub struct QueryManager {
     id_counter: u64,
     queries: FnvHashMap<QueryId, Query>,
     events: VecDeque<QueryEvent>,
+    broadcast: bool,
+    /// Get queries of sync queries that started without providers.
+    exhausted: Vec<QueryId>,
 }
 
 impl QueryManager {
+    /// Enables asking connected peers when a get query runs out of providers.
+    pub fn set_broadcast(&mut self, broadcast: bool) {
+        self.broadcast = broadcast;
+    }
+
+    /// Allocates an id for an operation that is not a query or a query that is started
+    /// later, so that it doesn't collide with query ids.
+    pub fn next_id(&mut self) -> QueryId {
+        let id = QueryId(self.id_counter);
+        self.id_counter += 1;
+        id
+    }
+
     /// Start a new subquery.
     fn start_query(
         &mut self,
@@ -147,12 +245,19 @@ impl QueryManager {
             .start_timer();
         let id = QueryId(self.id_counter);
         self.id_counter += 1;
+        let peer = match &req {
+            Request::Have(peer, _) | Request::Block(peer, _) | Request::Backoff(peer, _) => {
+                Some(*peer)
+            }
+            Request::MissingBlocks(_) | Request::Contains(_) | Request::Broadcast(_, _) => None,
+        };
         let query = Query {
             hdr: Header {
                 id,
                 root,
                 parent,
                 cid,
+                peer,
                 timer,
                 label,
             },
@@ -180,6 +285,17 @@ impl QueryManager {
         )
     }
 
+    /// Starts a query that waits before a peer is asked again.
+    fn backoff(&mut self, parent: &Header, peer_id: PeerId, delay: Duration) -> QueryId {
+        self.start_query(
+            parent.root,
+            Some(parent.id),
+            parent.cid,
+            Request::Backoff(peer_id, delay),
+            "backoff",
+        )
+    }
+
     /// Starts a query to determine the missing blocks of a dag.
     fn missing_blocks(&mut self, parent: QueryId, cid: Cid) -> QueryId {
         self.start_query(
@@ -191,7 +307,66 @@ impl QueryManager {
         )
     }
 
-    /// Starts a query to locate and retrieve a block. Panics if no providers are supplied.
+    /// Starts a query to look up a block in the local store.
+    fn contains(&mut self, parent: QueryId, cid: Cid) -> QueryId {
+        self.start_query(
+            parent,
+            Some(parent),
+            cid,
+            Request::Contains(cid),
+            "contains",
+        )
+    }
+
+    /// Starts have and block queries for a get query.
+    fn request_providers(
+        &mut self,
+        root: QueryId,
+        id: QueryId,
+        cid: Cid,
+        state: &mut GetState,
+        providers: impl Iterator<Item = PeerId>,
+    ) {
+        for peer in providers {
+            state.asked.insert(peer);
+            if state.block.is_none() {
+                state.block = Some(self.block(root, id, peer, cid));
+            } else {
+                state.have.insert(self.have(root, id, peer, cid));
+            }
+        }
+    }
+
+    /// Asks for connected peers once a get query has no providers left. Completes the
+    /// get query with a block-not-found error if broadcasting is disabled or the peers
+    /// were already asked.
+    fn exhausted(
+        &mut self,
+        parent: &Header,
+        mut state: GetState,
+    ) -> Transition<GetState, Result<(), Cid>> {
+        if !self.broadcast || state.broadcasted {
+            return Transition::Complete(Err(parent.cid));
+        }
+        state.broadcasted = true;
+        let asked = state.asked.iter().copied().collect();
+        state.broadcast = Some(self.start_query(
+            parent.root,
+            Some(parent.id),
+            parent.cid,
+            Request::Broadcast(parent.cid, asked),
+            "broadcast",
+        ));
+        Transition::Next(state)
+    }
+
+    /// Starts a query to locate and retrieve a block.
+    ///
+    /// A get query that isn't part of a sync query first looks up the block in the
+    /// local store and completes without asking the providers if it is found. A get
+    /// query that is part of a sync query and has no providers asks the connected
+    /// peers if broadcasting is enabled, otherwise it fails once the next event is
+    /// retrieved.
     pub fn get(
         &mut self,
         parent: Option<QueryId>,
@@ -206,20 +381,32 @@ impl QueryManager {
         let root = parent.unwrap_or(id);
         tracing::trace!("{} {} get", root, id);
         let mut state = GetState::default();
-        for peer in providers {
-            if state.block.is_none() {
-                state.block = Some(self.block(root, id, peer, cid));
-            } else {
-                state.have.insert(self.have(root, id, peer, cid));
+        if parent.is_none() {
+            state.local = Some(self.contains(root, cid));
+            state.initial = providers.collect();
+        } else {
+            self.request_providers(root, id, cid, &mut state, providers);
+            if state.block.is_none() && !self.broadcast {
+                // the sync query isn't inserted yet
+                self.exhausted.push(id);
+            } else if state.block.is_none() {
+                state.broadcasted = true;
+                state.broadcast = Some(self.start_query(
+                    root,
+                    Some(id),
+                    cid,
+                    Request::Broadcast(cid, vec![]),
+                    "broadcast",
+                ));
             }
         }
-        assert!(state.block.is_some());
         let query = Query {
             hdr: Header {
                 id,
                 root,
                 parent,
                 cid,
+                peer: None,
                 timer,
                 label: "get",
             },
@@ -237,11 +424,22 @@ impl QueryManager {
         providers: Vec<PeerId>,
         missing: impl Iterator<Item = Cid>,
     ) -> QueryId {
+        let id = self.next_id();
+        self.start_sync(id, cid, providers, missing);
+        id
+    }
+
+    /// Starts a sync query with an id returned by `next_id`.
+    pub fn start_sync(
+        &mut self,
+        id: QueryId,
+        cid: Cid,
+        providers: Vec<PeerId>,
+        missing: impl Iterator<Item = Cid>,
+    ) {
         let timer = REQUEST_DURATION_SECONDS
             .with_label_values(&["sync"])
             .start_timer();
-        let id = QueryId(self.id_counter);
-        self.id_counter += 1;
         tracing::trace!("{} {} sync", id, id);
         let mut state = SyncState::default();
         for cid in missing {
@@ -259,13 +457,22 @@ impl QueryManager {
                 root: id,
                 parent: None,
                 cid,
+                peer: None,
                 timer,
                 label: "sync",
             },
             state: State::Sync(state),
         };
         self.queries.insert(id, query);
-        id
+    }
+
+    /// Returns true if a subquery is part of a sync query.
+    pub fn in_sync(&self, id: QueryId) -> bool {
+        self.queries
+            .get(&id)
+            .and_then(|query| self.queries.get(&query.hdr.root))
+            .map(|root| matches!(root.state, State::Sync(_)))
+            .unwrap_or_default()
     }
 
     /// Cancels an in progress query.
@@ -291,6 +498,7 @@ impl QueryManager {
         match query.state {
             State::Get(_) => {
                 tracing::trace!("{} {} get cancel", root, root);
+                self.queries.retain(|_, query| query.hdr.root != root);
                 true
             }
             State::Sync(state) => {
@@ -299,6 +507,7 @@ impl QueryManager {
                     self.queries.remove(&id);
                 }
                 tracing::trace!("{} {} sync cancel", root, root);
+                self.queries.retain(|_, query| query.hdr.root != root);
                 true
             }
             State::None => {
@@ -308,6 +517,20 @@ impl QueryManager {
         }
     }
 
+    /// Cancels all queries. Returns the ids of the cancelled root queries.
+    pub fn cancel_all(&mut self) -> Vec<QueryId> {
+        self.events.clear();
+        self.exhausted.clear();
+        let roots = self
+            .queries
+            .values()
+            .filter(|query| query.hdr.parent.is_none())
+            .map(|query| query.hdr.id)
+            .collect();
+        self.queries.clear();
+        roots
+    }
+
     /// Advances a get query state machine using a transition function.
     fn get_query<F>(&mut self, id: QueryId, f: F)
     where
@@ -366,10 +589,11 @@ impl QueryManager {
     /// Processes the response of a have query.
     ///
     /// Marks the in progress query as complete and updates the set of peers that have
-    /// a block. If there isn't an in progress block query a new block query will be
-    /// started. If no block query can be started either a provider query is started or
-    /// the get query is marked as complete with a block-not-found error.
-    fn recv_have(&mut self, query: Header, peer_id: PeerId, have: bool) {
+    /// a block, together with the block size they announced. If there isn't an in
+    /// progress block query a new block query will be started. If no block query can be
+    /// started either connected peers are asked or the get query is marked as complete
+    /// with a block-not-found error.
+    fn recv_have(&mut self, query: Header, peer_id: PeerId, have: bool, size: Option<usize>) {
         self.get_query(query.parent.unwrap(), |mgr, parent, mut state| {
             state.have.remove(&query.id);
             if state.block == Some(query.id) {
@@ -377,22 +601,68 @@ impl QueryManager {
             }
             if have {
                 state.providers.push(peer_id);
+                if let Some(size) = size {
+                    state.sizes.insert(peer_id, size);
+                }
             }
-            if state.block.is_none() && !state.providers.is_empty() {
-                state.block = Some(mgr.block(
-                    parent.root,
-                    parent.id,
-                    state.providers.pop().unwrap(),
-                    query.cid,
-                ));
+            if state.block.is_none() {
+                if let Some(provider) = state.next_provider() {
+                    state.block = Some(mgr.block(parent.root, parent.id, provider, query.cid));
+                }
             }
-            if state.have.is_empty() && state.block.is_none() && state.providers.is_empty() {
-                if state.providers.is_empty() {
-                    return Transition::Complete(Err(query.cid));
-                } else {
-                    return Transition::Complete(Ok(()));
+            if state.have.is_empty()
+                && state.block.is_none()
+                && state.providers.is_empty()
+                && state.broadcast.is_none()
+            {
+                return mgr.exhausted(parent, state);
+            }
+            Transition::Next(state)
+        });
+    }
+
+    /// Processes the response of a local lookup.
+    ///
+    /// Completes the get query if the block is in the local store. Otherwise asks the
+    /// initial set of providers.
+    fn recv_contains(&mut self, query: Header, contains: bool) {
+        self.get_query(query.parent.unwrap(), |mgr, parent, mut state| {
+            state.local = None;
+            if contains {
+                return Transition::Complete(Ok(()));
+            }
+            let initial = std::mem::take(&mut state.initial);
+            mgr.request_providers(
+                parent.root,
+                parent.id,
+                parent.cid,
+                &mut state,
+                initial.into_iter(),
+            );
+            if state.block.is_none() {
+                return mgr.exhausted(parent, state);
+            }
+            Transition::Next(state)
+        });
+    }
+
+    /// Processes the response of a broadcast query.
+    ///
+    /// Sends have queries to the connected peers. If there are none the get query is
+    /// marked as complete with a block-not-found error.
+    fn recv_broadcast(&mut self, query: Header, peers: Vec<PeerId>) {
+        self.get_query(query.parent.unwrap(), |mgr, parent, mut state| {
+            state.broadcast = None;
+            for peer in peers {
+                if state.asked.insert(peer) {
+                    state
+                        .have
+                        .insert(mgr.have(parent.root, parent.id, peer, query.cid));
                 }
             }
+            if state.have.is_empty() && state.block.is_none() {
+                return Transition::Complete(Err(query.cid));
+            }
             Transition::Next(state)
         });
     }
@@ -407,8 +677,53 @@ impl QueryManager {
                 Transition::Complete(Ok(()))
             });
         } else {
-            self.recv_have(query, peer_id, block);
+            self.recv_have(query, peer_id, block, None);
+        }
+    }
+
+    /// Processes a busy or error response to a have or block query.
+    ///
+    /// The query is replaced by a backoff query, after which the peer is asked again.
+    /// A peer that was asked again too often is treated like a peer that doesn't have
+    /// the block.
+    fn recv_retry(&mut self, query: Header, peer_id: PeerId, delay: Duration) {
+        let parent = query.parent.unwrap();
+        let retry = match self.queries.get_mut(&parent).map(|q| &mut q.state) {
+            Some(State::Get(state)) => {
+                let retries = state.retries.entry(peer_id).or_default();
+                *retries += 1;
+                *retries <= MAX_RETRIES
+            }
+            _ => false,
+        };
+        if !retry {
+            self.recv_have(query, peer_id, false, None);
+            return;
         }
+        self.get_query(parent, |mgr, parent, mut state| {
+            if state.block == Some(query.id) {
+                state.block = Some(mgr.backoff(parent, peer_id, delay));
+            } else if state.have.remove(&query.id) {
+                state.have.insert(mgr.backoff(parent, peer_id, delay));
+            }
+            Transition::Next(state)
+        });
+    }
+
+    /// Processes the response of a backoff query.
+    ///
+    /// Asks the peer again with the query the backoff query replaced.
+    fn recv_backoff(&mut self, query: Header) {
+        let peer_id = query.peer.unwrap();
+        self.get_query(query.parent.unwrap(), |mgr, parent, mut state| {
+            if state.block == Some(query.id) {
+                state.block = Some(mgr.block(parent.root, parent.id, peer_id, parent.cid));
+            } else if state.have.remove(&query.id) {
+                let have = mgr.have(parent.root, parent.id, peer_id, parent.cid);
+                state.have.insert(have);
+            }
+            Transition::Next(state)
+        });
     }
 
     /// Processes the response of a missing blocks query.
@@ -479,7 +794,10 @@ impl QueryManager {
         tracing::trace!("{} {} {}", query.root, query.id, res);
         match res {
             Response::Have(peer, have) => {
-                self.recv_have(query, peer, have);
+                self.recv_have(query, peer, have, None);
+            }
+            Response::HaveSize(peer, size) => {
+                self.recv_have(query, peer, true, Some(size));
             }
             Response::Block(peer, block) => {
                 self.recv_block(query, peer, block);
@@ -487,6 +805,25 @@ impl QueryManager {
             Response::MissingBlocks(cids) => {
                 self.recv_missing_blocks(query, cids);
             }
+            Response::Contains(contains) => {
+                self.recv_contains(query, contains);
+            }
+            Response::Broadcast(peers) => {
+                self.recv_broadcast(query, peers);
+            }
+            Response::Busy(peer, retry_after) => {
+                self.recv_retry(query, peer, retry_after);
+            }
+            Response::Unauthorized(peer) => {
+                // the peer is skipped like a peer that doesn't have the block
+                self.recv_have(query, peer, false, None);
+            }
+            Response::Error(peer) => {
+                self.recv_retry(query, peer, ERROR_RETRY_AFTER);
+            }
+            Response::Backoff => {
+                self.recv_backoff(query);
+            }
         }
     }
 
@@ -495,8 +832,42 @@ impl QueryManager {
         self.queries.get(&id).map(|q| &q.hdr)
     }
 
+    /// Returns the total size of the blocks a query is still retrieving, as far as
+    /// providers announced their sizes.
+    pub fn pending_bytes(&self, root: QueryId) -> u64 {
+        self.queries
+            .values()
+            .filter(|query| query.hdr.root == root)
+            .filter_map(|query| match &query.state {
+                State::Get(state) => state.size(),
+                _ => None,
+            })
+            .map(|size| size as u64)
+            .fold(0u64, u64::saturating_add)
+    }
+
+    /// Returns the peers that an active query sends requests to or lists as a provider.
+    pub fn providers(&self) -> FnvHashSet<PeerId> {
+        let mut peers = FnvHashSet::default();
+        for query in self.queries.values() {
+            peers.extend(query.hdr.peer);
+            match &query.state {
+                State::Get(state) => {
+                    peers.extend(state.providers.iter().copied());
+                    peers.extend(state.initial.iter().copied());
+                }
+                State::Sync(state) => peers.extend(state.providers.iter().copied()),
+                State::None => {}
+            }
+        }
+        peers
+    }
+
     /// Retrieves the next query event.
     pub fn next(&mut self) -> Option<QueryEvent> {
+        for id in std::mem::take(&mut self.exhausted) {
+            self.get_query(id, |mgr, parent, state| mgr.exhausted(parent, state));
+        }
         self.events.pop_front()
     }
 }
@@ -531,6 +902,12 @@ mod tests {
         }
     }
 
+    /// Answers the local lookup of a get query with a miss.
+    fn assert_not_local(mgr: &mut QueryManager, cid: Cid) {
+        let id = assert_request(mgr.next(), Request::Contains(cid));
+        mgr.inject_response(id, Response::Contains(false));
+    }
+
     fn assert_complete(event: Option<QueryEvent>, id: QueryId, res: Result<(), Cid>) {
         if let Some(QueryEvent::Complete(id2, res2)) = event {
             assert_eq!(id, id2);
@@ -547,6 +924,7 @@ mod tests {
         let cid = Cid::default();
 
         let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
 
         let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
@@ -566,6 +944,7 @@ mod tests {
         let cid = Cid::default();
 
         let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
 
         let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
@@ -585,6 +964,7 @@ mod tests {
         let cid = Cid::default();
 
         let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
 
         let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
@@ -607,6 +987,7 @@ mod tests {
         let cid = Cid::default();
 
         let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
 
         let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
         let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
@@ -625,6 +1006,91 @@ mod tests {
         assert_complete(mgr.next(), id, Ok(()));
     }
 
+    #[test]
+    fn test_get_query_retries_busy_peer() {
+        let mut mgr = QueryManager::default();
+        let initial_set = gen_peers(2);
+        let cid = Cid::default();
+
+        let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
+
+        let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
+
+        let delay = Duration::from_millis(100);
+        mgr.inject_response(id1, Response::Busy(initial_set[0], delay));
+        mgr.inject_response(id2, Response::Unauthorized(initial_set[1]));
+
+        let id1 = assert_request(mgr.next(), Request::Backoff(initial_set[0], delay));
+        assert!(mgr.next().is_none());
+        mgr.inject_response(id1, Response::Backoff);
+
+        let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        mgr.inject_response(id1, Response::Block(initial_set[0], true));
+
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
+    #[test]
+    fn test_get_query_gives_up_after_retries() {
+        let mut mgr = QueryManager::default();
+        let initial_set = gen_peers(2);
+        let cid = Cid::default();
+
+        let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
+
+        let mut id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
+        mgr.inject_response(id2, Response::Have(initial_set[1], true));
+
+        for _ in 0..MAX_RETRIES {
+            mgr.inject_response(id1, Response::Error(initial_set[0]));
+            let backoff = Request::Backoff(initial_set[0], ERROR_RETRY_AFTER);
+            let backoff = assert_request(mgr.next(), backoff);
+            mgr.inject_response(backoff, Response::Backoff);
+            id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        }
+        // the provider that answered is asked next
+        mgr.inject_response(id1, Response::Error(initial_set[0]));
+        let id2 = assert_request(mgr.next(), Request::Block(initial_set[1], cid));
+        mgr.inject_response(id2, Response::Block(initial_set[1], true));
+
+        assert_complete(mgr.next(), id, Ok(()));
+    }
+
+    #[test]
+    fn test_get_query_prefers_agreed_size() {
+        let mut mgr = QueryManager::default();
+        let initial_set = gen_peers(5);
+        let cid = Cid::default();
+
+        let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
+
+        let id0 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        let ids: Vec<_> = initial_set[1..]
+            .iter()
+            .map(|peer| assert_request(mgr.next(), Request::Have(*peer, cid)))
+            .collect();
+        assert_eq!(mgr.pending_bytes(id), 0);
+
+        mgr.inject_response(ids[0], Response::HaveSize(initial_set[1], 100));
+        mgr.inject_response(ids[1], Response::Have(initial_set[2], true));
+        mgr.inject_response(ids[2], Response::HaveSize(initial_set[3], 999));
+        mgr.inject_response(ids[3], Response::HaveSize(initial_set[4], 100));
+        assert_eq!(mgr.pending_bytes(id), 100);
+
+        // agreeing providers first, then providers without a size
+        mgr.inject_response(id0, Response::Block(initial_set[0], false));
+        for i in [4, 1, 2, 3] {
+            let id1 = assert_request(mgr.next(), Request::Block(initial_set[i], cid));
+            mgr.inject_response(id1, Response::Block(initial_set[i], false));
+        }
+        assert_complete(mgr.next(), id, Err(cid));
+    }
+
     #[test]
     fn test_sync_query() {
         tracing_try_init();
@@ -658,4 +1124,106 @@ mod tests {
         mgr.inject_response(id1, Response::MissingBlocks(vec![]));
         assert_complete(mgr.next(), id, Ok(()));
     }
+
+    #[test]
+    fn test_get_query_local() {
+        let mut mgr = QueryManager::default();
+        let initial_set = gen_peers(2);
+        let cid = Cid::default();
+
+        let id = mgr.get(None, cid, initial_set.iter().copied());
+        let id1 = assert_request(mgr.next(), Request::Contains(cid));
+        mgr.inject_response(id1, Response::Contains(true));
+        assert_complete(mgr.next(), id, Ok(()));
+        assert!(mgr.next().is_none());
+
+        let id = mgr.get(None, cid, std::iter::empty());
+        assert_not_local(&mut mgr, cid);
+        assert_complete(mgr.next(), id, Err(cid));
+    }
+
+    #[test]
+    fn test_get_query_broadcast() {
+        let mut mgr = QueryManager::default();
+        mgr.set_broadcast(true);
+        let initial_set = gen_peers(1);
+        let connected = gen_peers(2);
+        let cid = Cid::default();
+
+        let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
+        let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        mgr.inject_response(id1, Response::Block(initial_set[0], false));
+
+        let id1 = assert_request(mgr.next(), Request::Broadcast(cid, initial_set.clone()));
+        mgr.inject_response(id1, Response::Broadcast(connected.clone()));
+        let id1 = assert_request(mgr.next(), Request::Have(connected[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(connected[1], cid));
+        mgr.inject_response(id1, Response::Have(connected[0], false));
+        mgr.inject_response(id2, Response::Have(connected[1], true));
+
+        let id1 = assert_request(mgr.next(), Request::Block(connected[1], cid));
+        mgr.inject_response(id1, Response::Block(connected[1], false));
+        assert_complete(mgr.next(), id, Err(cid));
+    }
+
+    #[test]
+    fn test_get_query_broadcast_no_peers() {
+        let mut mgr = QueryManager::default();
+        mgr.set_broadcast(true);
+        let cid = Cid::default();
+
+        let id = mgr.get(None, cid, std::iter::empty());
+        assert_not_local(&mut mgr, cid);
+        let id1 = assert_request(mgr.next(), Request::Broadcast(cid, vec![]));
+        mgr.inject_response(id1, Response::Broadcast(vec![]));
+        assert_complete(mgr.next(), id, Err(cid));
+    }
+
+    #[test]
+    fn test_sync_query_no_providers() {
+        let mut mgr = QueryManager::default();
+        let cid = Cid::default();
+
+        let id = mgr.sync(cid, vec![], std::iter::once(cid));
+        assert_complete(mgr.next(), id, Err(cid));
+    }
+
+    #[test]
+    fn test_providers() {
+        let mut mgr = QueryManager::default();
+        let providers = gen_peers(2);
+        let cid = Cid::default();
+
+        let id = mgr.sync(cid, providers.clone(), std::iter::once(cid));
+        let id1 = assert_request(mgr.next(), Request::Block(providers[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(providers[1], cid));
+        assert_eq!(mgr.providers(), providers.iter().copied().collect());
+
+        mgr.inject_response(id1, Response::Block(providers[0], true));
+        mgr.inject_response(id2, Response::Have(providers[1], false));
+        assert_eq!(mgr.providers(), providers.iter().copied().collect());
+
+        assert!(mgr.cancel(id));
+        assert!(mgr.providers().is_empty());
+    }
+
+    #[test]
+    fn test_cancel_removes_subqueries() {
+        let mut mgr = QueryManager::default();
+        let initial_set = gen_peers(2);
+        let cid = Cid::default();
+
+        let id = mgr.get(None, cid, initial_set.iter().copied());
+        assert_not_local(&mut mgr, cid);
+
+        let id1 = assert_request(mgr.next(), Request::Block(initial_set[0], cid));
+        let id2 = assert_request(mgr.next(), Request::Have(initial_set[1], cid));
+
+        assert!(mgr.cancel(id));
+        assert!(mgr.query_info(id1).is_none());
+        assert!(mgr.query_info(id2).is_none());
+        mgr.inject_response(id1, Response::Block(initial_set[0], true));
+        assert!(mgr.next().is_none());
+    }
 }
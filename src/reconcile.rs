@@ -0,0 +1,255 @@
+//! Set reconciliation for sync queries.
+//!
+//! When two peers share most of a dag, like successive versions of a dataset, a
+//! sync query would still ask for every block below each missing one. A reconcile
+//! query first collects the blocks of a dag the store already has into a bloom filter
+//! and sends it along with the dag request for its root. The provider leaves out the
+//! descendants that are in the filter and doesn't descend into them.
+//!
+//! A false positive leaves out a block the requester doesn't have. The sync query
+//! finds it with `BitswapStore::missing_blocks` once its parent is stored and asks
+//! for it like any other missing block.
+use crate::behaviour::AsyncBitswapStore;
+use crate::protocol::invalid_data;
+use crate::push::References;
+use bytes::Bytes;
+use fnv::{FnvHashSet, FnvHasher};
+use libipld::{store::StoreParams, Block, Cid, Result};
+use std::hash::Hasher;
+use std::io;
+use thiserror::Error;
+
+/// Maximum size of a filter. Blocks beyond its capacity raise the false positive
+/// rate, which only costs extra requests.
+pub(crate) const MAX_FILTER_BYTES: usize = 64 * 1024;
+
+/// Maximum number of blocks collected into a filter.
+const MAX_FILTER_CIDS: usize = 64 * 1024;
+
+/// Bits per element, which gives a false positive rate of about one percent.
+const BITS_PER_CID: usize = 10;
+
+/// Maximum number of hash functions of a filter.
+const MAX_HASHES: u32 = 16;
+
+/// Number of blocks read from the store at once while collecting a filter.
+const READ_BATCH_SIZE: usize = 128;
+
+/// Error returned when a received filter has no bits or an invalid number of hashes.
+#[derive(Debug, Error)]
+#[error("invalid bloom filter with {0} hashes and {1} bytes")]
+pub struct InvalidFilter(u32, usize);
+
+/// Bloom filter of cids.
+///
+/// A cid is hashed with 64 bit FNV-1a over its binary representation. The low and
+/// the high 32 bits `h1` and `h2` give the bit indices `(h1 + i * h2) mod m` for
+/// `i` below the number of hashes, where `m` is the number of bits. Bit `j` is bit
+/// `j % 8` of byte `j / 8`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BloomFilter {
+    hashes: u32,
+    bits: Bytes,
+}
+
+impl BloomFilter {
+    /// Creates a filter containing the cids, of at most `max_bytes` bytes.
+    pub fn new(cids: &[Cid], max_bytes: usize) -> Self {
+        let bytes = cids.len() * BITS_PER_CID / 8;
+        let bytes = bytes.clamp(1, max_bytes.max(1));
+        let bits = bytes * 8;
+        // k = m / n * ln 2
+        let hashes = (bits as f64 / cids.len().max(1) as f64 * std::f64::consts::LN_2).round();
+        let hashes = (hashes as u32).clamp(1, MAX_HASHES);
+        let mut data = vec![0u8; bytes];
+        for cid in cids {
+            for bit in indices(cid, hashes, bits) {
+                data[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Self {
+            hashes,
+            bits: data.into(),
+        }
+    }
+
+    /// Decodes a filter from its number of hashes and its bits.
+    pub fn from_parts(hashes: u32, bits: Bytes) -> io::Result<Self> {
+        if hashes == 0 || hashes > MAX_HASHES || bits.is_empty() {
+            return Err(invalid_data(InvalidFilter(hashes, bits.len())));
+        }
+        Ok(Self { hashes, bits })
+    }
+
+    /// Returns the number of hashes.
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    /// Returns the bits.
+    pub fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// Returns true if the cid may be in the filter.
+    pub fn contains(&self, cid: &Cid) -> bool {
+        indices(cid, self.hashes, self.bits.len() * 8)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+fn indices(cid: &Cid, hashes: u32, bits: usize) -> impl Iterator<Item = usize> {
+    let mut hasher = FnvHasher::default();
+    hasher.write(&cid.to_bytes());
+    let hash = hasher.finish();
+    let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+    (0..hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits as u64) as usize)
+}
+
+/// Collects the blocks of the dag below `base` that are in the store into a filter.
+///
+/// The filter is only sent with the first dag request of a query, whose response
+/// doesn't reach deeper than `depth` below the requested root. The blocks of `base`
+/// are at least as deep in the requested dag as in `base`, so the blocks more than
+/// `depth` levels below `base` can't be left out and aren't collected.
+pub(crate) async fn read_filter<S: AsyncBitswapStore>(
+    store: &S,
+    base: Cid,
+    depth: Option<u64>,
+    references: References<S::Params>,
+) -> Result<BloomFilter> {
+    let mut cids = vec![];
+    let mut seen = FnvHashSet::default();
+    seen.insert(base);
+    let mut level = vec![base];
+    let mut level_depth = 0;
+    while !level.is_empty() && cids.len() < MAX_FILTER_CIDS {
+        let expand = depth.map_or(true, |depth| level_depth < depth);
+        let mut next = vec![];
+        for batch in level.chunks(READ_BATCH_SIZE) {
+            let blocks = store.get_many(batch).await?;
+            for (cid, data) in batch.iter().zip(blocks) {
+                // blocks the store doesn't have can't be left out
+                let data = match data {
+                    Some(data) => data,
+                    None => continue,
+                };
+                cids.push(*cid);
+                if !expand {
+                    continue;
+                }
+                let block = Block::<S::Params>::new_unchecked(*cid, data);
+                let mut refs = FnvHashSet::default();
+                if let Err(err) = references(&block, &mut refs) {
+                    tracing::debug!("can't collect references of {}: {}", cid, err);
+                }
+                next.extend(refs.into_iter().filter(|cid| seen.insert(*cid)));
+            }
+            if cids.len() >= MAX_FILTER_CIDS {
+                break;
+            }
+        }
+        level = next;
+        level_depth += 1;
+    }
+    cids.truncate(MAX_FILTER_CIDS);
+    let max_bytes = MAX_FILTER_BYTES.min(S::Params::MAX_BLOCK_SIZE / 2);
+    Ok(BloomFilter::new(&cids, max_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behaviour::BitswapStore;
+    use crate::db::BlockingStore;
+    use crate::protocol::tests::create_cid;
+    use fnv::FnvHashMap;
+    use libipld::cbor::DagCborCodec;
+    use libipld::ipld;
+    use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
+
+    #[derive(Default)]
+    struct Store(FnvHashMap<Cid, Vec<u8>>);
+
+    impl BitswapStore for Store {
+        type Params = DefaultParams;
+        fn contains(&mut self, cid: &Cid) -> Result<bool> {
+            Ok(self.0.contains_key(cid))
+        }
+        fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+            Ok(self.0.get(cid).cloned())
+        }
+        fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+            self.0.insert(*block.cid(), block.data().to_vec());
+            Ok(())
+        }
+        fn missing_blocks(&mut self, _cid: &Cid) -> Result<Vec<Cid>> {
+            Ok(vec![])
+        }
+    }
+
+    fn references(block: &Block<DefaultParams>, refs: &mut FnvHashSet<Cid>) -> Result<()> {
+        block.references(refs)
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let cids: Vec<Cid> = (0..1000u32).map(|i| create_cid(&i.to_be_bytes())).collect();
+        let filter = BloomFilter::new(&cids, MAX_FILTER_BYTES);
+        assert_eq!(filter.bits().len(), 1250);
+        assert_eq!(filter.hashes(), 7);
+        assert!(cids.iter().all(|cid| filter.contains(cid)));
+        let false_positives = (1000..11000u32)
+            .filter(|i| filter.contains(&create_cid(&i.to_be_bytes())))
+            .count();
+        assert!(false_positives < 250, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_bloom_filter_limits() {
+        let empty = BloomFilter::new(&[], MAX_FILTER_BYTES);
+        assert_eq!(empty.bits().len(), 1);
+        assert!(!empty.contains(&create_cid(b"block")));
+
+        let cids: Vec<Cid> = (0..100u32).map(|i| create_cid(&i.to_be_bytes())).collect();
+        let small = BloomFilter::new(&cids, 8);
+        assert_eq!(small.bits().len(), 8);
+        assert!(cids.iter().all(|cid| small.contains(cid)));
+
+        let parts = BloomFilter::from_parts(small.hashes(), small.bits.clone()).unwrap();
+        assert_eq!(parts, small);
+        assert!(BloomFilter::from_parts(0, small.bits.clone()).is_err());
+        assert!(BloomFilter::from_parts(MAX_HASHES + 1, small.bits.clone()).is_err());
+        assert!(BloomFilter::from_parts(1, Bytes::new()).is_err());
+    }
+
+    #[test]
+    fn test_read_filter_depth() {
+        // chain of blocks, every block links to the previous one
+        let mut store = Store::default();
+        let mut cids = vec![];
+        for n in 0..4u64 {
+            let block = match cids.last() {
+                Some(prev) => ipld!({ "prev": prev, "n": n }),
+                None => ipld!({ "n": n }),
+            };
+            let block = Block::encode(DagCborCodec, Code::Blake3_256, &block).unwrap();
+            cids.push(*block.cid());
+            store.insert(&block).unwrap();
+        }
+        let store = BlockingStore::new(store);
+        let base = *cids.last().unwrap();
+        let read =
+            |depth| futures::executor::block_on(read_filter(&store, base, depth, references));
+
+        let filter = read(None).unwrap();
+        assert!(cids.iter().all(|cid| filter.contains(cid)));
+
+        // blocks below the depth limit can't be left out of the first response
+        let filter = read(Some(1)).unwrap();
+        assert!(filter.contains(&cids[3]));
+        assert!(filter.contains(&cids[2]));
+        assert_eq!(filter, BloomFilter::new(&cids[2..], MAX_FILTER_BYTES));
+    }
+}
@@ -0,0 +1,172 @@
+use crate::stats::{MISBEHAVIOUR_TOTAL, PEERS_BANNED};
+use fnv::FnvHashMap;
+use libp2p::PeerId;
+use std::time::{Duration, Instant};
+
+/// Ways in which a peer can violate the protocol.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Misbehaviour {
+    /// Sent a block that doesn't match the requested cid or was rejected by the validator.
+    InvalidBlock,
+    /// Sent a message that exceeds the size limit.
+    OversizeMessage,
+    /// Sent a message that couldn't be decoded.
+    DecodeError,
+    /// Sent a response that wasn't requested.
+    UnsolicitedResponse,
+}
+
+impl Misbehaviour {
+    /// Penalty added to the score of a peer.
+    fn penalty(self) -> u32 {
+        match self {
+            Self::InvalidBlock => 4,
+            Self::OversizeMessage => 4,
+            Self::DecodeError => 2,
+            Self::UnsolicitedResponse => 1,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::InvalidBlock => "invalid_block",
+            Self::OversizeMessage => "oversize_message",
+            Self::DecodeError => "decode_error",
+            Self::UnsolicitedResponse => "unsolicited_response",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Score {
+    score: u32,
+    last_update: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Keeps track of misbehaving peers.
+///
+/// Every misbehaviour adds a penalty to the score of a peer. When the score reaches the
+/// ban threshold the peer is banned for the ban duration. The score is reset when a peer
+/// behaves for the ban duration.
+#[derive(Debug)]
+pub struct Reputation {
+    threshold: u32,
+    duration: Duration,
+    scores: FnvHashMap<PeerId, Score>,
+}
+
+impl Reputation {
+    /// Creates a new `Reputation`. A threshold of zero disables banning.
+    pub fn new(threshold: u32, duration: Duration) -> Self {
+        Self {
+            threshold,
+            duration,
+            scores: Default::default(),
+        }
+    }
+
+    /// Changes the ban threshold and duration. Bans in effect keep their expiry.
+    pub fn set_limits(&mut self, threshold: u32, duration: Duration) {
+        self.threshold = threshold;
+        self.duration = duration;
+    }
+
+    /// Records a misbehaviour. Returns true if the peer got banned.
+    pub fn report(&mut self, peer: PeerId, misbehaviour: Misbehaviour) -> bool {
+        tracing::debug!("peer {} misbehaved {:?}", peer, misbehaviour);
+        MISBEHAVIOUR_TOTAL
+            .with_label_values(&[misbehaviour.label()])
+            .inc();
+        if self.threshold == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let score = self.scores.entry(peer).or_insert(Score {
+            score: 0,
+            last_update: now,
+            banned_until: None,
+        });
+        if score
+            .banned_until
+            .map(|until| until > now)
+            .unwrap_or_default()
+        {
+            return false;
+        }
+        if now.duration_since(score.last_update) > self.duration {
+            score.score = 0;
+        }
+        score.score += misbehaviour.penalty();
+        score.last_update = now;
+        if score.score < self.threshold {
+            return false;
+        }
+        tracing::info!("banning peer {} for {:?}", peer, self.duration);
+        PEERS_BANNED.inc();
+        score.score = 0;
+        score.banned_until = Some(now + self.duration);
+        true
+    }
+
+    /// Returns true if the peer is currently banned.
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.scores
+            .get(peer)
+            .and_then(|score| score.banned_until)
+            .map(|until| until > Instant::now())
+            .unwrap_or_default()
+    }
+
+    /// Forgets peers that behaved for the ban duration.
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        let duration = self.duration;
+        self.scores.retain(|_, score| {
+            score
+                .banned_until
+                .map(|until| until > now)
+                .unwrap_or_default()
+                || now.duration_since(score.last_update) <= duration
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban() {
+        let mut reputation = Reputation::new(8, Duration::from_secs(60));
+        let peer = PeerId::random();
+        assert!(!reputation.report(peer, Misbehaviour::InvalidBlock));
+        assert!(!reputation.is_banned(&peer));
+        assert!(reputation.report(peer, Misbehaviour::InvalidBlock));
+        assert!(reputation.is_banned(&peer));
+        assert!(!reputation.report(peer, Misbehaviour::InvalidBlock));
+        assert!(!reputation.is_banned(&PeerId::random()));
+    }
+
+    #[test]
+    fn test_ban_expires() {
+        let mut reputation = Reputation::new(1, Duration::from_millis(10));
+        let peer = PeerId::random();
+        assert!(reputation.report(peer, Misbehaviour::UnsolicitedResponse));
+        assert!(reputation.is_banned(&peer));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!reputation.is_banned(&peer));
+        reputation.prune();
+        assert!(reputation.scores.is_empty());
+    }
+
+    #[test]
+    fn test_ban_disabled() {
+        let mut reputation = Reputation::new(0, Duration::from_secs(60));
+        let peer = PeerId::random();
+        for _ in 0..100 {
+            assert!(!reputation.report(peer, Misbehaviour::InvalidBlock));
+        }
+        assert!(!reputation.is_banned(&peer));
+    }
+}
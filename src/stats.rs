@@ -1,5 +1,5 @@
 use lazy_static::lazy_static;
-use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts};
+use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts};
 
 lazy_static! {
     pub static ref REQUESTS_TOTAL: IntCounterVec = IntCounterVec::new(
@@ -47,6 +47,11 @@ lazy_static! {
         "Number of received bytes that didn't match the hash.",
     )
     .unwrap();
+    pub static ref REJECTED_BLOCK_BYTES: IntCounter = IntCounter::new(
+        "bitswap_rejected_block_bytes",
+        "Number of received bytes that were rejected by the block validator.",
+    )
+    .unwrap();
     pub static ref SENT_BLOCK_BYTES: IntCounter =
         IntCounter::new("bitswap_sent_block_bytes", "Number of sent block bytes.",).unwrap();
     pub static ref RESPONSES_TOTAL: IntCounterVec = IntCounterVec::new(
@@ -57,6 +62,19 @@ lazy_static! {
         &["type"],
     )
     .unwrap();
+    pub static ref PUSHED_BLOCKS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_pushed_blocks_total",
+            "Number of blocks pushed to or by peers.",
+        ),
+        &["status"],
+    )
+    .unwrap();
+    pub static ref BROADCAST_REQUESTS: IntCounter = IntCounter::new(
+        "bitswap_broadcast_requests_total",
+        "Number of have requests sent to connected peers that weren't providers.",
+    )
+    .unwrap();
     pub static ref THROTTLED_INBOUND: IntCounter = IntCounter::new(
         "bitswap_throttled_too_many_inbound_total",
         "Number of too many inbound events.",
@@ -83,4 +101,39 @@ lazy_static! {
         &["type"],
     )
     .unwrap();
+    pub static ref DB_QUEUE_DEPTH: IntGauge = IntGauge::new(
+        "bitswap_db_queue_depth",
+        "Number of requests queued for the block store.",
+    )
+    .unwrap();
+    pub static ref MISBEHAVIOUR_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_misbehaviour_total",
+            "Number of protocol violations by peers labelled by type.",
+        ),
+        &["type"],
+    )
+    .unwrap();
+    pub static ref PEERS_BANNED: IntCounter = IntCounter::new(
+        "bitswap_peers_banned_total",
+        "Number of times a misbehaving peer was banned.",
+    )
+    .unwrap();
+    pub static ref SHED_INBOUND: IntCounter = IntCounter::new(
+        "bitswap_shed_inbound_total",
+        "Number of inbound requests answered with dont have because the store was busy.",
+    )
+    .unwrap();
+}
+
+#[cfg(feature = "compression")]
+lazy_static! {
+    pub static ref COMPRESSION_RATIO: prometheus::Histogram = prometheus::Histogram::with_opts(
+        HistogramOpts::new(
+            "bitswap_compression_ratio",
+            "Ratio of uncompressed to compressed size of sent blocks.",
+        )
+        .buckets(vec![1.0, 1.5, 2.0, 3.0, 4.0, 5.0, 8.0]),
+    )
+    .unwrap();
 }
@@ -0,0 +1,127 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::{store::StoreParams, Block, Result};
+use thiserror::Error;
+
+/// Validates a received block before it is inserted into the store.
+///
+/// The block has already been checked to hash to the requested cid. A block that is
+/// rejected is treated like an invalid block and the next provider is tried.
+pub trait BlockValidator<P: StoreParams>: Send + Sync + 'static {
+    /// Returns an error if the block should not be inserted into the store.
+    fn validate(&self, block: &Block<P>) -> Result<()>;
+}
+
+impl<P, F> BlockValidator<P> for F
+where
+    P: StoreParams,
+    F: Fn(&Block<P>) -> Result<()> + Send + Sync + 'static,
+{
+    fn validate(&self, block: &Block<P>) -> Result<()> {
+        self(block)
+    }
+}
+
+/// Validator that restricts the codecs, hash functions and sizes of received blocks.
+#[derive(Clone, Debug, Default)]
+pub struct BlockRules {
+    codecs: Option<FnvHashSet<u64>>,
+    hashes: Option<FnvHashSet<u64>>,
+    max_size: FnvHashMap<u64, usize>,
+}
+
+impl BlockRules {
+    /// Creates a new `BlockRules` that accepts all blocks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accepts blocks with one of the given codecs.
+    pub fn allow_codecs(mut self, codecs: impl IntoIterator<Item = u64>) -> Self {
+        self.codecs = Some(codecs.into_iter().collect());
+        self
+    }
+
+    /// Only accepts blocks hashed with one of the given multihash codes.
+    pub fn allow_hashes(mut self, hashes: impl IntoIterator<Item = u64>) -> Self {
+        self.hashes = Some(hashes.into_iter().collect());
+        self
+    }
+
+    /// Limits the size of blocks with the given codec.
+    pub fn max_size(mut self, codec: u64, size: usize) -> Self {
+        self.max_size.insert(codec, size);
+        self
+    }
+}
+
+impl<P: StoreParams> BlockValidator<P> for BlockRules {
+    fn validate(&self, block: &Block<P>) -> Result<()> {
+        let codec = block.cid().codec();
+        if let Some(codecs) = &self.codecs {
+            if !codecs.contains(&codec) {
+                return Err(ValidationError::DisallowedCodec(codec).into());
+            }
+        }
+        let hash = block.cid().hash().code();
+        if let Some(hashes) = &self.hashes {
+            if !hashes.contains(&hash) {
+                return Err(ValidationError::DisallowedHash(hash).into());
+            }
+        }
+        if let Some(max_size) = self.max_size.get(&codec) {
+            if block.data().len() > *max_size {
+                return Err(ValidationError::BlockTooLarge(codec, block.data().len()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by [`BlockRules`].
+#[derive(Debug, Error)]
+pub enum ValidationError {
+    /// The codec of the block is not allowed.
+    #[error("codec {0:#x} is not allowed")]
+    DisallowedCodec(u64),
+    /// The hash function of the block is not allowed.
+    #[error("hash {0:#x} is not allowed")]
+    DisallowedHash(u64),
+    /// The block exceeds the size limit of its codec.
+    #[error("block with codec {0:#x} too large {1}")]
+    BlockTooLarge(u64, usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::cbor::DagCborCodec;
+    use libipld::ipld;
+    use libipld::multihash::Code;
+    use libipld::store::DefaultParams;
+
+    const DAG_CBOR: u64 = 0x71;
+    const RAW: u64 = 0x55;
+
+    fn validate(rules: &BlockRules, block: &Block<DefaultParams>) -> Result<()> {
+        BlockValidator::<DefaultParams>::validate(rules, block)
+    }
+
+    #[test]
+    fn test_block_rules() {
+        let block =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld!({ "n": 0 }))
+                .unwrap();
+
+        assert!(validate(&BlockRules::new(), &block).is_ok());
+        assert!(validate(&BlockRules::new().allow_codecs([DAG_CBOR]), &block).is_ok());
+        assert!(validate(&BlockRules::new().allow_codecs([RAW]), &block).is_err());
+        let blake3 = u64::from(Code::Blake3_256);
+        let sha2 = u64::from(Code::Sha2_256);
+        assert!(validate(&BlockRules::new().allow_hashes([blake3]), &block).is_ok());
+        assert!(validate(&BlockRules::new().allow_hashes([sha2]), &block).is_err());
+        let len = block.data().len();
+        assert!(validate(&BlockRules::new().max_size(DAG_CBOR, len), &block).is_ok());
+        assert!(validate(&BlockRules::new().max_size(DAG_CBOR, len - 1), &block).is_err());
+        assert!(validate(&BlockRules::new().max_size(RAW, len - 1), &block).is_ok());
+    }
+}